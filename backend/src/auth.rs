@@ -0,0 +1,277 @@
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::{error, info};
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "a bearer or query string token is required"),
+            AuthError::Invalid => write!(f, "token is invalid or has expired"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Rejects a websocket upgrade before it happens, answered with a 401.
+#[derive(Debug)]
+pub struct Unauthorized(pub AuthError);
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Checks the token presented on upgrade against an HS256 JWT secret, a static token file, or
+/// both. With neither configured every upgrade is let through without a subject.
+pub struct Auth {
+    decoding_key: Option<DecodingKey>,
+    validation: Validation,
+    tokens: HashMap<String, String>,
+    required: bool,
+}
+
+impl Auth {
+    pub async fn init(config: &Config) -> Result<Auth, Box<dyn std::error::Error>> {
+        let decoding_key = config
+            .auth_jwt_secret
+            .as_ref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+        let tokens = match &config.auth_token_file {
+            Some(path) => Auth::read_tokens(path).await?,
+            None => HashMap::with_capacity(0),
+        };
+
+        let required = decoding_key.is_some() || config.auth_token_file.is_some();
+
+        if required {
+            info!("authentication required on upgrade");
+        }
+
+        Ok(Auth {
+            decoding_key,
+            validation: Validation::new(Algorithm::HS256),
+            tokens,
+            required,
+        })
+    }
+
+    /// Read `subject token` pairs, one per line, skipping blank lines and `#` comments.
+    async fn read_tokens(
+        path: &Path,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut tokens = HashMap::with_capacity(10);
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(char::is_whitespace) {
+                Some((subject, token)) if !token.trim().is_empty() => {
+                    tokens.insert(token.trim().to_owned(), subject.to_owned());
+                }
+                _ => error!("skipping token file line {} -> missing token", number + 1),
+            }
+        }
+
+        info!("loaded {} tokens from {:?}", tokens.len(), path);
+
+        Ok(tokens)
+    }
+
+    pub async fn enabled(&self) -> bool {
+        self.required
+    }
+
+    /// The subject a token was issued to, or `None` when authentication is not configured.
+    pub async fn authenticate(&self, token: Option<&str>) -> Result<Option<String>, AuthError> {
+        if !self.enabled().await {
+            return Ok(None);
+        }
+
+        let token = match token {
+            Some(token) if !token.is_empty() => token,
+            _ => return Err(AuthError::Missing),
+        };
+
+        if let Some(subject) = self.tokens.get(token) {
+            return Ok(Some(subject.to_owned()));
+        }
+
+        if let Some(decoding_key) = &self.decoding_key {
+            match decode::<Claims>(token, decoding_key, &self.validation) {
+                Ok(token_data) if !token_data.claims.sub.is_empty() => {
+                    return Ok(Some(token_data.claims.sub))
+                }
+                Ok(_) => info!("rejected token without a subject"),
+                Err(error) => info!("rejected token -> {:?}", error),
+            }
+        }
+
+        Err(AuthError::Invalid)
+    }
+}
+
+/// Take the token from an `Authorization: Bearer` header, falling back to `?token=`.
+pub async fn token<'a>(
+    authorization: Option<&'a str>,
+    query: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    let bearer = authorization.and_then(|header| {
+        header
+            .strip_prefix("Bearer ")
+            .or_else(|| header.strip_prefix("bearer "))
+    });
+
+    bearer
+        .or_else(|| query.get("token").map(String::as_str))
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct TestClaims {
+        sub: String,
+        exp: u64,
+    }
+
+    async fn test_jwt(secret: &str, sub: &str, exp: u64) -> String {
+        let test_claims = TestClaims {
+            sub: sub.to_owned(),
+            exp,
+        };
+
+        encode(
+            &Header::default(),
+            &test_claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .expect("test jwt")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disabled() -> Result<(), Box<dyn std::error::Error>> {
+        let test_auth = Auth::init(&Config::default()).await?;
+
+        assert!(!test_auth.enabled().await);
+        assert_eq!(test_auth.authenticate(None).await, Ok(None));
+        assert_eq!(test_auth.authenticate(Some("test_token")).await, Ok(None));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn jwt() -> Result<(), Box<dyn std::error::Error>> {
+        let test_config = Config {
+            auth_jwt_secret: Some(String::from("test_secret")),
+            ..Config::default()
+        };
+        let test_auth = Auth::init(&test_config).await?;
+        let test_expiry = crate::store::timestamp().await / 1000 + 3600;
+
+        let test_valid = test_jwt("test_secret", "test_subject", test_expiry).await;
+        let test_wrong_secret = test_jwt("test_other_secret", "test_subject", test_expiry).await;
+        let test_expired = test_jwt("test_secret", "test_subject", 1000).await;
+
+        assert!(test_auth.enabled().await);
+        assert_eq!(
+            test_auth.authenticate(Some(&test_valid)).await,
+            Ok(Some(String::from("test_subject"))),
+        );
+        assert_eq!(
+            test_auth.authenticate(Some(&test_wrong_secret)).await,
+            Err(AuthError::Invalid),
+        );
+        assert_eq!(
+            test_auth.authenticate(Some(&test_expired)).await,
+            Err(AuthError::Invalid),
+        );
+        assert_eq!(
+            test_auth.authenticate(Some("test_garbage")).await,
+            Err(AuthError::Invalid),
+        );
+        assert_eq!(test_auth.authenticate(None).await, Err(AuthError::Missing));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn token_file() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.tokens", uuid::Uuid::new_v4()));
+
+        std::fs::write(
+            &test_path,
+            "# test tokens\n\ntest_subject test_token\ntest_missing_token\n",
+        )?;
+
+        let test_config = Config {
+            auth_token_file: Some(test_path.to_owned()),
+            ..Config::default()
+        };
+        let test_auth = Auth::init(&test_config).await?;
+
+        std::fs::remove_file(&test_path)?;
+
+        assert!(test_auth.enabled().await);
+        assert_eq!(test_auth.tokens.len(), 1);
+        assert_eq!(
+            test_auth.authenticate(Some("test_token")).await,
+            Ok(Some(String::from("test_subject"))),
+        );
+        assert_eq!(
+            test_auth.authenticate(Some("test_subject")).await,
+            Err(AuthError::Invalid),
+        );
+        assert_eq!(
+            test_auth.authenticate(Some("")).await,
+            Err(AuthError::Missing)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn token() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_query = HashMap::with_capacity(1);
+
+        assert_eq!(super::token(None, &test_query).await, None);
+        assert_eq!(
+            super::token(Some("Bearer test_header"), &test_query).await,
+            Some("test_header"),
+        );
+        assert_eq!(super::token(Some("Basic test"), &test_query).await, None);
+
+        test_query.insert(String::from("token"), String::from("test_query"));
+
+        assert_eq!(
+            super::token(Some("Bearer test_header"), &test_query).await,
+            Some("test_header"),
+        );
+        assert_eq!(super::token(None, &test_query).await, Some("test_query"),);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use sha2::{Digest, Sha256};
+
+use std::fmt::Write;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::info;
+
+/// Served for blobs stored before their content type was kept.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Attachment contents kept on disk, named by the hex SHA-256 of their bytes.
+///
+/// Storing the same bytes twice is a no-op, so ids can be handed out freely and a blob never
+/// changes once it has been written.
+#[derive(Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub async fn init(dir: &Path) -> BlobStore {
+        BlobStore {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// Write a blob with the content type its uploader declared, returning its id. The directory
+    /// is created on first use, and the first content type stored for some bytes is kept.
+    pub async fn put(
+        &self,
+        bytes: &[u8],
+        content_type: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let id = BlobStore::id(bytes).await;
+        let path = self.dir.join(&id);
+
+        if path.exists() {
+            info!("blob already stored -> {:?}", &id);
+
+            return Ok(id);
+        }
+
+        std::fs::create_dir_all(&self.dir)?;
+
+        BlobStore::write(
+            &self.dir.join(format!("{}.type", &id)),
+            content_type.as_bytes(),
+        )
+        .await?;
+        BlobStore::write(&path, bytes).await?;
+
+        info!("stored blob -> {:?}", &id);
+
+        Ok(id)
+    }
+
+    /// Read a blob back with its content type, or `None` if the id is malformed or unknown.
+    pub async fn get(
+        &self,
+        id: &str,
+    ) -> Result<Option<(Vec<u8>, String)>, Box<dyn std::error::Error>> {
+        if !BlobStore::valid_id(id).await {
+            return Ok(None);
+        }
+
+        let bytes = match std::fs::read(self.dir.join(id)) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(Box::new(error)),
+        };
+
+        let content_type = match std::fs::read_to_string(self.dir.join(format!("{}.type", id))) {
+            Ok(content_type) => content_type,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                String::from(DEFAULT_CONTENT_TYPE)
+            }
+            Err(error) => return Err(Box::new(error)),
+        };
+
+        Ok(Some((bytes, content_type)))
+    }
+
+    /// Write through a `.partial` file renamed into place, so a crash never leaves half a file.
+    async fn write(path: &Path, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut partial_path = path.as_os_str().to_owned();
+
+        partial_path.push(".partial");
+
+        let mut partial = File::create(&partial_path)?;
+
+        std::io::Write::write_all(&mut partial, bytes)?;
+
+        partial.sync_all()?;
+
+        std::fs::rename(&partial_path, path)?;
+
+        Ok(())
+    }
+
+    pub async fn id(bytes: &[u8]) -> String {
+        let digest = Sha256::digest(bytes);
+        let mut id = String::with_capacity(64);
+
+        for byte in digest {
+            write!(id, "{:02x}", byte).ok();
+        }
+
+        id
+    }
+
+    /// Ids are exactly 64 lowercase hex characters, which also keeps them inside the directory.
+    pub async fn valid_id(id: &str) -> bool {
+        id.len() == 64
+            && id
+                .chars()
+                .all(|character| matches!(character, '0'..='9' | 'a'..='f'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn id() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            BlobStore::id(b"test_blob").await,
+            BlobStore::id(b"test_blob").await,
+        );
+        assert_ne!(
+            BlobStore::id(b"test_blob").await,
+            BlobStore::id(b"test_other_blob").await,
+        );
+        assert_eq!(
+            BlobStore::id(b"").await,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn valid_id() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(BlobStore::valid_id(&BlobStore::id(b"test_blob").await).await);
+        assert!(!BlobStore::valid_id("").await);
+        assert!(!BlobStore::valid_id("../relay.log").await);
+        assert!(!BlobStore::valid_id(&"A".repeat(64)).await);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn put_and_get() -> Result<(), Box<dyn std::error::Error>> {
+        let test_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let test_blobs = BlobStore::init(&test_dir).await;
+
+        assert!(!test_dir.exists());
+
+        let test_id = test_blobs.put(b"test_blob", "text/plain").await?;
+
+        assert_eq!(test_id, BlobStore::id(b"test_blob").await);
+        assert_eq!(test_blobs.put(b"test_blob", "image/png").await?, test_id);
+        assert_eq!(std::fs::read_dir(&test_dir)?.count(), 2);
+        assert_eq!(
+            test_blobs.get(&test_id).await?,
+            Some((b"test_blob".to_vec(), String::from("text/plain"))),
+        );
+
+        std::fs::remove_file(test_dir.join(format!("{}.type", &test_id)))?;
+
+        assert_eq!(
+            test_blobs.get(&test_id).await?,
+            Some((
+                b"test_blob".to_vec(),
+                String::from("application/octet-stream")
+            )),
+        );
+        assert_eq!(
+            test_blobs
+                .get(&BlobStore::id(b"test_unknown_blob").await)
+                .await?,
+            None,
+        );
+        assert_eq!(test_blobs.get("../test_blob").await?, None);
+
+        std::fs::remove_dir_all(&test_dir)?;
+
+        Ok(())
+    }
+}
@@ -1,144 +1,898 @@
-use std::collections::HashMap;
-use tokio::sync::{mpsc, oneshot, watch};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::time;
 use warp::ws::Message;
 
+use crate::config::SlowConsumer;
 use crate::info;
+use crate::json::{Presence, ReactionEvent};
+use crate::search::SearchPage;
+use crate::state::SuspendedSession;
+use crate::store::MessageRecord;
 
 pub type ConnectedUsers = HashMap<String, WebSocketSender>;
 pub type ShutdownSignal = watch::Receiver<u8>;
 pub type StateReceiver = mpsc::Receiver<(StateRequest, oneshot::Sender<StateResponse>)>;
-pub type StateSender = mpsc::Sender<(StateRequest, oneshot::Sender<StateResponse>)>;
-pub type WebSocketReceiver = mpsc::Receiver<WebSocketConnection>;
-pub type WebSocketSender = mpsc::Sender<WebSocketConnection>;
+
+/// Sent to connections closed by the `disconnect` slow consumer policy, as a policy violation.
+pub const SLOW_CONSUMER_CLOSE_CODE: u16 = 1008;
 
 #[derive(Clone, Debug)]
 pub enum StateRequest {
-    AddMessage(Message),
+    AddMessage(MessageRecord),
+    /// The reacting user's uuid, the message's id and the emoji
+    AddReaction((String, String, String)),
     AddUser((String, WebSocketSender)),
-    GetUser(String),
-    GetUsers,
-    GetMessages,
-    RemoveUser(String),
+    Broadcast((Audience, Arc<Message>)),
+    /// The author's uuid and the message's id
+    DeleteMessage((String, String)),
+    DirectMessage(MessageRecord),
+    /// The author's uuid, the message's id and its new contents
+    EditMessage((String, String, String)),
+    GetConversation((String, String)),
+    /// The room, the id of the message the page ends before and the most it holds
+    GetHistory((String, Option<String>, usize)),
+    GetUsers(String),
+    /// The room, the last sequence already seen and the most a page holds
+    GetMissed((String, u64, usize)),
+    GetPresence(String),
+    /// The user's uuid and the id of any message in the thread
+    GetThread((String, String)),
+    JoinRoom((String, String)),
+    LeaveRoom((String, String)),
+    /// A resume token issued to an open connection, and the uuid it belongs to
+    RegisterSession((String, String)),
+    RemoveReaction((String, String, String)),
+    /// The user's uuid and the connection asking, which is ignored once it has been replaced
+    RemoveUser((String, WebSocketSender)),
+    ResumeSession((String, Option<String>)),
+    SuspendSession((String, SuspendedSession, WebSocketSender)),
+    /// The user's uuid, the room, the query, how many matches to skip and the most a page holds
+    Search((String, String, String, usize, usize)),
+    SetNickname((String, String)),
     Shutdown,
+    Typing((String, String)),
 }
 
 #[derive(Clone, Debug)]
 pub enum StateResponse {
-    Messages(Vec<Message>),
-    User(WebSocketSender),
+    Message(MessageRecord),
+    Messages(Vec<MessageRecord>),
+    /// A page of history and whether there are older messages
+    History((Vec<MessageRecord>, bool)),
     Users(ConnectedUsers),
+    Presence(Vec<Presence>),
+    /// The nickname a user still holds, if any
+    Nickname(Option<String>),
+    NicknameTaken,
+    /// The message a request named was sent by someone else
+    NotAuthor,
+    Reacted(ReactionEvent),
+    /// A reply, along with how many replies its thread now has
+    Reply((MessageRecord, usize)),
+    Rooms(Vec<String>),
+    Search(SearchPage),
+    Suspended(SuspendedSession),
+    /// The user, room or session a request named does not exist
+    NotFound,
+    Error(RelayError),
     Ok,
 }
 
+/// Who a broadcast is delivered to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Audience {
+    Everyone,
+    Room(String),
+    /// Everyone in a room apart from one user, usually the sender
+    RoomExcept((String, String)),
+}
+
+/// Outbound frames are serialized once and shared between every connection they are sent to.
 #[derive(Clone, Debug)]
 pub enum WebSocketConnection {
-    SendMessage(Message),
+    SendMessage(Arc<Message>),
     Close,
+    /// Close the socket with `SLOW_CONSUMER_CLOSE_CODE`, discarding whatever is still queued
+    Disconnect,
 }
 
-pub async fn add_message(
-    state: &StateSender,
-    message: &Message,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (request, _response) = oneshot::channel();
+/// What became of a frame pushed onto a connection's outbound queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    Queued,
+    /// The queue was full, so either the oldest queued frame or this one was discarded
+    Dropped,
+    /// The queue was full and the connection is being closed
+    Disconnected,
+    Closed,
+}
 
-    state
-        .send((StateRequest::AddMessage(message.to_owned()), request))
-        .await?;
+/// Shared by both halves of a connection's outbound queue.
+#[derive(Debug)]
+struct Outbound {
+    capacity: usize,
+    policy: SlowConsumer,
+    queue: Mutex<Queue>,
+    /// Wakes the receiver whenever something is queued or either half goes away
+    notify: Notify,
+    dropped: Arc<AtomicU64>,
+    disconnected: AtomicBool,
+    /// Clones of the sending half still alive, so the receiver knows when nothing more can come
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+}
+
+/// Frames waiting to be written, holding at most `capacity` messages.
+#[derive(Debug, Default)]
+struct Queue {
+    frames: VecDeque<WebSocketConnection>,
+    messages: usize,
+}
+
+/// The sending half of a connection's outbound queue. Pushing never waits, so a connection
+/// that stops reading can not hold up whoever is sending to it. Once `capacity` messages are
+/// waiting, the slow consumer policy decides what gives.
+#[derive(Debug)]
+pub struct WebSocketSender {
+    outbound: Arc<Outbound>,
+}
+
+#[derive(Debug)]
+pub struct WebSocketReceiver {
+    outbound: Arc<Outbound>,
+    finished: bool,
+}
+
+pub async fn outbound(
+    capacity: usize,
+    policy: SlowConsumer,
+) -> (WebSocketSender, WebSocketReceiver) {
+    let outbound = Arc::new(Outbound {
+        capacity,
+        policy,
+        queue: Mutex::new(Queue::default()),
+        notify: Notify::new(),
+        dropped: Arc::new(AtomicU64::new(0)),
+        disconnected: AtomicBool::new(false),
+        senders: AtomicUsize::new(1),
+        receiver_closed: AtomicBool::new(false),
+    });
+
+    (
+        WebSocketSender {
+            outbound: Arc::clone(&outbound),
+        },
+        WebSocketReceiver {
+            outbound,
+            finished: false,
+        },
+    )
+}
+
+impl Outbound {
+    /// The queue is never held across an await, so a poisoned lock still holds a usable queue.
+    async fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Clone for WebSocketSender {
+    fn clone(&self) -> Self {
+        self.outbound.senders.fetch_add(1, Ordering::SeqCst);
+
+        WebSocketSender {
+            outbound: Arc::clone(&self.outbound),
+        }
+    }
+}
+
+impl Drop for WebSocketSender {
+    fn drop(&mut self) {
+        if self.outbound.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.outbound.notify.notify_one();
+        }
+    }
+}
+
+impl WebSocketSender {
+    /// Queue a frame, applying the slow consumer policy if the queue is full. `Close` and
+    /// `Disconnect` are always queued.
+    pub async fn push(&self, frame: WebSocketConnection) -> Delivery {
+        if self.is_closed().await {
+            return Delivery::Closed;
+        }
+
+        let outbound = &self.outbound;
+        let mut queue = outbound.lock().await;
+        let mut delivery = Delivery::Queued;
+
+        if let WebSocketConnection::SendMessage(_) = frame {
+            if queue.messages >= outbound.capacity {
+                match outbound.policy {
+                    SlowConsumer::DropOldest => {
+                        outbound.dropped.fetch_add(1, Ordering::Relaxed);
+
+                        let oldest = queue.frames.iter().position(|queued| {
+                            matches!(queued, WebSocketConnection::SendMessage(_))
+                        });
+
+                        match oldest {
+                            Some(oldest) => {
+                                queue.frames.remove(oldest);
+                                queue.messages -= 1;
+                            }
+                            None => return Delivery::Dropped,
+                        }
+
+                        delivery = Delivery::Dropped;
+                    }
+                    SlowConsumer::DropNewest => {
+                        outbound.dropped.fetch_add(1, Ordering::Relaxed);
+
+                        return Delivery::Dropped;
+                    }
+                    SlowConsumer::Disconnect => {
+                        if outbound.disconnected.swap(true, Ordering::SeqCst) {
+                            return Delivery::Closed;
+                        }
+
+                        outbound
+                            .dropped
+                            .fetch_add(queue.messages as u64 + 1, Ordering::Relaxed);
+
+                        queue.frames.clear();
+                        queue.messages = 0;
+                        queue.frames.push_back(WebSocketConnection::Disconnect);
+                        outbound.notify.notify_one();
+
+                        return Delivery::Disconnected;
+                    }
+                }
+            }
+
+            queue.messages += 1;
+        }
+
+        queue.frames.push_back(frame);
+        outbound.notify.notify_one();
+
+        delivery
+    }
+
+    /// Queue a frame for a connection's own session, which only fails if the sink is closed.
+    pub async fn send(&self, frame: WebSocketConnection) -> Result<(), RelayError> {
+        match self.push(frame).await {
+            Delivery::Closed => Err(RelayError::WebSocketSend(String::from("sink is closed"))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the connection has gone away, or is being disconnected for falling behind.
+    pub async fn is_closed(&self) -> bool {
+        self.outbound.receiver_closed.load(Ordering::SeqCst)
+            || self.outbound.disconnected.load(Ordering::SeqCst)
+    }
+
+    /// Whether both senders feed the same connection's queue.
+    pub async fn same_connection(&self, other: &WebSocketSender) -> bool {
+        Arc::ptr_eq(&self.outbound, &other.outbound)
+    }
+
+    pub async fn disconnected(&self) -> bool {
+        self.outbound.disconnected.load(Ordering::SeqCst)
+    }
+
+    /// Frames dropped by the slow consumer policy over the life of the connection.
+    pub async fn dropped(&self) -> u64 {
+        self.outbound.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The counter behind `dropped`, for exposing in metrics while the connection is open.
+    pub async fn dropped_counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.outbound.dropped)
+    }
+}
+
+impl Drop for WebSocketReceiver {
+    fn drop(&mut self) {
+        self.outbound.receiver_closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl WebSocketReceiver {
+    /// The next frame to write to the socket, or `None` once every sender has gone and the queue
+    /// is empty. After a `Disconnect` there is nothing more to write.
+    pub async fn recv(&mut self) -> Option<WebSocketConnection> {
+        loop {
+            if self.finished {
+                return None;
+            }
 
-    Ok(())
+            let popped = {
+                let mut queue = self.outbound.lock().await;
+                let frame = queue.frames.pop_front();
+
+                if let Some(WebSocketConnection::SendMessage(_)) = frame {
+                    queue.messages -= 1;
+                }
+
+                frame
+            };
+
+            if let Some(frame) = popped {
+                if let WebSocketConnection::Disconnect = frame {
+                    self.finished = true;
+                }
+
+                return Some(frame);
+            }
+
+            if self.outbound.senders.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+
+            self.outbound.notify.notified().await;
+        }
+    }
+}
+
+/// Everything that can go wrong relaying frames between connections and state, at runtime.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelayError {
+    /// The state actor has stopped, so no request can be answered
+    StateChannelClosed,
+    /// State answered a request with a response meant for another, named by the request
+    UnexpectedResponse(&'static str),
+    Serialization(String),
+    /// A connection's sink, or the websocket behind it, has gone away
+    WebSocketSend(String),
+    UserNotFound(String),
+    MessageNotFound(String),
+    /// A user tried to change a message, named by id, that someone else sent
+    NotAuthor(String),
+    Storage(String),
+    /// State took longer than the configured timeout to answer the named request
+    Timeout(&'static str),
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::StateChannelClosed => write!(f, "state channel is closed"),
+            RelayError::UnexpectedResponse(request) => {
+                write!(f, "unexpected state response to {}", request)
+            }
+            RelayError::Serialization(error) => write!(f, "serialization failed -> {}", error),
+            RelayError::WebSocketSend(error) => write!(f, "websocket send failed -> {}", error),
+            RelayError::UserNotFound(user) => write!(f, "user not found -> {}", user),
+            RelayError::MessageNotFound(id) => write!(f, "message not found -> {}", id),
+            RelayError::NotAuthor(id) => write!(f, "not the author of message -> {}", id),
+            RelayError::Storage(error) => write!(f, "storage failed -> {}", error),
+            RelayError::Timeout(request) => write!(f, "timed out waiting on state for {}", request),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+impl From<mpsc::error::SendError<(StateRequest, oneshot::Sender<StateResponse>)>> for RelayError {
+    fn from(_: mpsc::error::SendError<(StateRequest, oneshot::Sender<StateResponse>)>) -> Self {
+        RelayError::StateChannelClosed
+    }
+}
+
+impl From<oneshot::error::RecvError> for RelayError {
+    fn from(_: oneshot::error::RecvError) -> Self {
+        RelayError::StateChannelClosed
+    }
+}
+
+impl From<warp::Error> for RelayError {
+    fn from(error: warp::Error) -> Self {
+        RelayError::WebSocketSend(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for RelayError {
+    fn from(error: serde_json::Error) -> Self {
+        RelayError::Serialization(error.to_string())
+    }
 }
 
+/// The sending half of the state channel, and how long callers wait on each request.
+#[derive(Clone, Debug)]
+pub struct StateSender {
+    sender: mpsc::Sender<(StateRequest, oneshot::Sender<StateResponse>)>,
+    timeout: Duration,
+}
+
+impl StateSender {
+    pub async fn init(
+        sender: mpsc::Sender<(StateRequest, oneshot::Sender<StateResponse>)>,
+        timeout: Duration,
+    ) -> StateSender {
+        StateSender { sender, timeout }
+    }
+
+    /// Send a request and wait for the response, giving up once the timeout has passed so that
+    /// a stuck state actor can not hold up the caller. `name` identifies the request in errors.
+    async fn request(
+        &self,
+        request: StateRequest,
+        name: &'static str,
+    ) -> Result<StateResponse, RelayError> {
+        let (respond, response) = oneshot::channel();
+        let exchange = async {
+            self.sender.send((request, respond)).await?;
+
+            Ok(response.await?)
+        };
+
+        match time::timeout(self.timeout, exchange).await {
+            Ok(Ok(StateResponse::Error(error))) => Err(error),
+            Ok(result) => result,
+            Err(_) => Err(RelayError::Timeout(name)),
+        }
+    }
+}
+
+/// Store a message, returning it with the id, sequence and timestamp state gave it. Replies
+/// also return how many replies their thread now has.
+pub async fn add_message(
+    state: &StateSender,
+    message: &MessageRecord,
+) -> Result<Option<(MessageRecord, Option<usize>)>, RelayError> {
+    match state
+        .request(StateRequest::AddMessage(message.to_owned()), "add_message")
+        .await?
+    {
+        StateResponse::Message(message) => Ok(Some((message, None))),
+        StateResponse::Reply((message, replies)) => Ok(Some((message, Some(replies)))),
+        StateResponse::NotFound => {
+            info!("message for a closed room -> {:?}", &message.room);
+
+            Ok(None)
+        }
+        _ => Err(RelayError::UnexpectedResponse("add_message")),
+    }
+}
+
+/// Register a user's connection, returning the nickname they still hold from an earlier one.
 pub async fn add_user(
     state: &StateSender,
     uuid: String,
     websocket: WebSocketSender,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (request, response) = oneshot::channel();
-
-    state
-        .send((StateRequest::AddUser((uuid, websocket)), request))
-        .await?;
-
-    match response.await? {
-        StateResponse::Ok => {
+) -> Result<Option<String>, RelayError> {
+    match state
+        .request(StateRequest::AddUser((uuid, websocket)), "add_user")
+        .await?
+    {
+        StateResponse::Nickname(nickname) => {
             info!("successfully added user...");
 
-            Ok(())
+            Ok(nickname)
         }
-        _ => panic!("unexpected response!"),
+        _ => Err(RelayError::UnexpectedResponse("add_user")),
     }
 }
 
-pub async fn get_messages(state: &StateSender) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
-    let (request, response) = oneshot::channel();
+/// Deliver a message to each member of the audience, returning the rooms of any users found to
+/// have closed their sink, who are disconnected along the way.
+pub async fn broadcast(
+    state: &StateSender,
+    audience: Audience,
+    message: Arc<Message>,
+) -> Result<Vec<String>, RelayError> {
+    match state
+        .request(StateRequest::Broadcast((audience, message)), "broadcast")
+        .await?
+    {
+        StateResponse::Rooms(rooms) => Ok(rooms),
+        _ => Err(RelayError::UnexpectedResponse("broadcast")),
+    }
+}
 
-    state.send((StateRequest::GetMessages, request)).await?;
+/// Store a direct message with its recipient's conversation and deliver it to both sides. The
+/// recipient may be named by uuid or nickname, and must be connected. Returns the rooms of any
+/// users found to have gone away, as `broadcast` does.
+pub async fn direct_message(
+    state: &StateSender,
+    message: &MessageRecord,
+) -> Result<Vec<String>, RelayError> {
+    match state
+        .request(
+            StateRequest::DirectMessage(message.to_owned()),
+            "direct_message",
+        )
+        .await?
+    {
+        StateResponse::Rooms(rooms) => Ok(rooms),
+        StateResponse::NotFound => Err(RelayError::UserNotFound(
+            message.recipient.to_owned().unwrap_or_default(),
+        )),
+        _ => Err(RelayError::UnexpectedResponse("direct_message")),
+    }
+}
 
-    match response.await? {
-        StateResponse::Messages(messages) => Ok(messages),
-        _ => panic!("unexpected response!"),
+/// Add or remove a user's reaction to a message, returning the change or `None` if they had
+/// already reacted, or not, with that emoji.
+pub async fn react(
+    state: &StateSender,
+    uuid: &str,
+    id: &str,
+    emoji: &str,
+    add: bool,
+) -> Result<Option<ReactionEvent>, RelayError> {
+    let reaction = (uuid.to_owned(), id.to_owned(), emoji.to_owned());
+    let request = match add {
+        true => StateRequest::AddReaction(reaction),
+        false => StateRequest::RemoveReaction(reaction),
+    };
+
+    match state.request(request, "react").await? {
+        StateResponse::Reacted(event) => Ok(Some(event)),
+        StateResponse::Ok => Ok(None),
+        StateResponse::NotFound => Err(RelayError::MessageNotFound(id.to_owned())),
+        _ => Err(RelayError::UnexpectedResponse("react")),
     }
 }
 
-pub async fn get_user(
+/// Tombstone one of a user's own messages, returning what is left of it.
+pub async fn delete_message(
     state: &StateSender,
     uuid: &str,
-) -> Result<WebSocketSender, Box<dyn std::error::Error>> {
-    let (request, response) = oneshot::channel();
+    id: &str,
+) -> Result<MessageRecord, RelayError> {
+    match state
+        .request(
+            StateRequest::DeleteMessage((uuid.to_owned(), id.to_owned())),
+            "delete_message",
+        )
+        .await?
+    {
+        StateResponse::Message(message) => Ok(message),
+        StateResponse::NotFound => Err(RelayError::MessageNotFound(id.to_owned())),
+        StateResponse::NotAuthor => Err(RelayError::NotAuthor(id.to_owned())),
+        _ => Err(RelayError::UnexpectedResponse("delete_message")),
+    }
+}
 
-    state
-        .send((StateRequest::GetUser(uuid.to_owned()), request))
-        .await?;
+/// Replace the contents of one of a user's own messages, returning the edited version.
+pub async fn edit_message(
+    state: &StateSender,
+    uuid: &str,
+    id: &str,
+    contents: &str,
+) -> Result<MessageRecord, RelayError> {
+    match state
+        .request(
+            StateRequest::EditMessage((uuid.to_owned(), id.to_owned(), contents.to_owned())),
+            "edit_message",
+        )
+        .await?
+    {
+        StateResponse::Message(message) => Ok(message),
+        StateResponse::NotFound => Err(RelayError::MessageNotFound(id.to_owned())),
+        StateResponse::NotAuthor => Err(RelayError::NotAuthor(id.to_owned())),
+        _ => Err(RelayError::UnexpectedResponse("edit_message")),
+    }
+}
+
+/// Direct messages exchanged between a user and another, named by uuid or nickname.
+pub async fn get_conversation(
+    state: &StateSender,
+    uuid: &str,
+    with: &str,
+) -> Result<Vec<MessageRecord>, RelayError> {
+    match state
+        .request(
+            StateRequest::GetConversation((uuid.to_owned(), with.to_owned())),
+            "get_conversation",
+        )
+        .await?
+    {
+        StateResponse::Messages(messages) => Ok(messages),
+        StateResponse::NotFound => Ok(Vec::new()),
+        _ => Err(RelayError::UnexpectedResponse("get_conversation")),
+    }
+}
 
-    match response.await? {
-        StateResponse::User(user) => Ok(user),
-        _ => panic!("unexpected response!"),
+/// The first message of a thread and its replies, by the id of any of them, if the user is in
+/// the thread's room.
+pub async fn get_thread(
+    state: &StateSender,
+    uuid: &str,
+    id: &str,
+) -> Result<Vec<MessageRecord>, RelayError> {
+    let thread = (uuid.to_owned(), id.to_owned());
+
+    match state
+        .request(StateRequest::GetThread(thread), "get_thread")
+        .await?
+    {
+        StateResponse::Messages(messages) => Ok(messages),
+        StateResponse::NotFound => Err(RelayError::MessageNotFound(id.to_owned())),
+        _ => Err(RelayError::UnexpectedResponse("get_thread")),
     }
 }
 
-pub async fn get_users(state: &StateSender) -> Result<ConnectedUsers, Box<dyn std::error::Error>> {
-    let (request, response) = oneshot::channel();
+/// Up to `limit` messages from a room, ending just before `before` or at the latest message,
+/// and whether there are older ones.
+pub async fn get_history(
+    state: &StateSender,
+    room: &str,
+    before: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<MessageRecord>, bool), RelayError> {
+    let page = (room.to_owned(), before.map(str::to_owned), limit);
+
+    match state
+        .request(StateRequest::GetHistory(page), "get_history")
+        .await?
+    {
+        StateResponse::History(page) => Ok(page),
+        StateResponse::NotFound => Err(RelayError::MessageNotFound(
+            before.unwrap_or_default().to_owned(),
+        )),
+        _ => Err(RelayError::UnexpectedResponse("get_history")),
+    }
+}
 
-    state.send((StateRequest::GetUsers, request)).await?;
+/// Up to `limit` of the latest messages from a room with a later sequence than `after`, and
+/// whether any earlier ones past `after` were left out.
+pub async fn get_missed(
+    state: &StateSender,
+    room: &str,
+    after: u64,
+    limit: usize,
+) -> Result<(Vec<MessageRecord>, bool), RelayError> {
+    match state
+        .request(
+            StateRequest::GetMissed((room.to_owned(), after, limit)),
+            "get_missed",
+        )
+        .await?
+    {
+        StateResponse::History(page) => Ok(page),
+        _ => Err(RelayError::UnexpectedResponse("get_missed")),
+    }
+}
 
-    match response.await? {
+pub async fn get_users(state: &StateSender, room: &str) -> Result<ConnectedUsers, RelayError> {
+    match state
+        .request(StateRequest::GetUsers(room.to_owned()), "get_users")
+        .await?
+    {
         StateResponse::Users(connected_users) => Ok(connected_users),
-        _ => panic!("unexpected response!"),
+        StateResponse::NotFound => Ok(HashMap::new()),
+        _ => Err(RelayError::UnexpectedResponse("get_users")),
+    }
+}
+
+pub async fn get_presence(state: &StateSender, room: &str) -> Result<Vec<Presence>, RelayError> {
+    match state
+        .request(StateRequest::GetPresence(room.to_owned()), "get_presence")
+        .await?
+    {
+        StateResponse::Presence(presence) => Ok(presence),
+        StateResponse::NotFound => Ok(Vec::new()),
+        _ => Err(RelayError::UnexpectedResponse("get_presence")),
+    }
+}
+
+pub async fn join_room(state: &StateSender, uuid: &str, room: &str) -> Result<(), RelayError> {
+    match state
+        .request(
+            StateRequest::JoinRoom((uuid.to_owned(), room.to_owned())),
+            "join_room",
+        )
+        .await?
+    {
+        StateResponse::Ok => {
+            info!("joined room -> {:?}", room);
+
+            Ok(())
+        }
+        StateResponse::NotFound => Err(RelayError::UserNotFound(uuid.to_owned())),
+        _ => Err(RelayError::UnexpectedResponse("join_room")),
+    }
+}
+
+pub async fn leave_room(state: &StateSender, uuid: &str, room: &str) -> Result<(), RelayError> {
+    match state
+        .request(
+            StateRequest::LeaveRoom((uuid.to_owned(), room.to_owned())),
+            "leave_room",
+        )
+        .await?
+    {
+        StateResponse::Ok => {
+            info!("left room -> {:?}", room);
+
+            Ok(())
+        }
+        StateResponse::NotFound => {
+            info!("room already closed -> {:?}", room);
+
+            Ok(())
+        }
+        _ => Err(RelayError::UnexpectedResponse("leave_room")),
     }
 }
 
+/// Remove a user on behalf of one of their connections, which does nothing if that connection
+/// has since been replaced.
 pub async fn remove_user(
     state: &StateSender,
     session_id: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (request, response) = oneshot::channel();
+    connection: &WebSocketSender,
+) -> Result<(), RelayError> {
+    match state
+        .request(
+            StateRequest::RemoveUser((session_id.to_owned(), connection.to_owned())),
+            "remove_user",
+        )
+        .await?
+    {
+        StateResponse::Ok | StateResponse::NotFound => {
+            info!("closing time...");
 
-    state
-        .send((StateRequest::RemoveUser(session_id.to_owned()), request))
-        .await?;
+            Ok(())
+        }
+        _ => Err(RelayError::UnexpectedResponse("remove_user")),
+    }
+}
+
+/// Record the resume token issued to an open connection, so that a reconnect presenting it can
+/// take over before the old socket is noticed as gone.
+pub async fn register_session(
+    state: &StateSender,
+    token: &str,
+    uuid: &str,
+) -> Result<(), RelayError> {
+    match state
+        .request(
+            StateRequest::RegisterSession((token.to_owned(), uuid.to_owned())),
+            "register_session",
+        )
+        .await?
+    {
+        StateResponse::Ok => Ok(()),
+        _ => Err(RelayError::UnexpectedResponse("register_session")),
+    }
+}
+
+/// Take back a suspended session, if the token is known and the grace period has not run out,
+/// or take over an open one.
+pub async fn resume_session(
+    state: &StateSender,
+    token: &str,
+    subject: Option<&str>,
+) -> Result<Option<SuspendedSession>, RelayError> {
+    match state
+        .request(
+            StateRequest::ResumeSession((token.to_owned(), subject.map(str::to_owned))),
+            "resume_session",
+        )
+        .await?
+    {
+        StateResponse::Suspended(suspended) => Ok(Some(suspended)),
+        StateResponse::NotFound => Ok(None),
+        _ => Err(RelayError::UnexpectedResponse("resume_session")),
+    }
+}
 
-    match response.await? {
+pub async fn suspend_session(
+    state: &StateSender,
+    token: &str,
+    suspended: SuspendedSession,
+    connection: &WebSocketSender,
+) -> Result<(), RelayError> {
+    match state
+        .request(
+            StateRequest::SuspendSession((token.to_owned(), suspended, connection.to_owned())),
+            "suspend_session",
+        )
+        .await?
+    {
         StateResponse::Ok => {
-            info!("closing time...");
+            info!("suspended session...");
 
             Ok(())
         }
-        _ => panic!("unexpected response!"),
+        _ => Err(RelayError::UnexpectedResponse("suspend_session")),
+    }
+}
+
+/// Up to `limit` of the messages in a room matching a query, best first, skipping `offset`, or
+/// `None` if the user is not in the room.
+pub async fn search(
+    state: &StateSender,
+    uuid: &str,
+    room: &str,
+    query: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<Option<SearchPage>, RelayError> {
+    let search = (
+        uuid.to_owned(),
+        room.to_owned(),
+        query.to_owned(),
+        offset,
+        limit,
+    );
+
+    match state
+        .request(StateRequest::Search(search), "search")
+        .await?
+    {
+        StateResponse::Search(page) => Ok(Some(page)),
+        StateResponse::NotFound => Ok(None),
+        _ => Err(RelayError::UnexpectedResponse("search")),
+    }
+}
+
+/// Claim a nickname for a session, returning `false` if another session already holds it.
+pub async fn set_nickname(
+    state: &StateSender,
+    uuid: &str,
+    nickname: &str,
+) -> Result<bool, RelayError> {
+    match state
+        .request(
+            StateRequest::SetNickname((uuid.to_owned(), nickname.to_owned())),
+            "set_nickname",
+        )
+        .await?
+    {
+        StateResponse::Ok => {
+            info!("set nickname -> {:?}", nickname);
+
+            Ok(true)
+        }
+        StateResponse::NicknameTaken => Ok(false),
+        _ => Err(RelayError::UnexpectedResponse("set_nickname")),
     }
 }
 
-pub async fn shutdown(state: &StateSender) -> Result<(), Box<dyn std::error::Error>> {
-    let (_request, _response) = oneshot::channel();
+pub async fn shutdown(state: &StateSender) -> Result<(), RelayError> {
+    match state.request(StateRequest::Shutdown, "shutdown").await? {
+        StateResponse::Ok => Ok(()),
+        _ => Err(RelayError::UnexpectedResponse("shutdown")),
+    }
+}
 
-    state.send((StateRequest::Shutdown, _request)).await?;
+/// Mark a user as typing in a room until the typing timeout passes without another call.
+pub async fn typing(state: &StateSender, uuid: &str, room: &str) -> Result<(), RelayError> {
+    match state
+        .request(
+            StateRequest::Typing((uuid.to_owned(), room.to_owned())),
+            "typing",
+        )
+        .await?
+    {
+        StateResponse::Ok => Ok(()),
+        StateResponse::NotFound => {
+            info!("typing in a closed room -> {:?}", room);
 
-    Ok(())
+            Ok(())
+        }
+        _ => Err(RelayError::UnexpectedResponse("typing")),
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::never_loop)]
 mod tests {
     use super::*;
+    use crate::store::timestamp;
     use std::str::FromStr;
     use uuid::Uuid;
 
@@ -146,45 +900,66 @@ mod tests {
     async fn add_message() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, mut test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
 
         let test_task = tokio::spawn(async move {
             let mut test_state_messages = Vec::with_capacity(5);
 
             assert_eq!(test_state_messages.len(), 0);
 
-            while let Some((test_request, _test_response)) = test_state_receiver.recv().await {
-                match test_request {
-                    StateRequest::AddMessage(test_new_message) => {
-                        test_state_messages.push(test_new_message);
-
-                        break;
-                    }
-                    StateRequest::AddUser(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::GetMessages => {
-                        unimplemented!();
-                    }
-                    StateRequest::GetUser(_) => unimplemented!(),
-                    StateRequest::GetUsers => {
-                        unimplemented!();
-                    }
-                    StateRequest::RemoveUser(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::Shutdown => {
-                        unimplemented!();
+            for test_sequence in [1, 2] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::AddMessage(test_new_message) => {
+                            assert_eq!(test_new_message.room, "test_room");
+
+                            test_state_messages.push(test_new_message.to_owned());
+
+                            let test_accepted = MessageRecord {
+                                id: format!("test_id_{}", test_sequence),
+                                sequence: test_sequence,
+                                ..test_new_message
+                            };
+                            let test_response_to_send = match test_accepted.reply_to {
+                                Some(_) => StateResponse::Reply((test_accepted, 1)),
+                                None => StateResponse::Message(test_accepted),
+                            };
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        _ => unimplemented!(),
                     }
                 }
             }
 
-            assert_eq!(test_state_messages.len(), 1);
+            assert_eq!(test_state_messages.len(), 2);
         });
 
-        let test_message = Message::text("test_message");
-
-        super::add_message(&test_state_sender, &test_message).await?;
-
+        let test_message = MessageRecord {
+            room: String::from("test_room"),
+            contents: String::from("test_message"),
+            timestamp: timestamp().await,
+            ..MessageRecord::default()
+        };
+
+        let (test_accepted, test_replies) = super::add_message(&test_state_sender, &test_message)
+            .await?
+            .expect("accepted");
+
+        assert_eq!(test_accepted.id, "test_id_1");
+        assert_eq!(test_accepted.sequence, 1);
+        assert_eq!(test_replies, None);
+
+        let test_reply = MessageRecord {
+            reply_to: Some(test_accepted.id),
+            ..test_message
+        };
+        let (test_accepted, test_replies) = super::add_message(&test_state_sender, &test_reply)
+            .await?
+            .expect("accepted");
+
+        assert_eq!(test_accepted.reply_to.as_deref(), Some("test_id_1"));
+        assert_eq!(test_replies, Some(1));
         assert!(test_task.await.is_ok());
 
         Ok(())
@@ -194,6 +969,7 @@ mod tests {
     async fn add_user() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, mut test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
 
         let test_task = tokio::spawn(async move {
             let mut test_state_users = HashMap::with_capacity(5);
@@ -202,31 +978,16 @@ mod tests {
 
             while let Some((test_request, test_response)) = test_state_receiver.recv().await {
                 match test_request {
-                    StateRequest::AddMessage(_) => {
-                        unimplemented!();
-                    }
                     StateRequest::AddUser((test_id, test_channel)) => {
                         let test_none = test_state_users.insert(test_id, test_channel);
 
                         assert!(test_none.is_none());
 
-                        test_response.send(StateResponse::Ok).unwrap();
+                        test_response.send(StateResponse::Nickname(None)).unwrap();
 
                         break;
                     }
-                    StateRequest::GetMessages => {
-                        unimplemented!();
-                    }
-                    StateRequest::GetUser(_) => unimplemented!(),
-                    StateRequest::GetUsers => {
-                        unimplemented!();
-                    }
-                    StateRequest::RemoveUser(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::Shutdown => {
-                        unimplemented!();
-                    }
+                    _ => unimplemented!(),
                 }
             }
 
@@ -234,7 +995,8 @@ mod tests {
         });
 
         let test_uuid = uuid::Uuid::new_v4().to_string();
-        let (test_websocket_sender, test_websocket_receiver) = mpsc::channel(16);
+        let (test_websocket_sender, test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
 
         drop(test_websocket_receiver);
 
@@ -246,68 +1008,386 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn get_messages() -> Result<(), Box<dyn std::error::Error>> {
+    async fn direct_message() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, mut test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
 
         let test_task = tokio::spawn(async move {
-            let mut test_state_messages = Vec::with_capacity(5);
-
-            assert_eq!(test_state_messages.len(), 0);
-
-            let test_message = Message::text("test_message");
-
-            test_state_messages.push(test_message);
+            for test_response_to_send in [
+                StateResponse::Rooms(vec![String::from("test_room")]),
+                StateResponse::NotFound,
+            ] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::DirectMessage(test_message) => {
+                            assert_eq!(test_message.recipient.as_deref(), Some("test_nickname"));
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        _ => unimplemented!(),
+                    }
+                }
+            }
+        });
 
-            assert_eq!(test_state_messages.len(), 1);
+        let test_message = MessageRecord {
+            uuid: String::from("test_uuid"),
+            contents: String::from("test_contents"),
+            recipient: Some(String::from("test_nickname")),
+            ..MessageRecord::default()
+        };
+
+        assert_eq!(
+            super::direct_message(&test_state_sender, &test_message).await?,
+            vec![String::from("test_room")],
+        );
+        assert_eq!(
+            super::direct_message(&test_state_sender, &test_message).await,
+            Err(RelayError::UserNotFound(String::from("test_nickname"))),
+        );
+        assert!(test_task.await.is_ok());
 
-            while let Some((test_request, test_response)) = test_state_receiver.recv().await {
-                match test_request {
-                    StateRequest::AddMessage(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::AddUser(_) => {
-                        unimplemented!()
-                    }
-                    StateRequest::GetMessages => {
-                        let test_messages = test_state_messages.to_vec();
+        Ok(())
+    }
 
-                        test_response
-                            .send(StateResponse::Messages(test_messages))
-                            .unwrap();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn amend_message() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
 
-                        break;
-                    }
-                    StateRequest::GetUser(_) => unimplemented!(),
-                    StateRequest::GetUsers => {
-                        unimplemented!();
-                    }
-                    StateRequest::RemoveUser(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::Shutdown => {
-                        unimplemented!();
+        let test_task = tokio::spawn(async move {
+            for test_response_to_send in [
+                StateResponse::Message(MessageRecord {
+                    id: String::from("test_id"),
+                    contents: String::from("test_edit"),
+                    ..MessageRecord::default()
+                }),
+                StateResponse::NotAuthor,
+                StateResponse::NotFound,
+            ] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::EditMessage((test_uuid, test_id, test_contents)) => {
+                            assert_eq!(test_uuid, "test_uuid");
+                            assert_eq!(test_id, "test_id");
+                            assert_eq!(test_contents, "test_edit");
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        StateRequest::DeleteMessage((test_uuid, test_id)) => {
+                            assert_eq!(test_uuid, "test_uuid");
+                            assert_eq!(test_id, "test_id");
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        _ => unimplemented!(),
                     }
                 }
             }
         });
 
-        let test_messages = super::get_messages(&test_state_sender).await?;
-
+        assert_eq!(
+            super::edit_message(&test_state_sender, "test_uuid", "test_id", "test_edit")
+                .await?
+                .contents,
+            "test_edit",
+        );
+        assert_eq!(
+            super::edit_message(&test_state_sender, "test_uuid", "test_id", "test_edit").await,
+            Err(RelayError::NotAuthor(String::from("test_id"))),
+        );
+        assert_eq!(
+            super::delete_message(&test_state_sender, "test_uuid", "test_id").await,
+            Err(RelayError::MessageNotFound(String::from("test_id"))),
+        );
         assert!(test_task.await.is_ok());
-        assert_eq!(test_messages.len(), 1);
-
-        for test_message in &test_messages {
-            assert_eq!(test_message.to_str().unwrap(), "test_message");
-        }
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn get_users() -> Result<(), Box<dyn std::error::Error>> {
+    async fn react() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, mut test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+        let test_event = ReactionEvent {
+            room: String::from("test_room"),
+            id: String::from("test_id"),
+            emoji: String::from("👍"),
+            uuid: String::from("test_uuid"),
+            count: 1,
+        };
+        let test_reacted = test_event.to_owned();
+
+        let test_task = tokio::spawn(async move {
+            for test_response_to_send in [
+                StateResponse::Reacted(test_reacted),
+                StateResponse::Ok,
+                StateResponse::NotFound,
+            ] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::AddReaction((test_uuid, test_id, test_emoji))
+                        | StateRequest::RemoveReaction((test_uuid, test_id, test_emoji)) => {
+                            assert_eq!(test_uuid, "test_uuid");
+                            assert_eq!(test_id, "test_id");
+                            assert_eq!(test_emoji, "👍");
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        _ => unimplemented!(),
+                    }
+                }
+            }
+        });
+
+        assert_eq!(
+            super::react(&test_state_sender, "test_uuid", "test_id", "👍", true).await?,
+            Some(test_event),
+        );
+        assert_eq!(
+            super::react(&test_state_sender, "test_uuid", "test_id", "👍", false).await?,
+            None,
+        );
+        assert_eq!(
+            super::react(&test_state_sender, "test_uuid", "test_id", "👍", true).await,
+            Err(RelayError::MessageNotFound(String::from("test_id"))),
+        );
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_history() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            for test_response_to_send in [
+                StateResponse::History((vec![MessageRecord::default()], true)),
+                StateResponse::NotFound,
+            ] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::GetHistory((test_room, test_before, test_limit)) => {
+                            assert_eq!(test_room, "test_room");
+                            assert_eq!(test_before.as_deref(), Some("test_id"));
+                            assert_eq!(test_limit, 10);
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        _ => unimplemented!(),
+                    }
+                }
+            }
+        });
+
+        assert_eq!(
+            super::get_history(&test_state_sender, "test_room", Some("test_id"), 10).await?,
+            (vec![MessageRecord::default()], true),
+        );
+        assert_eq!(
+            super::get_history(&test_state_sender, "test_room", Some("test_id"), 10).await,
+            Err(RelayError::MessageNotFound(String::from("test_id"))),
+        );
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_missed() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                match test_request {
+                    StateRequest::GetMissed((test_room, test_after, test_limit)) => {
+                        assert_eq!(test_room, "test_room");
+                        assert_eq!(test_after, 3);
+                        assert_eq!(test_limit, 10);
+
+                        test_response
+                            .send(StateResponse::History((
+                                vec![MessageRecord::default()],
+                                true,
+                            )))
+                            .unwrap();
+                    }
+                    _ => unimplemented!(),
+                }
+            }
+        });
+
+        assert_eq!(
+            super::get_missed(&test_state_sender, "test_room", 3, 10).await?,
+            (vec![MessageRecord::default()], true),
+        );
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn search() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            while let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                match test_request {
+                    StateRequest::Search((
+                        test_uuid,
+                        test_room,
+                        test_query,
+                        test_offset,
+                        test_limit,
+                    )) => {
+                        assert_eq!(test_room, "test_room");
+                        assert_eq!(test_query, "test_query");
+                        assert_eq!(test_offset, 5);
+                        assert_eq!(test_limit, 10);
+
+                        let test_page = SearchPage {
+                            room: test_room,
+                            query: test_query,
+                            offset: test_offset,
+                            total: 0,
+                            results: Vec::new(),
+                        };
+                        let test_state_response = match test_uuid.as_str() {
+                            "test_uuid" => StateResponse::Search(test_page),
+                            _ => StateResponse::NotFound,
+                        };
+
+                        test_response.send(test_state_response).unwrap();
+                    }
+                    _ => unimplemented!(),
+                }
+            }
+        });
+
+        let test_page = super::search(
+            &test_state_sender,
+            "test_uuid",
+            "test_room",
+            "test_query",
+            5,
+            10,
+        )
+        .await?
+        .expect("member");
+
+        assert_eq!(test_page.room, "test_room");
+        assert_eq!(test_page.offset, 5);
+        assert!(test_page.results.is_empty());
+        assert_eq!(
+            super::search(
+                &test_state_sender,
+                "test_outsider",
+                "test_room",
+                "test_query",
+                5,
+                10
+            )
+            .await?,
+            None,
+        );
+
+        drop(test_state_sender);
+
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_conversation() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                match test_request {
+                    StateRequest::GetConversation((test_uuid, test_with)) => {
+                        assert_eq!(test_uuid, "test_uuid");
+                        assert_eq!(test_with, "test_nickname");
+
+                        let test_messages = vec![MessageRecord {
+                            contents: String::from("test_contents"),
+                            ..MessageRecord::default()
+                        }];
+
+                        test_response
+                            .send(StateResponse::Messages(test_messages))
+                            .unwrap();
+                    }
+                    _ => unimplemented!(),
+                }
+            }
+        });
+
+        let test_messages =
+            super::get_conversation(&test_state_sender, "test_uuid", "test_nickname").await?;
+
+        assert!(test_task.await.is_ok());
+        assert_eq!(test_messages.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_presence() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            for test_response_to_send in [
+                StateResponse::Presence(vec![Presence {
+                    uuid: String::from("test_uuid"),
+                    nickname: String::from("test_nickname"),
+                }]),
+                StateResponse::NotFound,
+            ] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::GetPresence(test_room) => {
+                            assert_eq!(test_room, "test_room");
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        _ => unimplemented!(),
+                    }
+                }
+            }
+        });
+
+        let test_presence = super::get_presence(&test_state_sender, "test_room").await?;
+
+        assert_eq!(test_presence.len(), 1);
+        assert_eq!(test_presence[0].nickname, "test_nickname");
+        assert!(super::get_presence(&test_state_sender, "test_room")
+            .await?
+            .is_empty());
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_users() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
 
         let test_task = tokio::spawn(async move {
             let mut test_state_users = HashMap::with_capacity(5);
@@ -315,7 +1395,8 @@ mod tests {
             assert_eq!(test_state_users.len(), 0);
 
             let test_uuid = uuid::Uuid::new_v4().to_string();
-            let (test_websocket_sender, test_websocket_receiver) = mpsc::channel(16);
+            let (test_websocket_sender, test_websocket_receiver) =
+                outbound(16, SlowConsumer::DropOldest).await;
 
             drop(test_websocket_receiver);
 
@@ -326,34 +1407,21 @@ mod tests {
 
             while let Some((test_request, test_response)) = test_state_receiver.recv().await {
                 match test_request {
-                    StateRequest::AddMessage(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::AddUser(_) => {
-                        unimplemented!()
-                    }
-                    StateRequest::GetMessages => {
-                        unimplemented!();
-                    }
-                    StateRequest::GetUser(_) => unimplemented!(),
-                    StateRequest::GetUsers => {
+                    StateRequest::GetUsers(test_room) => {
+                        assert_eq!(test_room, "test_room");
+
                         test_response
                             .send(StateResponse::Users(test_state_users.clone()))
                             .unwrap();
 
                         break;
                     }
-                    StateRequest::RemoveUser(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::Shutdown => {
-                        unimplemented!();
-                    }
+                    _ => unimplemented!(),
                 }
             }
         });
 
-        let test_users = super::get_users(&test_state_sender).await?;
+        let test_users = super::get_users(&test_state_sender, "test_room").await?;
 
         assert!(test_task.await.is_ok());
         assert_eq!(test_users.len(), 1);
@@ -365,49 +1433,107 @@ mod tests {
                     .get_version_num(),
                 4,
             );
-            assert!(test_websocket_connection.is_closed());
+            assert!(test_websocket_connection.is_closed().await);
         }
 
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn join_room() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let test_lookup_uuid = test_uuid.clone();
+
+        let test_task = tokio::spawn(async move {
+            let mut test_state_rooms = HashMap::with_capacity(5);
+
+            if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                match test_request {
+                    StateRequest::JoinRoom((test_id, test_room)) => {
+                        test_state_rooms.insert(test_id, test_room);
+
+                        test_response.send(StateResponse::Ok).unwrap();
+                    }
+                    _ => unimplemented!(),
+                }
+            }
+
+            assert_eq!(test_state_rooms.get(&test_uuid).unwrap(), "test_room");
+        });
+
+        super::join_room(&test_state_sender, &test_lookup_uuid, "test_room").await?;
+
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn leave_room() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let test_lookup_uuid = test_uuid.clone();
+
+        let test_task = tokio::spawn(async move {
+            let mut test_state_rooms = HashMap::with_capacity(5);
+
+            test_state_rooms.insert(test_uuid.to_owned(), String::from("test_room"));
+
+            if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                match test_request {
+                    StateRequest::LeaveRoom((test_id, test_room)) => {
+                        assert_eq!(test_room, "test_room");
+
+                        test_state_rooms.remove(&test_id);
+
+                        test_response.send(StateResponse::Ok).unwrap();
+                    }
+                    _ => unimplemented!(),
+                }
+            }
+
+            assert!(test_state_rooms.is_empty());
+        });
+
+        super::leave_room(&test_state_sender, &test_lookup_uuid, "test_room").await?;
+
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn remove_user() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, mut test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
 
         let test_uuid = uuid::Uuid::new_v4().to_string();
         let test_lookup_uuid = test_uuid.clone();
+        let (test_websocket_sender, test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+        let test_state_connection = test_websocket_sender.to_owned();
+
+        drop(test_websocket_receiver);
 
         let test_task = tokio::spawn(async move {
             let mut test_state_users = HashMap::with_capacity(5);
 
             assert_eq!(test_state_users.len(), 0);
 
-            let (test_websocket_sender, test_websocket_receiver) =
-                mpsc::channel::<WebSocketConnection>(16);
-
-            drop(test_websocket_receiver);
-
-            test_state_users.insert(test_uuid, test_websocket_sender);
+            test_state_users.insert(test_uuid, test_state_connection);
 
             assert_eq!(test_state_users.len(), 1);
 
             while let Some((test_request, test_response)) = test_state_receiver.recv().await {
                 match test_request {
-                    StateRequest::AddMessage(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::AddUser(_) => {
-                        unimplemented!()
-                    }
-                    StateRequest::GetMessages => {
-                        unimplemented!();
-                    }
-                    StateRequest::GetUser(_) => unimplemented!(),
-                    StateRequest::GetUsers => {
-                        unimplemented!()
-                    }
                     StateRequest::RemoveUser(_) => {
                         test_state_users.clear();
 
@@ -415,16 +1541,19 @@ mod tests {
 
                         break;
                     }
-                    StateRequest::Shutdown => {
-                        unimplemented!();
-                    }
+                    _ => unimplemented!(),
                 }
             }
 
             assert_eq!(test_state_users.len(), 0);
         });
 
-        super::remove_user(&test_state_sender, &test_lookup_uuid).await?;
+        super::remove_user(
+            &test_state_sender,
+            &test_lookup_uuid,
+            &test_websocket_sender,
+        )
+        .await?;
 
         assert!(test_task.await.is_ok());
 
@@ -432,32 +1561,294 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    async fn outbound_policies() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = |test_contents: &str| {
+            WebSocketConnection::SendMessage(Arc::new(Message::text(test_contents)))
+        };
+
+        for (test_policy, test_expected) in [
+            (SlowConsumer::DropOldest, ["test_two", "test_three"]),
+            (SlowConsumer::DropNewest, ["test_one", "test_two"]),
+        ] {
+            let (test_sender, mut test_receiver) = outbound(2, test_policy).await;
+
+            assert_eq!(
+                test_sender.push(test_frame("test_one")).await,
+                Delivery::Queued
+            );
+            assert_eq!(
+                test_sender.push(test_frame("test_two")).await,
+                Delivery::Queued
+            );
+            assert_eq!(
+                test_sender.push(test_frame("test_three")).await,
+                Delivery::Dropped
+            );
+            assert_eq!(
+                test_sender.push(WebSocketConnection::Close).await,
+                Delivery::Queued
+            );
+            assert_eq!(test_sender.dropped().await, 1);
+
+            for test_contents in test_expected {
+                match test_receiver.recv().await {
+                    Some(WebSocketConnection::SendMessage(test_message)) => {
+                        assert_eq!(test_message.to_str(), Ok(test_contents));
+                    }
+                    test_received => panic!("expected message -> {:?}", test_received),
+                }
+            }
+
+            assert!(matches!(
+                test_receiver.recv().await,
+                Some(WebSocketConnection::Close),
+            ));
+
+            drop(test_receiver);
+
+            assert!(test_sender.is_closed().await);
+            assert_eq!(
+                test_sender.push(test_frame("test_four")).await,
+                Delivery::Closed
+            );
+            assert!(test_sender.send(test_frame("test_four")).await.is_err());
+        }
+
+        let (test_sender, mut test_receiver) = outbound(2, SlowConsumer::Disconnect).await;
+
+        test_sender.push(test_frame("test_one")).await;
+        test_sender.push(test_frame("test_two")).await;
+
+        assert_eq!(
+            test_sender.push(test_frame("test_three")).await,
+            Delivery::Disconnected,
+        );
+        assert!(test_sender.is_closed().await);
+        assert!(test_sender.disconnected().await);
+        assert_eq!(test_sender.dropped().await, 3);
+        assert_eq!(
+            test_sender.push(test_frame("test_four")).await,
+            Delivery::Closed
+        );
+        assert!(matches!(
+            test_receiver.recv().await,
+            Some(WebSocketConnection::Disconnect),
+        ));
+        assert!(test_receiver.recv().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn outbound_capacity() -> Result<(), Box<dyn std::error::Error>> {
+        for test_policy in [SlowConsumer::DropOldest, SlowConsumer::DropNewest] {
+            let (test_sender, mut test_receiver) = outbound(4, test_policy).await;
+
+            for test_frame in 0..100 {
+                test_sender
+                    .push(WebSocketConnection::SendMessage(Arc::new(Message::text(
+                        test_frame.to_string(),
+                    ))))
+                    .await;
+
+                let test_queue = test_sender.outbound.lock().await;
+
+                assert!(test_queue.messages <= 4);
+                assert!(test_queue.frames.len() <= 4);
+            }
+
+            assert_eq!(test_sender.dropped().await, 96);
+
+            drop(test_sender);
+
+            let mut test_received = Vec::with_capacity(4);
+
+            while let Some(WebSocketConnection::SendMessage(test_message)) =
+                test_receiver.recv().await
+            {
+                test_received.push(test_message.to_str().unwrap_or_default().to_owned());
+            }
+
+            let test_expected = match test_policy {
+                SlowConsumer::DropOldest => ["96", "97", "98", "99"],
+                _ => ["0", "1", "2", "3"],
+            };
+
+            assert_eq!(test_received, test_expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relay_errors() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, mut test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
 
         let test_task = tokio::spawn(async move {
-            while let Some((test_request, _test_response)) = test_state_receiver.recv().await {
-                match test_request {
-                    StateRequest::AddMessage(_) => {
-                        unimplemented!();
-                    }
-                    StateRequest::AddUser(_) => {
-                        unimplemented!()
-                    }
-                    StateRequest::GetMessages => {
-                        unimplemented!();
+            for test_state_response in [
+                StateResponse::NicknameTaken,
+                StateResponse::Error(RelayError::UserNotFound(String::from("test_uuid"))),
+            ] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::JoinRoom(_) => {
+                            test_response.send(test_state_response).unwrap();
+                        }
+                        _ => unimplemented!(),
                     }
-                    StateRequest::GetUser(_) => unimplemented!(),
-                    StateRequest::GetUsers => {
-                        unimplemented!()
+                }
+            }
+
+            if let Some((_test_request, test_response)) = test_state_receiver.recv().await {
+                drop(test_response);
+            }
+        });
+
+        assert_eq!(
+            super::join_room(&test_state_sender, "test_uuid", "test_room").await,
+            Err(RelayError::UnexpectedResponse("join_room")),
+        );
+        assert_eq!(
+            super::join_room(&test_state_sender, "test_uuid", "test_room").await,
+            Err(RelayError::UserNotFound(String::from("test_uuid"))),
+        );
+        assert_eq!(
+            super::get_users(&test_state_sender, "test_room")
+                .await
+                .unwrap_err(),
+            RelayError::StateChannelClosed,
+        );
+        assert!(test_task.await.is_ok());
+        assert_eq!(
+            super::get_users(&test_state_sender, "test_room")
+                .await
+                .unwrap_err(),
+            RelayError::StateChannelClosed,
+        );
+        assert_eq!(
+            RelayError::UnexpectedResponse("join_room").to_string(),
+            "unexpected state response to join_room",
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn timeout() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(1);
+        let test_state_sender =
+            StateSender::init(test_state_sender, Duration::from_millis(50)).await;
+
+        let test_task = tokio::spawn(async move {
+            let mut test_held = Vec::with_capacity(1);
+
+            if let Some(test_exchange) = test_state_receiver.recv().await {
+                test_held.push(test_exchange);
+            }
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        });
+
+        assert_eq!(
+            super::get_users(&test_state_sender, "test_room")
+                .await
+                .unwrap_err(),
+            RelayError::Timeout("get_users"),
+        );
+        assert_eq!(
+            super::add_message(&test_state_sender, &MessageRecord::default()).await,
+            Err(RelayError::Timeout("add_message")),
+        );
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn broadcast() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                match test_request {
+                    StateRequest::Broadcast((test_audience, test_message)) => {
+                        assert_eq!(test_audience, Audience::Room(String::from("test_room")));
+                        assert_eq!(test_message.to_str(), Ok("test_message"));
+
+                        let test_rooms = vec![String::from("test_room")];
+
+                        test_response
+                            .send(StateResponse::Rooms(test_rooms))
+                            .unwrap();
                     }
-                    StateRequest::RemoveUser(_) => {
-                        unimplemented!();
+                    _ => unimplemented!(),
+                }
+            }
+        });
+
+        assert_eq!(
+            super::broadcast(
+                &test_state_sender,
+                Audience::Room(String::from("test_room")),
+                Arc::new(Message::text("test_message")),
+            )
+            .await?,
+            vec![String::from("test_room")],
+        );
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn set_nickname() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            for test_state_response in [StateResponse::Ok, StateResponse::NicknameTaken] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::SetNickname((test_uuid, test_nickname)) => {
+                            assert_eq!(test_uuid, "test_uuid");
+                            assert_eq!(test_nickname, "test_nickname");
+
+                            test_response.send(test_state_response).unwrap();
+                        }
+                        _ => unimplemented!(),
                     }
+                }
+            }
+        });
+
+        assert!(super::set_nickname(&test_state_sender, "test_uuid", "test_nickname").await?);
+        assert!(!super::set_nickname(&test_state_sender, "test_uuid", "test_nickname").await?);
+        assert!(test_task.await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_mpsc_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender =
+            StateSender::init(test_mpsc_sender.to_owned(), Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            while let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                match test_request {
                     StateRequest::Shutdown => {
                         test_state_receiver.close();
+                        test_response.send(StateResponse::Ok).unwrap();
                     }
+                    _ => unimplemented!(),
                 }
             }
         });
@@ -465,7 +1856,37 @@ mod tests {
         super::shutdown(&test_state_sender).await?;
 
         assert!(test_task.await.is_ok());
-        assert!(test_state_sender.is_closed());
+        assert!(test_mpsc_sender.is_closed());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn typing() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, mut test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let test_task = tokio::spawn(async move {
+            for test_response_to_send in [StateResponse::Ok, StateResponse::NotFound] {
+                if let Some((test_request, test_response)) = test_state_receiver.recv().await {
+                    match test_request {
+                        StateRequest::Typing((test_uuid, test_room)) => {
+                            assert_eq!(test_uuid, "test_uuid");
+                            assert_eq!(test_room, "test_room");
+
+                            test_response.send(test_response_to_send).unwrap();
+                        }
+                        _ => unimplemented!(),
+                    }
+                }
+            }
+        });
+
+        super::typing(&test_state_sender, "test_uuid", "test_room").await?;
+        super::typing(&test_state_sender, "test_uuid", "test_room").await?;
+
+        assert!(test_task.await.is_ok());
 
         Ok(())
     }
@@ -0,0 +1,820 @@
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Deserializer};
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_CONFIG_FILE: &str = "relay.toml";
+
+/// Command line flags, each of which can also be set with a `RELAY_*` environment variable.
+///
+/// Every value is optional so that anything left unset can fall through to `relay.toml` and then
+/// to the built in defaults.
+#[derive(Debug, Default, Parser)]
+#[command(
+    name = "relay",
+    version,
+    about = "r e l a y - a sample websockets chat app"
+)]
+pub struct Flags {
+    /// Path to a TOML config file [default: relay.toml, if present]
+    #[arg(long, env = "RELAY_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to bind the server to [default: 0.0.0.0]
+    #[arg(long, env = "RELAY_ADDRESS")]
+    pub address: Option<IpAddr>,
+    /// Port to bind the server to [default: 1806]
+    #[arg(long, env = "RELAY_PORT")]
+    pub port: Option<u16>,
+    /// Path the websocket upgrade is served on [default: /ws]
+    #[arg(long, env = "RELAY_PATH")]
+    pub path: Option<String>,
+    /// Depth of the channel used to send requests to state [default: 64]
+    #[arg(long, env = "RELAY_STATE_CHANNEL_CAPACITY")]
+    pub state_channel_capacity: Option<usize>,
+    /// Depth of each connection's outgoing message channel [default: 16]
+    #[arg(long, env = "RELAY_SINK_CAPACITY")]
+    pub sink_capacity: Option<usize>,
+    /// What happens to frames for a connection whose outgoing channel is full [default: drop-oldest]
+    #[arg(long, env = "RELAY_SLOW_CONSUMER", value_enum)]
+    pub slow_consumer: Option<SlowConsumer>,
+    /// Number of messages history is initially allocated for [default: 100]
+    #[arg(long, env = "RELAY_HISTORY_CAPACITY")]
+    pub history_capacity: Option<usize>,
+    /// Messages replayed on entering a room, and the most a fetched page of history holds [default: 50]
+    #[arg(long, env = "RELAY_HISTORY_PAGE_SIZE")]
+    pub history_page_size: Option<usize>,
+    /// Where message history is kept [default: memory]
+    #[arg(long, env = "RELAY_STORAGE", value_enum)]
+    pub storage: Option<Storage>,
+    /// File message history is appended to when using file storage [default: relay.log]
+    #[arg(long, env = "RELAY_STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+    /// Most messages kept in each room's history [default: unlimited]
+    #[arg(long, env = "RELAY_RETENTION_MAX_MESSAGES")]
+    pub retention_max_messages: Option<usize>,
+    /// Most bytes of message contents kept in each room's history [default: unlimited]
+    #[arg(long, env = "RELAY_RETENTION_MAX_BYTES")]
+    pub retention_max_bytes: Option<usize>,
+    /// Oldest a message in history can be, such as 90s, 30m, 12h or 7d [default: unlimited]
+    #[arg(long, env = "RELAY_RETENTION_MAX_AGE", value_parser = parse_duration)]
+    pub retention_max_age: Option<Duration>,
+    /// Treat text frames that are not JSON as chat messages [default: true]
+    #[arg(long, env = "RELAY_PLAIN_TEXT")]
+    pub plain_text: Option<bool>,
+    /// Secret HS256 signed JWTs are verified with, requiring a token to connect [default: unset]
+    #[arg(long, env = "RELAY_AUTH_JWT_SECRET", hide_env_values = true)]
+    pub auth_jwt_secret: Option<String>,
+    /// File of `subject token` lines, requiring a token to connect [default: unset]
+    #[arg(long, env = "RELAY_AUTH_TOKEN_FILE")]
+    pub auth_token_file: Option<PathBuf>,
+    /// How long a dropped session can be resumed for, or 0 to disable resumption [default: 2m]
+    #[arg(long, env = "RELAY_SESSION_GRACE_PERIOD", value_parser = parse_duration)]
+    pub session_grace_period: Option<Duration>,
+    /// How often each connection is pinged [default: 30s]
+    #[arg(long, env = "RELAY_HEARTBEAT_INTERVAL", value_parser = parse_duration)]
+    pub heartbeat_interval: Option<Duration>,
+    /// How long without a pong before a connection is closed [default: 90s]
+    #[arg(long, env = "RELAY_HEARTBEAT_TIMEOUT", value_parser = parse_duration)]
+    pub heartbeat_timeout: Option<Duration>,
+    /// Directory attachments are stored in [default: attachments]
+    #[arg(long, env = "RELAY_ATTACHMENT_DIR")]
+    pub attachment_dir: Option<PathBuf>,
+    /// Largest attachment accepted, in bytes [default: 10485760]
+    #[arg(long, env = "RELAY_ATTACHMENT_MAX_BYTES")]
+    pub attachment_max_bytes: Option<usize>,
+    /// How long a connection waits on state before giving up on a request [default: 5s]
+    #[arg(long, env = "RELAY_STATE_TIMEOUT", value_parser = parse_duration)]
+    pub state_timeout: Option<Duration>,
+    /// How long a typing signal lasts without being refreshed [default: 5s]
+    #[arg(long, env = "RELAY_TYPING_TIMEOUT", value_parser = parse_duration)]
+    pub typing_timeout: Option<Duration>,
+}
+
+/// Values read from a `relay.toml` file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct File {
+    pub address: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub path: Option<String>,
+    pub state_channel_capacity: Option<usize>,
+    pub sink_capacity: Option<usize>,
+    pub slow_consumer: Option<SlowConsumer>,
+    pub history_capacity: Option<usize>,
+    pub history_page_size: Option<usize>,
+    pub storage: Option<Storage>,
+    pub storage_path: Option<PathBuf>,
+    pub retention_max_messages: Option<usize>,
+    pub retention_max_bytes: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub retention_max_age: Option<Duration>,
+    pub plain_text: Option<bool>,
+    pub auth_jwt_secret: Option<String>,
+    pub auth_token_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub session_grace_period: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub heartbeat_interval: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub heartbeat_timeout: Option<Duration>,
+    pub attachment_dir: Option<PathBuf>,
+    pub attachment_max_bytes: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub state_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub typing_timeout: Option<Duration>,
+}
+
+/// Backends message history can be kept in.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// Held by state only, and lost on restart
+    #[default]
+    Memory,
+    /// Appended to a log file and reloaded on startup
+    File,
+}
+
+/// What to do with a frame for a connection that has fallen so far behind that its outgoing
+/// channel is full.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumer {
+    /// Discard the oldest queued frame to make room
+    #[default]
+    DropOldest,
+    /// Discard the new frame
+    DropNewest,
+    /// Close the connection with a policy violation close code
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Port,
+    Path(String),
+    Capacity(&'static str),
+    StoragePath,
+    Retention(&'static str),
+    Auth(&'static str),
+    Heartbeat,
+    AttachmentDir,
+    StateTimeout,
+    TypingTimeout,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => {
+                write!(f, "unable to read config file {:?} -> {}", path, error)
+            }
+            ConfigError::Parse(path, error) => {
+                write!(f, "unable to parse config file {:?} -> {}", path, error)
+            }
+            ConfigError::Port => write!(f, "port must be greater than 0"),
+            ConfigError::Path(path) => write!(
+                f,
+                "path {:?} must be a single, non-empty segment such as \"/ws\"",
+                path,
+            ),
+            ConfigError::Capacity(name) => write!(f, "{} must be greater than 0", name),
+            ConfigError::StoragePath => write!(f, "storage_path must not be empty"),
+            ConfigError::Retention(name) => {
+                write!(f, "{} must be greater than 0, or left unset", name)
+            }
+            ConfigError::Auth(name) => write!(f, "{} must not be empty, or left unset", name),
+            ConfigError::Heartbeat => write!(
+                f,
+                "heartbeat_interval must be greater than 0 and less than heartbeat_timeout",
+            ),
+            ConfigError::AttachmentDir => write!(f, "attachment_dir must not be empty"),
+            ConfigError::StateTimeout => write!(f, "state_timeout must be greater than 0"),
+            ConfigError::TypingTimeout => write!(f, "typing_timeout must be greater than 0"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub path: String,
+    pub state_channel_capacity: usize,
+    pub sink_capacity: usize,
+    pub slow_consumer: SlowConsumer,
+    pub history_capacity: usize,
+    pub history_page_size: usize,
+    pub storage: Storage,
+    pub storage_path: PathBuf,
+    pub retention_max_messages: Option<usize>,
+    pub retention_max_bytes: Option<usize>,
+    pub retention_max_age: Option<Duration>,
+    pub plain_text: bool,
+    pub auth_jwt_secret: Option<String>,
+    pub auth_token_file: Option<PathBuf>,
+    pub session_grace_period: Duration,
+    pub heartbeat_interval: Duration,
+    pub heartbeat_timeout: Duration,
+    pub attachment_dir: PathBuf,
+    pub attachment_max_bytes: usize,
+    pub state_timeout: Duration,
+    pub typing_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 1806,
+            path: String::from("ws"),
+            state_channel_capacity: 64,
+            sink_capacity: 16,
+            slow_consumer: SlowConsumer::DropOldest,
+            history_capacity: 100,
+            history_page_size: 50,
+            storage: Storage::Memory,
+            storage_path: PathBuf::from("relay.log"),
+            retention_max_messages: None,
+            retention_max_bytes: None,
+            retention_max_age: None,
+            plain_text: true,
+            auth_jwt_secret: None,
+            auth_token_file: None,
+            session_grace_period: Duration::from_secs(120),
+            heartbeat_interval: Duration::from_secs(30),
+            heartbeat_timeout: Duration::from_secs(90),
+            attachment_dir: PathBuf::from("attachments"),
+            attachment_max_bytes: 10 * 1024 * 1024,
+            state_timeout: Duration::from_secs(5),
+            typing_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Config {
+    /// Build the config from, in order of precedence, command line flags, `RELAY_*` environment
+    /// variables, the config file and finally the defaults.
+    pub async fn load() -> Result<Config, ConfigError> {
+        let flags = Flags::parse();
+        let file = match &flags.config {
+            Some(path) => File::read(path).await?,
+            None => {
+                let path = Path::new(DEFAULT_CONFIG_FILE);
+
+                match path.exists() {
+                    true => File::read(path).await?,
+                    false => File::default(),
+                }
+            }
+        };
+
+        Config::merge(flags, file).await
+    }
+
+    pub async fn merge(flags: Flags, file: File) -> Result<Config, ConfigError> {
+        let default = Config::default();
+
+        let config = Config {
+            address: flags.address.or(file.address).unwrap_or(default.address),
+            port: flags.port.or(file.port).unwrap_or(default.port),
+            path: flags.path.or(file.path).unwrap_or(default.path),
+            state_channel_capacity: flags
+                .state_channel_capacity
+                .or(file.state_channel_capacity)
+                .unwrap_or(default.state_channel_capacity),
+            sink_capacity: flags
+                .sink_capacity
+                .or(file.sink_capacity)
+                .unwrap_or(default.sink_capacity),
+            slow_consumer: flags
+                .slow_consumer
+                .or(file.slow_consumer)
+                .unwrap_or(default.slow_consumer),
+            history_capacity: flags
+                .history_capacity
+                .or(file.history_capacity)
+                .unwrap_or(default.history_capacity),
+            history_page_size: flags
+                .history_page_size
+                .or(file.history_page_size)
+                .unwrap_or(default.history_page_size),
+            storage: flags.storage.or(file.storage).unwrap_or(default.storage),
+            storage_path: flags
+                .storage_path
+                .or(file.storage_path)
+                .unwrap_or(default.storage_path),
+            retention_max_messages: flags.retention_max_messages.or(file.retention_max_messages),
+            retention_max_bytes: flags.retention_max_bytes.or(file.retention_max_bytes),
+            retention_max_age: flags.retention_max_age.or(file.retention_max_age),
+            plain_text: flags
+                .plain_text
+                .or(file.plain_text)
+                .unwrap_or(default.plain_text),
+            auth_jwt_secret: flags.auth_jwt_secret.or(file.auth_jwt_secret),
+            auth_token_file: flags.auth_token_file.or(file.auth_token_file),
+            session_grace_period: flags
+                .session_grace_period
+                .or(file.session_grace_period)
+                .unwrap_or(default.session_grace_period),
+            heartbeat_interval: flags
+                .heartbeat_interval
+                .or(file.heartbeat_interval)
+                .unwrap_or(default.heartbeat_interval),
+            heartbeat_timeout: flags
+                .heartbeat_timeout
+                .or(file.heartbeat_timeout)
+                .unwrap_or(default.heartbeat_timeout),
+            attachment_dir: flags
+                .attachment_dir
+                .or(file.attachment_dir)
+                .unwrap_or(default.attachment_dir),
+            attachment_max_bytes: flags
+                .attachment_max_bytes
+                .or(file.attachment_max_bytes)
+                .unwrap_or(default.attachment_max_bytes),
+            state_timeout: flags
+                .state_timeout
+                .or(file.state_timeout)
+                .unwrap_or(default.state_timeout),
+            typing_timeout: flags
+                .typing_timeout
+                .or(file.typing_timeout)
+                .unwrap_or(default.typing_timeout),
+        };
+
+        config.validate().await
+    }
+
+    pub async fn socket_address(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    async fn validate(mut self) -> Result<Config, ConfigError> {
+        if self.port == 0 {
+            return Err(ConfigError::Port);
+        }
+
+        let path = self.path.trim_matches('/');
+
+        if path.is_empty() || path.contains('/') {
+            return Err(ConfigError::Path(self.path));
+        }
+
+        self.path = path.to_owned();
+
+        if self.state_channel_capacity == 0 {
+            return Err(ConfigError::Capacity("state_channel_capacity"));
+        }
+
+        if self.sink_capacity == 0 {
+            return Err(ConfigError::Capacity("sink_capacity"));
+        }
+
+        if self.history_page_size == 0 {
+            return Err(ConfigError::Capacity("history_page_size"));
+        }
+
+        if self.storage == Storage::File && self.storage_path.as_os_str().is_empty() {
+            return Err(ConfigError::StoragePath);
+        }
+
+        if self.attachment_dir.as_os_str().is_empty() {
+            return Err(ConfigError::AttachmentDir);
+        }
+
+        if self.attachment_max_bytes == 0 {
+            return Err(ConfigError::Capacity("attachment_max_bytes"));
+        }
+
+        if self.retention_max_messages == Some(0) {
+            return Err(ConfigError::Retention("retention_max_messages"));
+        }
+
+        if self.retention_max_bytes == Some(0) {
+            return Err(ConfigError::Retention("retention_max_bytes"));
+        }
+
+        if self.retention_max_age == Some(Duration::ZERO) {
+            return Err(ConfigError::Retention("retention_max_age"));
+        }
+
+        if self.heartbeat_interval.is_zero() || self.heartbeat_interval >= self.heartbeat_timeout {
+            return Err(ConfigError::Heartbeat);
+        }
+
+        if self.state_timeout.is_zero() {
+            return Err(ConfigError::StateTimeout);
+        }
+
+        if self.typing_timeout.is_zero() {
+            return Err(ConfigError::TypingTimeout);
+        }
+
+        if self.auth_jwt_secret.as_deref() == Some("") {
+            return Err(ConfigError::Auth("auth_jwt_secret"));
+        }
+
+        if let Some(auth_token_file) = &self.auth_token_file {
+            if auth_token_file.as_os_str().is_empty() {
+                return Err(ConfigError::Auth("auth_token_file"));
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+impl File {
+    pub async fn read(path: &Path) -> Result<File, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| ConfigError::Read(path.to_owned(), error))?;
+
+        toml::from_str(&contents).map_err(|error| ConfigError::Parse(path.to_owned(), error))
+    }
+}
+
+/// Parse a duration written as a whole number followed by `ms`, `s`, `m`, `h` or `d`.
+///
+/// A bare number is taken as seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|character: char| !character.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("{:?} is not a duration such as 90s, 30m, 12h or 7d", value))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount.saturating_mul(60))),
+        "h" => Ok(Duration::from_secs(amount.saturating_mul(3600))),
+        "d" => Ok(Duration::from_secs(amount.saturating_mul(86400))),
+        _ => Err(format!(
+            "{:?} has an unknown duration unit {:?}",
+            value, unit
+        )),
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => parse_duration(&value)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn default() -> Result<(), Box<dyn std::error::Error>> {
+        let test_config = Config::merge(Flags::default(), File::default()).await?;
+
+        assert_eq!(test_config, Config::default());
+        assert_eq!(
+            test_config.socket_address().await.to_string(),
+            "0.0.0.0:1806",
+        );
+        assert_eq!(test_config.path, "ws");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flags() -> Result<(), Box<dyn std::error::Error>> {
+        let test_flags = Flags::try_parse_from([
+            "relay",
+            "--address",
+            "127.0.0.1",
+            "--port",
+            "8080",
+            "--path",
+            "/chat",
+            "--state-channel-capacity",
+            "128",
+            "--sink-capacity",
+            "32",
+            "--slow-consumer",
+            "disconnect",
+            "--history-capacity",
+            "10",
+            "--history-page-size",
+            "20",
+            "--storage",
+            "file",
+            "--storage-path",
+            "/tmp/relay.log",
+            "--plain-text",
+            "false",
+            "--session-grace-period",
+            "30s",
+            "--heartbeat-interval",
+            "10s",
+            "--heartbeat-timeout",
+            "25s",
+            "--attachment-dir",
+            "/tmp/attachments",
+            "--attachment-max-bytes",
+            "1024",
+            "--state-timeout",
+            "2s",
+            "--typing-timeout",
+            "3s",
+        ])?;
+
+        let test_config = Config::merge(test_flags, File::default()).await?;
+
+        assert_eq!(
+            test_config.socket_address().await.to_string(),
+            "127.0.0.1:8080",
+        );
+        assert_eq!(test_config.path, "chat");
+        assert_eq!(test_config.state_channel_capacity, 128);
+        assert_eq!(test_config.sink_capacity, 32);
+        assert_eq!(test_config.slow_consumer, SlowConsumer::Disconnect);
+        assert_eq!(test_config.history_capacity, 10);
+        assert_eq!(test_config.history_page_size, 20);
+        assert_eq!(test_config.storage, Storage::File);
+        assert_eq!(test_config.storage_path, PathBuf::from("/tmp/relay.log"));
+        assert!(!test_config.plain_text);
+        assert_eq!(test_config.session_grace_period, Duration::from_secs(30));
+        assert_eq!(test_config.heartbeat_interval, Duration::from_secs(10));
+        assert_eq!(test_config.heartbeat_timeout, Duration::from_secs(25));
+        assert_eq!(
+            test_config.attachment_dir,
+            PathBuf::from("/tmp/attachments")
+        );
+        assert_eq!(test_config.attachment_max_bytes, 1024);
+        assert_eq!(test_config.state_timeout, Duration::from_secs(2));
+        assert_eq!(test_config.typing_timeout, Duration::from_secs(3));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file() -> Result<(), Box<dyn std::error::Error>> {
+        let test_file: File = toml::from_str(
+            r#"
+                address = "127.0.0.1"
+                port = 8080
+                sink_capacity = 8
+                slow_consumer = "drop-newest"
+                storage = "file"
+                plain_text = false
+            "#,
+        )?;
+
+        let test_config = Config::merge(Flags::default(), test_file).await?;
+
+        assert_eq!(
+            test_config.socket_address().await.to_string(),
+            "127.0.0.1:8080",
+        );
+        assert_eq!(test_config.sink_capacity, 8);
+        assert_eq!(test_config.slow_consumer, SlowConsumer::DropNewest);
+        assert_eq!(test_config.state_channel_capacity, 64);
+        assert_eq!(test_config.storage, Storage::File);
+        assert_eq!(test_config.storage_path, PathBuf::from("relay.log"));
+        assert!(!test_config.plain_text);
+
+        assert!(toml::from_str::<File>("unknown = 1").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn precedence() -> Result<(), Box<dyn std::error::Error>> {
+        let test_flags = Flags::try_parse_from(["relay", "--port", "9000"])?;
+        let test_file: File = toml::from_str(
+            r#"
+                port = 8080
+                path = "file"
+            "#,
+        )?;
+
+        let test_config = Config::merge(test_flags, test_file).await?;
+
+        assert_eq!(test_config.port, 9000);
+        assert_eq!(test_config.path, "file");
+        assert_eq!(test_config.sink_capacity, 16);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn validate() -> Result<(), Box<dyn std::error::Error>> {
+        let test_port = Flags::try_parse_from(["relay", "--port", "0"])?;
+
+        assert!(matches!(
+            Config::merge(test_port, File::default()).await,
+            Err(ConfigError::Port),
+        ));
+
+        for test_path in ["", "/", "/nested/path"] {
+            let test_flags = Flags::try_parse_from(["relay", "--path", test_path])?;
+
+            assert!(matches!(
+                Config::merge(test_flags, File::default()).await,
+                Err(ConfigError::Path(_)),
+            ));
+        }
+
+        let test_state_channel_capacity =
+            Flags::try_parse_from(["relay", "--state-channel-capacity", "0"])?;
+
+        assert!(matches!(
+            Config::merge(test_state_channel_capacity, File::default()).await,
+            Err(ConfigError::Capacity("state_channel_capacity")),
+        ));
+
+        let test_sink_capacity = Flags::try_parse_from(["relay", "--sink-capacity", "0"])?;
+
+        assert!(matches!(
+            Config::merge(test_sink_capacity, File::default()).await,
+            Err(ConfigError::Capacity("sink_capacity")),
+        ));
+
+        let test_history_page_size: File = toml::from_str("history_page_size = 0")?;
+
+        assert!(matches!(
+            Config::merge(Flags::default(), test_history_page_size).await,
+            Err(ConfigError::Capacity("history_page_size")),
+        ));
+
+        for test_heartbeat in [["0s", "90s"], ["90s", "90s"], ["2m", "90s"]] {
+            let test_flags = Flags::try_parse_from([
+                "relay",
+                "--heartbeat-interval",
+                test_heartbeat[0],
+                "--heartbeat-timeout",
+                test_heartbeat[1],
+            ])?;
+
+            assert!(matches!(
+                Config::merge(test_flags, File::default()).await,
+                Err(ConfigError::Heartbeat),
+            ));
+        }
+
+        let test_attachment_max_bytes =
+            Flags::try_parse_from(["relay", "--attachment-max-bytes", "0"])?;
+
+        assert!(matches!(
+            Config::merge(test_attachment_max_bytes, File::default()).await,
+            Err(ConfigError::Capacity("attachment_max_bytes")),
+        ));
+
+        let test_attachment_dir: File = toml::from_str(r#"attachment_dir = """#)?;
+
+        assert!(matches!(
+            Config::merge(Flags::default(), test_attachment_dir).await,
+            Err(ConfigError::AttachmentDir),
+        ));
+
+        let test_state_timeout = Flags::try_parse_from(["relay", "--state-timeout", "0s"])?;
+
+        assert!(matches!(
+            Config::merge(test_state_timeout, File::default()).await,
+            Err(ConfigError::StateTimeout),
+        ));
+
+        let test_typing_timeout: File = toml::from_str(r#"typing_timeout = "0s""#)?;
+
+        assert!(matches!(
+            Config::merge(Flags::default(), test_typing_timeout).await,
+            Err(ConfigError::TypingTimeout),
+        ));
+
+        let test_storage_path: File = toml::from_str(
+            r#"
+                storage = "file"
+                storage_path = ""
+            "#,
+        )?;
+
+        assert!(matches!(
+            Config::merge(Flags::default(), test_storage_path).await,
+            Err(ConfigError::StoragePath),
+        ));
+
+        assert!(Flags::try_parse_from(["relay", "--address", "not_an_address"]).is_err());
+        assert!(Flags::try_parse_from(["relay", "--storage", "not_a_storage"]).is_err());
+        assert!(Flags::try_parse_from(["relay", "--slow-consumer", "block"]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retention() -> Result<(), Box<dyn std::error::Error>> {
+        let test_flags = Flags::try_parse_from([
+            "relay",
+            "--retention-max-messages",
+            "500",
+            "--retention-max-age",
+            "12h",
+        ])?;
+        let test_file: File = toml::from_str(
+            r#"
+                retention_max_bytes = 1048576
+                retention_max_age = "7d"
+            "#,
+        )?;
+
+        let test_config = Config::merge(test_flags, test_file).await?;
+
+        assert_eq!(test_config.retention_max_messages, Some(500));
+        assert_eq!(test_config.retention_max_bytes, Some(1048576));
+        assert_eq!(
+            test_config.retention_max_age,
+            Some(Duration::from_secs(43200)),
+        );
+        assert_eq!(test_config.session_grace_period, Duration::from_secs(120));
+
+        let test_unlimited = Config::merge(Flags::default(), File::default()).await?;
+
+        assert!(test_unlimited.retention_max_messages.is_none());
+        assert!(test_unlimited.retention_max_bytes.is_none());
+        assert!(test_unlimited.retention_max_age.is_none());
+
+        let test_zero = Flags::try_parse_from(["relay", "--retention-max-messages", "0"])?;
+
+        assert!(matches!(
+            Config::merge(test_zero, File::default()).await,
+            Err(ConfigError::Retention("retention_max_messages")),
+        ));
+
+        assert!(toml::from_str::<File>(r#"retention_max_age = "soon""#).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn auth() -> Result<(), Box<dyn std::error::Error>> {
+        let test_flags = Flags::try_parse_from(["relay", "--auth-jwt-secret", "test_secret"])?;
+        let test_file: File = toml::from_str(
+            r#"
+                auth_jwt_secret = "test_file_secret"
+                auth_token_file = "tokens.txt"
+            "#,
+        )?;
+
+        let test_config = Config::merge(test_flags, test_file).await?;
+
+        assert_eq!(test_config.auth_jwt_secret.as_deref(), Some("test_secret"));
+        assert_eq!(
+            test_config.auth_token_file,
+            Some(PathBuf::from("tokens.txt"))
+        );
+
+        let test_empty_secret = Flags::try_parse_from(["relay", "--auth-jwt-secret", ""])?;
+
+        assert!(matches!(
+            Config::merge(test_empty_secret, File::default()).await,
+            Err(ConfigError::Auth("auth_jwt_secret")),
+        ));
+
+        let test_empty_file: File = toml::from_str(r#"auth_token_file = """#)?;
+
+        assert!(matches!(
+            Config::merge(Flags::default(), test_empty_file).await,
+            Err(ConfigError::Auth("auth_token_file")),
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duration() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(parse_duration("250ms")?, Duration::from_millis(250));
+        assert_eq!(parse_duration("90")?, Duration::from_secs(90));
+        assert_eq!(parse_duration("90s")?, Duration::from_secs(90));
+        assert_eq!(parse_duration("30m")?, Duration::from_secs(1800));
+        assert_eq!(parse_duration("12h")?, Duration::from_secs(43200));
+        assert_eq!(parse_duration("7d")?, Duration::from_secs(604800));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("-1s").is_err());
+
+        Ok(())
+    }
+}
@@ -1,38 +1,279 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use warp::filters::ws::Message;
 
-pub enum MessageKind {
-    Uuid,
-    Message,
-    ConnectedUsers,
+use crate::channels::RelayError;
+use crate::search::SearchPage;
+use crate::store::MessageRecord;
+
+/// Sent as `version` in every frame, in both directions.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// A frame and the protocol version it was written for.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Envelope<T> {
+    pub version: u8,
+    #[serde(flatten)]
+    pub frame: T,
+}
+
+/// Frames sent from a client to the server.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    SendMessage {
+        contents: String,
+        /// Id of a message in the same room, to reply in its thread
+        #[serde(default)]
+        reply_to: Option<String>,
+    },
+    SetNickname {
+        nickname: String,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom,
+    Typing,
+    /// A page of the room's history, ending just before the message with id `before` or at the
+    /// latest message
+    FetchHistory {
+        #[serde(default)]
+        before: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Announces an attachment, whose bytes follow in binary frames until `size` is reached
+    BeginAttachment {
+        name: String,
+        content_type: String,
+        size: usize,
+        #[serde(default)]
+        contents: String,
+    },
+    /// A private message to one session, addressed by its uuid or nickname
+    DirectMessage {
+        to: String,
+        contents: String,
+    },
+    /// Direct messages exchanged with one session, addressed by its uuid or nickname
+    FetchConversation {
+        with: String,
+    },
+    /// React to a message, by its id, with an emoji
+    AddReaction {
+        id: String,
+        emoji: String,
+    },
+    RemoveReaction {
+        id: String,
+        emoji: String,
+    },
+    /// A message and its replies, by the id of any message in the thread
+    FetchThread {
+        id: String,
+    },
+    /// Replace the contents of one of the sender's own messages, by its id
+    EditMessage {
+        id: String,
+        contents: String,
+    },
+    DeleteMessage {
+        id: String,
+    },
+    /// Messages in the sender's room matching a query, best first, skipping `offset` of them
+    Search {
+        query: String,
+        #[serde(default)]
+        offset: Option<usize>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+}
+
+/// Frames sent from the server to a client.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Session {
+        uuid: String,
+        /// Present as `?resume=` when reconnecting to carry on as the same user
+        token: String,
+        resumed: bool,
+    },
+    /// Sent on entering a room, ahead of the replay of its latest messages
+    Room {
+        room: String,
+        /// Whether older messages than those replayed are left to page with `fetch_history`
+        has_more: bool,
+    },
+    ConnectedUsers {
+        room: String,
+        count: usize,
+    },
+    UserJoined(PresenceEvent),
+    UserLeft(PresenceEvent),
+    /// Everyone in the room, sent on entering it
+    PresenceSnapshot {
+        room: String,
+        users: Vec<Presence>,
+    },
+    Nickname {
+        uuid: String,
+        nickname: String,
+        previous: String,
+    },
+    Typing {
+        room: String,
+        uuid: String,
+    },
+    /// Sent when a user who was typing sends a message, leaves, or stops refreshing `typing`
+    TypingStopped {
+        room: String,
+        uuid: String,
+    },
+    Message(MessageRecord),
+    /// A message as it stands after its author edited it
+    MessageEdited(MessageRecord),
+    MessageDeleted {
+        room: String,
+        id: String,
+    },
+    /// Sent to the room whenever a reply is added to a thread
+    ReplyCount {
+        room: String,
+        id: String,
+        replies: usize,
+    },
+    /// The first message of a thread followed by its replies
+    Thread {
+        room: String,
+        id: String,
+        messages: Vec<MessageRecord>,
+    },
+    ReactionAdded(ReactionEvent),
+    ReactionRemoved(ReactionEvent),
+    /// One page of history, oldest first, and whether there are older messages to fetch
+    History {
+        room: String,
+        messages: Vec<MessageRecord>,
+        has_more: bool,
+    },
+    /// One page of the messages matching a `search`
+    SearchResults(SearchPage),
+    /// Sent to both the sender and the recipient
+    DirectMessage(MessageRecord),
+    Conversation {
+        with: String,
+        messages: Vec<MessageRecord>,
+    },
+    Error(FrameError),
+}
+
+/// A user in a room, as listed by `presence_snapshot`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Presence {
+    pub uuid: String,
+    pub nickname: String,
+}
+
+/// A user entering or leaving a room, whether by changing rooms or connecting and closing.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PresenceEvent {
+    pub room: String,
+    pub uuid: String,
+    pub nickname: String,
+    pub timestamp: u64,
+}
+
+/// One user's reaction to a message changing, with how many now share it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ReactionEvent {
+    pub room: String,
+    /// The message reacted to
+    pub id: String,
+    pub emoji: String,
+    pub uuid: String,
+    pub count: usize,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedFrame,
+    UnsupportedVersion,
+    InvalidRoom,
+    InvalidNickname,
+    InvalidReaction,
+    NicknameTaken,
+    PlainTextDisabled,
+    InvalidAttachment,
+    AttachmentTooLarge,
+    UserNotFound,
+    MessageNotFound,
+    NotAuthor,
+    Timeout,
+    StorageFailed,
 }
 
-impl MessageKind {
-    pub async fn build(&self) -> String {
-        match self {
-            MessageKind::ConnectedUsers => String::from("connected_users"),
-            MessageKind::Message => String::from("message"),
-            MessageKind::Uuid => String::from("uuid"),
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FrameError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl FrameError {
+    pub async fn build(code: ErrorCode, message: impl Into<String>) -> FrameError {
+        FrameError {
+            code,
+            message: message.into(),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Object {
-    pub kind: String,
-    pub contents: String,
-}
+impl ClientFrame {
+    pub async fn parse(text: &str) -> Result<ClientFrame, FrameError> {
+        let value: Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(FrameError::build(ErrorCode::MalformedFrame, error.to_string()).await)
+            }
+        };
 
-impl Object {
-    pub async fn build(kind: MessageKind, contents: String) -> Object {
-        let kind = kind.build().await;
+        match value.get("version").map(Value::as_u64) {
+            Some(Some(version)) if version == PROTOCOL_VERSION as u64 => {}
+            Some(Some(version)) => {
+                let message = format!(
+                    "protocol version {} is not supported, expected {}",
+                    version, PROTOCOL_VERSION,
+                );
 
-        Object { kind, contents }
+                return Err(FrameError::build(ErrorCode::UnsupportedVersion, message).await);
+            }
+            Some(None) | None => {
+                let message = "frame is missing a numeric version";
+
+                return Err(FrameError::build(ErrorCode::MalformedFrame, message).await);
+            }
+        }
+
+        match serde_json::from_value::<Envelope<ClientFrame>>(value) {
+            Ok(envelope) => Ok(envelope.frame),
+            Err(error) => {
+                Err(FrameError::build(ErrorCode::MalformedFrame, error.to_string()).await)
+            }
+        }
     }
+}
 
-    pub async fn to_message(&self) -> Result<Message, Box<dyn std::error::Error>> {
-        let json = serde_json::to_string(&self)?;
+impl ServerFrame {
+    pub async fn to_message(&self) -> Result<Message, RelayError> {
+        let envelope = Envelope {
+            version: PROTOCOL_VERSION,
+            frame: self,
+        };
+        let json = serde_json::to_string(&envelope)?;
         let message = Message::text(&json);
 
         Ok(message)
@@ -42,64 +283,541 @@ impl Object {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::search::SearchResult;
+    use crate::store::{Attachment, Reaction};
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn message_kind_connected_users() -> Result<(), Box<dyn std::error::Error>> {
-        let test_message_kind_connected_users = MessageKind::ConnectedUsers.build().await;
-
+    async fn client_frame_parse() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"send_message","contents":"test_message"}"#)
+                .await
+                .expect("send message"),
+            ClientFrame::SendMessage {
+                contents: String::from("test_message"),
+                reply_to: None,
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(
+                r#"{"version":1,"type":"send_message","contents":"test_message","reply_to":"test_id"}"#
+            )
+            .await
+            .expect("send reply"),
+            ClientFrame::SendMessage {
+                contents: String::from("test_message"),
+                reply_to: Some(String::from("test_id")),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"fetch_thread","id":"test_id"}"#)
+                .await
+                .expect("fetch thread"),
+            ClientFrame::FetchThread {
+                id: String::from("test_id"),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"join_room","room":"test_room"}"#)
+                .await
+                .expect("join room"),
+            ClientFrame::JoinRoom {
+                room: String::from("test_room"),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"leave_room"}"#)
+                .await
+                .expect("leave room"),
+            ClientFrame::LeaveRoom,
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"set_nickname","nickname":"test_nickname"}"#)
+                .await
+                .expect("set nickname"),
+            ClientFrame::SetNickname {
+                nickname: String::from("test_nickname"),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"typing"}"#)
+                .await
+                .expect("typing"),
+            ClientFrame::Typing,
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"fetch_history"}"#)
+                .await
+                .expect("fetch history"),
+            ClientFrame::FetchHistory {
+                before: None,
+                limit: None,
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(
+                r#"{"version":1,"type":"fetch_history","before":"test_id","limit":10}"#
+            )
+            .await
+            .expect("fetch history page"),
+            ClientFrame::FetchHistory {
+                before: Some(String::from("test_id")),
+                limit: Some(10),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(
+                r#"{"version":1,"type":"begin_attachment","name":"test.png","content_type":"image/png","size":4}"#
+            )
+            .await
+            .expect("begin attachment"),
+            ClientFrame::BeginAttachment {
+                name: String::from("test.png"),
+                content_type: String::from("image/png"),
+                size: 4,
+                contents: String::new(),
+            },
+        );
         assert_eq!(
-            test_message_kind_connected_users.as_str(),
-            "connected_users",
+            ClientFrame::parse(
+                r#"{"version":1,"type":"direct_message","to":"test_nickname","contents":"test_message"}"#
+            )
+            .await
+            .expect("direct message"),
+            ClientFrame::DirectMessage {
+                to: String::from("test_nickname"),
+                contents: String::from("test_message"),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"fetch_conversation","with":"test_uuid"}"#)
+                .await
+                .expect("fetch conversation"),
+            ClientFrame::FetchConversation {
+                with: String::from("test_uuid"),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(
+                r#"{"version":1,"type":"edit_message","id":"test_id","contents":"test_edit"}"#
+            )
+            .await
+            .expect("edit message"),
+            ClientFrame::EditMessage {
+                id: String::from("test_id"),
+                contents: String::from("test_edit"),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"delete_message","id":"test_id"}"#)
+                .await
+                .expect("delete message"),
+            ClientFrame::DeleteMessage {
+                id: String::from("test_id"),
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(r#"{"version":1,"type":"search","query":"test_query"}"#)
+                .await
+                .expect("search"),
+            ClientFrame::Search {
+                query: String::from("test_query"),
+                offset: None,
+                limit: None,
+            },
+        );
+        assert_eq!(
+            ClientFrame::parse(
+                r#"{"version":1,"type":"search","query":"test_query","offset":10,"limit":5}"#
+            )
+            .await
+            .expect("search page"),
+            ClientFrame::Search {
+                query: String::from("test_query"),
+                offset: Some(10),
+                limit: Some(5),
+            },
         );
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn message_kind_message() -> Result<(), Box<dyn std::error::Error>> {
-        let test_message_kind_message = MessageKind::Message.build().await;
+    async fn client_frame_parse_errors() -> Result<(), Box<dyn std::error::Error>> {
+        for (test_text, test_code) in [
+            ("test_message", ErrorCode::MalformedFrame),
+            (r#"{"type":"leave_room"}"#, ErrorCode::MalformedFrame),
+            (
+                r#"{"version":"1","type":"leave_room"}"#,
+                ErrorCode::MalformedFrame,
+            ),
+            (
+                r#"{"version":2,"type":"leave_room"}"#,
+                ErrorCode::UnsupportedVersion,
+            ),
+            (
+                r#"{"version":1,"type":"test_unknown"}"#,
+                ErrorCode::MalformedFrame,
+            ),
+            (
+                r#"{"version":1,"type":"join_room"}"#,
+                ErrorCode::MalformedFrame,
+            ),
+        ] {
+            let test_error = ClientFrame::parse(test_text).await.expect_err(test_text);
 
-        assert_eq!(test_message_kind_message.as_str(), "message");
+            assert_eq!(test_error.code, test_code);
+            assert!(!test_error.message.is_empty());
+        }
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn message_kind_uuid() -> Result<(), Box<dyn std::error::Error>> {
-        let test_message_kind_uuid = MessageKind::Uuid.build().await;
+    async fn server_frame_session() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::Session {
+            uuid: String::from("test_uuid"),
+            token: String::from("test_token"),
+            resumed: false,
+        };
 
-        assert_eq!(test_message_kind_uuid.as_str(), "uuid");
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"session","uuid":"test_uuid","token":"test_token","resumed":false}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_room() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::Room {
+            room: String::from("test_room"),
+            has_more: true,
+        };
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"room","room":"test_room","has_more":true}"#,
+        );
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn object_build() -> Result<(), Box<dyn std::error::Error>> {
-        let test_message_kind = MessageKind::Message;
-        let test_contents = String::from("test_contents");
-        let test_object = Object::build(test_message_kind, test_contents).await;
+    async fn server_frame_connected_users() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::ConnectedUsers {
+            room: String::from("test_room"),
+            count: 3,
+        };
 
-        assert_eq!(test_object.kind.as_str(), "message");
-        assert_eq!(test_object.contents.as_str(), "test_contents");
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"connected_users","room":"test_room","count":3}"#,
+        );
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn object_to_message() -> Result<(), Box<dyn std::error::Error>> {
-        let test_message_kind = MessageKind::Message;
-        let test_contents = String::from("test_contents");
-        let test_object = Object::build(test_message_kind, test_contents).await;
+    async fn server_frame_presence() -> Result<(), Box<dyn std::error::Error>> {
+        let test_event = PresenceEvent {
+            room: String::from("test_room"),
+            uuid: String::from("test_uuid"),
+            nickname: String::from("test_nickname"),
+            timestamp: 1655000000000,
+        };
 
         assert_eq!(
-            test_object
+            ServerFrame::UserJoined(test_event.to_owned())
                 .to_message()
                 .await?
                 .to_str()
-                .expect("websocket message &str"),
-            r#"{"kind":"message","contents":"test_contents"}"#,
+                .expect("text"),
+            r#"{"version":1,"type":"user_joined","room":"test_room","uuid":"test_uuid","nickname":"test_nickname","timestamp":1655000000000}"#,
+        );
+        assert_eq!(
+            ServerFrame::UserLeft(test_event)
+                .to_message()
+                .await?
+                .to_str()
+                .expect("text"),
+            r#"{"version":1,"type":"user_left","room":"test_room","uuid":"test_uuid","nickname":"test_nickname","timestamp":1655000000000}"#,
+        );
+
+        let test_snapshot = ServerFrame::PresenceSnapshot {
+            room: String::from("test_room"),
+            users: vec![Presence {
+                uuid: String::from("test_uuid"),
+                nickname: String::from("test_nickname"),
+            }],
+        };
+
+        assert_eq!(
+            test_snapshot.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"presence_snapshot","room":"test_room","users":[{"uuid":"test_uuid","nickname":"test_nickname"}]}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_message() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::Message(MessageRecord {
+            id: String::from("test_id"),
+            room: String::from("test_room"),
+            sequence: 1,
+            uuid: String::from("test_uuid"),
+            display_name: String::from("test_nickname"),
+            contents: String::from("test_contents"),
+            timestamp: 1655000000000,
+            ..MessageRecord::default()
+        });
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"message","id":"test_id","room":"test_room","sequence":1,"uuid":"test_uuid","display_name":"test_nickname","contents":"test_contents","timestamp":1655000000000}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_message_attachment() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::Message(MessageRecord {
+            room: String::from("test_room"),
+            contents: String::from("test_contents"),
+            attachment: Some(Attachment {
+                id: String::from("test_id"),
+                name: String::from("test.png"),
+                content_type: String::from("image/png"),
+                size: 4,
+            }),
+            ..MessageRecord::default()
+        });
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"message","id":"","room":"test_room","sequence":0,"uuid":"","display_name":"","contents":"test_contents","timestamp":0,"attachment":{"id":"test_id","name":"test.png","content_type":"image/png","size":4}}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_direct_message() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::DirectMessage(MessageRecord {
+            id: String::from("test_id"),
+            sequence: 1,
+            uuid: String::from("test_uuid"),
+            display_name: String::from("test_nickname"),
+            contents: String::from("test_contents"),
+            timestamp: 1655000000000,
+            recipient: Some(String::from("test_recipient")),
+            ..MessageRecord::default()
+        });
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"direct_message","id":"test_id","sequence":1,"uuid":"test_uuid","display_name":"test_nickname","contents":"test_contents","timestamp":1655000000000,"recipient":"test_recipient"}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_message_amended() -> Result<(), Box<dyn std::error::Error>> {
+        let test_edited = ServerFrame::MessageEdited(MessageRecord {
+            id: String::from("test_id"),
+            room: String::from("test_room"),
+            sequence: 1,
+            contents: String::from("test_edit"),
+            timestamp: 1655000000000,
+            edited_at: Some(1655000060000),
+            ..MessageRecord::default()
+        });
+
+        assert_eq!(
+            test_edited.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"message_edited","id":"test_id","room":"test_room","sequence":1,"uuid":"","display_name":"","contents":"test_edit","timestamp":1655000000000,"edited_at":1655000060000}"#,
         );
 
+        let test_deleted = ServerFrame::MessageDeleted {
+            room: String::from("test_room"),
+            id: String::from("test_id"),
+        };
+
+        assert_eq!(
+            test_deleted.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"message_deleted","room":"test_room","id":"test_id"}"#,
+        );
+
+        let test_tombstone: MessageRecord =
+            serde_json::from_str(r#"{"id":"test_id","contents":"","deleted":true}"#)?;
+
+        assert!(test_tombstone.deleted);
+        assert_eq!(
+            serde_json::to_string(&test_tombstone)?,
+            r#"{"id":"test_id","sequence":0,"uuid":"","display_name":"","contents":"","timestamp":0,"deleted":true}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_reaction() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::ReactionAdded(ReactionEvent {
+            room: String::from("test_room"),
+            id: String::from("test_id"),
+            emoji: String::from("👍"),
+            uuid: String::from("test_uuid"),
+            count: 2,
+        });
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"reaction_added","room":"test_room","id":"test_id","emoji":"👍","uuid":"test_uuid","count":2}"#,
+        );
+
+        let test_record = MessageRecord {
+            id: String::from("test_id"),
+            contents: String::from("test_contents"),
+            reactions: vec![Reaction {
+                emoji: String::from("👍"),
+                count: 1,
+                users: vec![String::from("test_uuid")],
+            }],
+            ..MessageRecord::default()
+        };
+
+        assert_eq!(
+            serde_json::to_string(&test_record)?,
+            r#"{"id":"test_id","sequence":0,"uuid":"","display_name":"","contents":"test_contents","timestamp":0,"reactions":[{"emoji":"👍","count":1,"users":["test_uuid"]}]}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_thread() -> Result<(), Box<dyn std::error::Error>> {
+        let test_count = ServerFrame::ReplyCount {
+            room: String::from("test_room"),
+            id: String::from("test_id"),
+            replies: 1,
+        };
+
+        assert_eq!(
+            test_count.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"reply_count","room":"test_room","id":"test_id","replies":1}"#,
+        );
+
+        let test_thread = ServerFrame::Thread {
+            room: String::from("test_room"),
+            id: String::from("test_id"),
+            messages: vec![
+                MessageRecord {
+                    id: String::from("test_id"),
+                    replies: 1,
+                    ..MessageRecord::default()
+                },
+                MessageRecord {
+                    id: String::from("test_reply_id"),
+                    reply_to: Some(String::from("test_id")),
+                    ..MessageRecord::default()
+                },
+            ],
+        };
+
+        assert_eq!(
+            test_thread.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"thread","room":"test_room","id":"test_id","messages":[{"id":"test_id","sequence":0,"uuid":"","display_name":"","contents":"","timestamp":0,"replies":1},{"id":"test_reply_id","sequence":0,"uuid":"","display_name":"","contents":"","timestamp":0,"reply_to":"test_id"}]}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_history() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::History {
+            room: String::from("test_room"),
+            messages: vec![MessageRecord {
+                id: String::from("test_id"),
+                room: String::from("test_room"),
+                sequence: 1,
+                uuid: String::from("test_uuid"),
+                display_name: String::from("test_nickname"),
+                contents: String::from("test_contents"),
+                timestamp: 1655000000000,
+                ..MessageRecord::default()
+            }],
+            has_more: true,
+        };
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"history","room":"test_room","messages":[{"id":"test_id","room":"test_room","sequence":1,"uuid":"test_uuid","display_name":"test_nickname","contents":"test_contents","timestamp":1655000000000}],"has_more":true}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_search_results() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::SearchResults(SearchPage {
+            room: String::from("test_room"),
+            query: String::from("test"),
+            offset: 0,
+            total: 1,
+            results: vec![SearchResult {
+                message: MessageRecord {
+                    id: String::from("test_id"),
+                    room: String::from("test_room"),
+                    sequence: 1,
+                    uuid: String::from("test_uuid"),
+                    display_name: String::from("test_nickname"),
+                    contents: String::from("a test"),
+                    timestamp: 1655000000000,
+                    ..MessageRecord::default()
+                },
+                score: 0.5,
+                snippet: String::from("a <mark>test</mark>"),
+            }],
+        });
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"search_results","room":"test_room","query":"test","offset":0,"total":1,"results":[{"message":{"id":"test_id","room":"test_room","sequence":1,"uuid":"test_uuid","display_name":"test_nickname","contents":"a test","timestamp":1655000000000},"score":0.5,"snippet":"a <mark>test</mark>"}]}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_error() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::Error(
+            FrameError::build(ErrorCode::UnsupportedVersion, "test_error_message").await,
+        );
+
+        assert_eq!(
+            test_frame.to_message().await?.to_str().expect("text"),
+            r#"{"version":1,"type":"error","code":"unsupported_version","message":"test_error_message"}"#,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_frame_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frame = ServerFrame::ConnectedUsers {
+            room: String::from("test_room"),
+            count: 1,
+        };
+        let test_message = test_frame.to_message().await?;
+        let test_envelope: Envelope<ServerFrame> =
+            serde_json::from_str(test_message.to_str().expect("text"))?;
+
+        assert_eq!(test_envelope.version, PROTOCOL_VERSION);
+        assert_eq!(test_envelope.frame, test_frame);
+
         Ok(())
     }
 }
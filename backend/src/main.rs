@@ -1,15 +1,23 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::str::FromStr;
+use std::sync::Arc;
 use tokio::signal;
 use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{error, info};
 
+mod auth;
+mod blobs;
 mod channels;
+mod config;
 mod json;
+mod metrics;
+mod search;
 mod server;
+mod session;
 mod state;
+mod store;
 
-use crate::channels::{StateRequest, StateResponse};
+use crate::channels::{StateRequest, StateResponse, StateSender};
+use crate::config::Config;
+use crate::metrics::Metrics;
 use crate::server::Server;
 use crate::state::State;
 
@@ -17,15 +25,18 @@ use crate::state::State;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
-    let address = IpAddr::V4(Ipv4Addr::from_str("0.0.0.0")?);
-    let port = 1806;
-    let socket_address = SocketAddr::new(address, port);
+    let config = Config::load().await?;
 
-    let (sender, receiver) = mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+    let (sender, receiver) = mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(
+        config.state_channel_capacity,
+    );
     let (send_shutdown_signal, receive_shutdown_signal) = watch::channel(1);
 
-    let mut state = State::init(receiver).await;
-    let server = Server::init(socket_address, sender, receive_shutdown_signal).await?;
+    let metrics = Arc::new(Metrics::default());
+    let store = store::open(&config).await?;
+    let mut state = State::init(receiver, &config, store, metrics.to_owned()).await?;
+    let sender = StateSender::init(sender, config.state_timeout).await;
+    let server = Server::init(&config, sender, receive_shutdown_signal, metrics).await?;
 
     let state_task = tokio::spawn(async move {
         if let Err(error) = state.run().await {
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Why a message was evicted from history.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eviction {
+    Count,
+    Bytes,
+    Age,
+}
+
+/// Counters shared between state and the server, rendered in the Prometheus text format on
+/// `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    evicted_by_count: AtomicU64,
+    evicted_by_bytes: AtomicU64,
+    evicted_by_age: AtomicU64,
+    /// Frames dropped for connections that have since closed
+    closed_dropped_frames: AtomicU64,
+    slow_consumer_disconnects: AtomicU64,
+    /// The dropped frame counter of each open connection, by uuid
+    connections: Mutex<HashMap<String, Arc<AtomicU64>>>,
+}
+
+impl Metrics {
+    pub async fn evicted(&self, eviction: Eviction) {
+        let counter = match eviction {
+            Eviction::Count => &self.evicted_by_count,
+            Eviction::Bytes => &self.evicted_by_bytes,
+            Eviction::Age => &self.evicted_by_age,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn evicted_total(&self, eviction: Eviction) -> u64 {
+        match eviction {
+            Eviction::Count => self.evicted_by_count.load(Ordering::Relaxed),
+            Eviction::Bytes => self.evicted_by_bytes.load(Ordering::Relaxed),
+            Eviction::Age => self.evicted_by_age.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Expose an open connection's dropped frame counter until it is `untrack`ed.
+    pub async fn track(&self, uuid: &str, dropped: Arc<AtomicU64>) {
+        self.connections
+            .lock()
+            .await
+            .insert(uuid.to_owned(), dropped);
+    }
+
+    /// Fold a closed connection's dropped frames into the totals.
+    pub async fn untrack(&self, uuid: &str, dropped: &Arc<AtomicU64>, disconnected: bool) {
+        let mut connections = self.connections.lock().await;
+
+        // A uuid can be reused by a resumed or second authenticated connection
+        if connections
+            .get(uuid)
+            .is_some_and(|tracked| Arc::ptr_eq(tracked, dropped))
+        {
+            connections.remove(uuid);
+        }
+
+        self.closed_dropped_frames
+            .fetch_add(dropped.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        if disconnected {
+            self.slow_consumer_disconnects
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub async fn dropped_frames_total(&self) -> u64 {
+        let open: u64 = self
+            .connections
+            .lock()
+            .await
+            .values()
+            .map(|dropped| dropped.load(Ordering::Relaxed))
+            .sum();
+
+        open + self.closed_dropped_frames.load(Ordering::Relaxed)
+    }
+
+    pub async fn render(&self) -> String {
+        let mut metrics = String::with_capacity(512);
+
+        writeln!(
+            metrics,
+            "# HELP relay_evicted_messages_total Messages evicted from history by retention limits."
+        )
+        .ok();
+        writeln!(metrics, "# TYPE relay_evicted_messages_total counter").ok();
+
+        for (reason, eviction) in [
+            ("count", Eviction::Count),
+            ("bytes", Eviction::Bytes),
+            ("age", Eviction::Age),
+        ] {
+            writeln!(
+                metrics,
+                "relay_evicted_messages_total{{reason=\"{}\"}} {}",
+                reason,
+                self.evicted_total(eviction).await,
+            )
+            .ok();
+        }
+
+        writeln!(
+            metrics,
+            "# HELP relay_dropped_frames_total Frames dropped from full outbound queues by the slow consumer policy."
+        )
+        .ok();
+        writeln!(metrics, "# TYPE relay_dropped_frames_total counter").ok();
+        writeln!(
+            metrics,
+            "relay_dropped_frames_total {}",
+            self.dropped_frames_total().await,
+        )
+        .ok();
+        writeln!(
+            metrics,
+            "# HELP relay_slow_consumer_disconnects_total Connections closed for falling behind."
+        )
+        .ok();
+        writeln!(
+            metrics,
+            "# TYPE relay_slow_consumer_disconnects_total counter"
+        )
+        .ok();
+        writeln!(
+            metrics,
+            "relay_slow_consumer_disconnects_total {}",
+            self.slow_consumer_disconnects.load(Ordering::Relaxed),
+        )
+        .ok();
+        writeln!(
+            metrics,
+            "# HELP relay_connection_dropped_frames Frames dropped so far for each open connection that has dropped any."
+        )
+        .ok();
+        writeln!(metrics, "# TYPE relay_connection_dropped_frames gauge").ok();
+
+        for (uuid, dropped) in self.connections.lock().await.iter() {
+            let dropped = dropped.load(Ordering::Relaxed);
+
+            if dropped > 0 {
+                writeln!(
+                    metrics,
+                    "relay_connection_dropped_frames{{uuid=\"{}\"}} {}",
+                    escape(uuid).await,
+                    dropped,
+                )
+                .ok();
+            }
+        }
+
+        metrics
+    }
+}
+
+/// Escape a Prometheus label value.
+async fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn evicted() -> Result<(), Box<dyn std::error::Error>> {
+        let test_metrics = Metrics::default();
+
+        test_metrics.evicted(Eviction::Count).await;
+        test_metrics.evicted(Eviction::Count).await;
+        test_metrics.evicted(Eviction::Age).await;
+
+        assert_eq!(test_metrics.evicted_total(Eviction::Count).await, 2);
+        assert_eq!(test_metrics.evicted_total(Eviction::Bytes).await, 0);
+        assert_eq!(test_metrics.evicted_total(Eviction::Age).await, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn render() -> Result<(), Box<dyn std::error::Error>> {
+        let test_metrics = Metrics::default();
+
+        test_metrics.evicted(Eviction::Bytes).await;
+
+        let test_render = test_metrics.render().await;
+
+        assert!(test_render.contains("# TYPE relay_evicted_messages_total counter\n"));
+        assert!(test_render.contains("relay_evicted_messages_total{reason=\"count\"} 0\n"));
+        assert!(test_render.contains("relay_evicted_messages_total{reason=\"bytes\"} 1\n"));
+        assert!(test_render.contains("relay_evicted_messages_total{reason=\"age\"} 0\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropped_frames() -> Result<(), Box<dyn std::error::Error>> {
+        let test_metrics = Metrics::default();
+        let test_open = Arc::new(AtomicU64::new(3));
+        let test_closed = Arc::new(AtomicU64::new(2));
+        let test_quiet = Arc::new(AtomicU64::new(0));
+
+        test_metrics.track("test_open", test_open.to_owned()).await;
+        test_metrics
+            .track("test_\"quoted\"", test_closed.to_owned())
+            .await;
+        test_metrics
+            .track("test_quiet", test_quiet.to_owned())
+            .await;
+
+        let test_render = test_metrics.render().await;
+
+        assert!(test_render.contains("relay_dropped_frames_total 5\n"));
+        assert!(test_render.contains("relay_connection_dropped_frames{uuid=\"test_open\"} 3\n"));
+        assert!(test_render
+            .contains("relay_connection_dropped_frames{uuid=\"test_\\\"quoted\\\"\"} 2\n"));
+        assert!(!test_render.contains("test_quiet"));
+
+        test_metrics
+            .untrack("test_\"quoted\"", &test_closed, true)
+            .await;
+        test_open.fetch_add(1, Ordering::Relaxed);
+
+        let test_render = test_metrics.render().await;
+
+        assert_eq!(test_metrics.dropped_frames_total().await, 6);
+        assert!(test_render.contains("relay_slow_consumer_disconnects_total 1\n"));
+        assert!(!test_render.contains("quoted"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+
+use crate::store::MessageRecord;
+
+/// Words kept in a snippet ahead of the first match.
+const SNIPPET_LEAD: usize = 6;
+
+/// Words a snippet holds, including the lead.
+const SNIPPET_WORDS: usize = 24;
+
+/// One message matching a search.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub message: MessageRecord,
+    /// Relevance to the query, higher first
+    pub score: f64,
+    /// Contents around the first match, escaped as HTML with each matching word in `<mark>`
+    pub snippet: String,
+}
+
+/// A page of the messages in a room matching a query, best first.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SearchPage {
+    pub room: String,
+    pub query: String,
+    pub offset: usize,
+    /// Matches across every page
+    pub total: usize,
+    pub results: Vec<SearchResult>,
+}
+
+/// The words indexed for one message, so that it can be ranked and taken back out.
+#[derive(Debug)]
+struct Document {
+    sequence: u64,
+    words: usize,
+    terms: Vec<String>,
+}
+
+/// An inverted index over one room's messages, from each word to the messages holding it and how
+/// many times each does. Deleted messages are left out.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, HashMap<String, usize>>,
+    documents: HashMap<String, Document>,
+}
+
+impl SearchIndex {
+    pub async fn init() -> SearchIndex {
+        let postings = HashMap::with_capacity(100);
+        let documents = HashMap::with_capacity(100);
+
+        SearchIndex {
+            postings,
+            documents,
+        }
+    }
+
+    /// Index a message, or reindex a newer version of one already held.
+    pub async fn insert(&mut self, message: &MessageRecord) {
+        self.remove(&message.id).await;
+
+        if message.deleted {
+            return;
+        }
+
+        let words = words(&message.contents).await;
+
+        if words.is_empty() {
+            return;
+        }
+
+        let mut terms = Vec::with_capacity(words.len());
+
+        for word in &words {
+            let count = self
+                .postings
+                .entry(word.to_owned())
+                .or_default()
+                .entry(message.id.to_owned())
+                .or_default();
+
+            if *count == 0 {
+                terms.push(word.to_owned());
+            }
+
+            *count += 1;
+        }
+
+        let document = Document {
+            sequence: message.sequence,
+            words: words.len(),
+            terms,
+        };
+
+        self.documents.insert(message.id.to_owned(), document);
+    }
+
+    pub async fn remove(&mut self, id: &str) {
+        if let Some(document) = self.documents.remove(id) {
+            for term in document.terms {
+                if let Some(posting) = self.postings.get_mut(&term) {
+                    posting.remove(id);
+
+                    if posting.is_empty() {
+                        self.postings.remove(&term);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ids of the messages holding any word of the query, scored by tf-idf with each message's
+    /// term frequencies weighed against its length, best first and newest first among equals.
+    pub async fn search(&self, query: &str) -> Vec<(String, f64)> {
+        let mut terms = words(query).await;
+
+        terms.sort();
+        terms.dedup();
+
+        let total = self.documents.len() as f64;
+        let mut scores: HashMap<&str, f64> = HashMap::with_capacity(10);
+
+        for term in &terms {
+            if let Some(posting) = self.postings.get(term) {
+                let idf = (1.0 + total / posting.len() as f64).ln();
+
+                for (id, count) in posting {
+                    let words = self.documents.get(id).map_or(1, |document| document.words);
+                    let tf = *count as f64 / (words as f64).sqrt();
+
+                    *scores.entry(id.as_str()).or_default() += tf * idf;
+                }
+            }
+        }
+
+        let mut ranked: Vec<(&str, f64)> = scores.into_iter().collect();
+
+        ranked.sort_by(|(id, score), (other_id, other_score)| {
+            other_score
+                .total_cmp(score)
+                .then_with(|| self.sequence(other_id).cmp(&self.sequence(id)))
+        });
+
+        ranked
+            .into_iter()
+            .map(|(id, score)| (id.to_owned(), score))
+            .collect()
+    }
+
+    fn sequence(&self, id: &str) -> u64 {
+        self.documents
+            .get(id)
+            .map_or(0, |document| document.sequence)
+    }
+}
+
+/// Byte ranges of each run of letters and digits in some text.
+async fn spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::with_capacity(10);
+    let mut start = None;
+
+    for (index, character) in text.char_indices() {
+        match (character.is_alphanumeric(), start) {
+            (true, None) => start = Some(index),
+            (false, Some(begun)) => {
+                spans.push((begun, index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(begun) = start {
+        spans.push((begun, text.len()));
+    }
+
+    spans
+}
+
+/// The words in some text, lowercased, in the order they appear.
+pub async fn words(text: &str) -> Vec<String> {
+    spans(text)
+        .await
+        .into_iter()
+        .map(|(start, end)| text[start..end].to_lowercase())
+        .collect()
+}
+
+/// A window of the contents starting a few words ahead of the first word matching the query,
+/// escaped as HTML, with every matching word in it wrapped in `<mark>`. Ellipses mark where the
+/// contents were cut.
+pub async fn snippet(contents: &str, query: &str) -> String {
+    let terms: HashSet<String> = words(query).await.into_iter().collect();
+    let spans = spans(contents).await;
+    let matches =
+        |&(start, end): &(usize, usize)| terms.contains(&contents[start..end].to_lowercase());
+
+    let first = spans.iter().position(matches).unwrap_or(0);
+    let lead = first.saturating_sub(SNIPPET_LEAD);
+    let last = (lead + SNIPPET_WORDS).min(spans.len());
+
+    let start = match lead {
+        0 => 0,
+        lead => spans[lead].0,
+    };
+    let end = match last == spans.len() {
+        true => contents.len(),
+        false => spans[last - 1].1,
+    };
+
+    let mut snippet = String::with_capacity(end - start + 32);
+    let mut cursor = start;
+
+    if start > 0 {
+        snippet.push('…');
+    }
+
+    for span in spans[lead..last].iter().filter(|span| matches(span)) {
+        snippet.push_str(&escape(&contents[cursor..span.0]).await);
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape(&contents[span.0..span.1]).await);
+        snippet.push_str("</mark>");
+        cursor = span.1;
+    }
+
+    snippet.push_str(&escape(&contents[cursor..end]).await);
+
+    if end < contents.len() {
+        snippet.push('…');
+    }
+
+    snippet
+}
+
+async fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            character => escaped.push(character),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn words() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            super::words("Hello, World! it's 2024 — Ünïcode").await,
+            vec!["hello", "world", "it", "s", "2024", "ünïcode"],
+        );
+        assert!(super::words("  ...  ").await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn search() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_index = SearchIndex::init().await;
+
+        for (test_sequence, test_contents) in [
+            "the quick brown fox",
+            "a fox, another fox and a fox",
+            "a slow brown dog",
+            "nothing to see here",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let test_message = MessageRecord {
+                id: format!("test_id_{}", test_sequence + 1),
+                sequence: test_sequence as u64 + 1,
+                contents: test_contents.to_string(),
+                ..MessageRecord::default()
+            };
+
+            test_index.insert(&test_message).await;
+        }
+
+        let test_ids = |test_ranked: Vec<(String, f64)>| -> Vec<String> {
+            test_ranked
+                .into_iter()
+                .map(|(test_id, _)| test_id)
+                .collect()
+        };
+
+        assert_eq!(
+            test_ids(test_index.search("FOX").await),
+            vec!["test_id_2", "test_id_1"],
+        );
+        assert_eq!(
+            test_ids(test_index.search("brown fox").await),
+            vec!["test_id_2", "test_id_1", "test_id_3"],
+        );
+        assert!(test_index.search("cat").await.is_empty());
+        assert!(test_index.search("").await.is_empty());
+
+        let test_edited = MessageRecord {
+            id: String::from("test_id_4"),
+            sequence: 4,
+            contents: String::from("the quick brown fox"),
+            ..MessageRecord::default()
+        };
+
+        test_index.insert(&test_edited).await;
+
+        assert_eq!(
+            test_ids(test_index.search("quick").await),
+            vec!["test_id_4", "test_id_1"],
+        );
+        assert!(test_index.search("nothing").await.is_empty());
+
+        let test_deleted = MessageRecord {
+            id: String::from("test_id_1"),
+            sequence: 1,
+            deleted: true,
+            ..MessageRecord::default()
+        };
+
+        test_index.insert(&test_deleted).await;
+        test_index.remove("test_id_2").await;
+
+        assert_eq!(test_ids(test_index.search("fox").await), vec!["test_id_4"]);
+        assert!(!test_index.postings.contains_key("another"));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn snippet() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            super::snippet("Say <hi> to the Fox & friends", "fox").await,
+            "Say &lt;hi&gt; to the <mark>Fox</mark> &amp; friends",
+        );
+
+        let test_contents = (1..=40)
+            .map(|test_word| format!("w{}", test_word))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        assert_eq!(
+            super::snippet(&test_contents, "w10 w12").await,
+            "…w4 w5 w6 w7 w8 w9 <mark>w10</mark> w11 <mark>w12</mark> w13 w14 w15 w16 w17 w18 w19 w20 w21 w22 w23 w24 w25 w26 w27…",
+        );
+        assert_eq!(
+            super::snippet("no match here.", "fox").await,
+            "no match here.",
+        );
+
+        Ok(())
+    }
+}
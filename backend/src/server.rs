@@ -1,39 +1,61 @@
-use futures_util::stream::SplitSink;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::sync::mpsc;
+use tokio::time::{self, MissedTickBehavior};
 
+use warp::http::StatusCode;
 use warp::ws::{Message, WebSocket, Ws};
-use warp::{ws, Filter};
+use warp::{ws, Filter, Rejection, Reply};
 
 use uuid::Uuid;
 
 use crate::{error, info};
 
-use crate::channels::{
-    add_message, add_user, get_messages, get_user, get_users, remove_user, shutdown,
-};
-use crate::channels::{ShutdownSignal, StateSender, WebSocketConnection, WebSocketReceiver};
-use crate::json::{MessageKind, Object};
+use crate::auth::{token, Auth, Unauthorized};
+use crate::blobs::BlobStore;
+use crate::channels::{add_user, outbound, register_session, resume_session, search, shutdown};
+use crate::channels::{RelayError, ShutdownSignal, StateSender};
+use crate::channels::{WebSocketConnection, WebSocketReceiver, SLOW_CONSUMER_CLOSE_CODE};
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::session::{Session, SessionOptions};
+use crate::state::{SuspendedSession, DEFAULT_ROOM};
 
 pub struct Server {
     socket_address: SocketAddr,
+    path: String,
+    options: SessionOptions,
+    auth: Arc<Auth>,
     sender: StateSender,
     shutdown_signal: ShutdownSignal,
+    metrics: Arc<Metrics>,
 }
 
 impl Server {
     pub async fn init(
-        socket_address: SocketAddr,
+        config: &Config,
         sender: StateSender,
         shutdown_signal: ShutdownSignal,
+        metrics: Arc<Metrics>,
     ) -> Result<Server, Box<dyn std::error::Error>> {
+        let socket_address = config.socket_address().await;
+        let path = config.path.to_owned();
+        let options = SessionOptions::init(config, metrics.to_owned()).await;
+        let auth = Arc::new(Auth::init(config).await?);
+
         Ok(Server {
             socket_address,
+            path,
+            options,
+            auth,
             sender,
             shutdown_signal,
+            metrics,
         })
     }
 
@@ -43,19 +65,42 @@ impl Server {
 
         let mut shutdown_signal = self.shutdown_signal.to_owned();
         let send_shutdown = self.sender.to_owned();
+        let options = self.options.to_owned();
+        let blobs = self.options.blobs.to_owned();
+        let search = Self::search(
+            self.auth.to_owned(),
+            self.sender.to_owned(),
+            self.options.history_page_size,
+        )
+        .recover(Self::unauthorized);
 
-        let filter = warp::path("ws")
+        let websocket = warp::path(self.path.to_owned())
             .and(ws())
+            .and(Self::authenticate(self.auth.to_owned()))
+            .and(Self::resume())
             .and(state_channel)
-            .map(|ws: Ws, state_channel| {
-                ws.on_upgrade(|connection| async move {
-                    if let Err(error) = Self::handle(connection, state_channel).await {
-                        error!("connection error -> {:?}", error)
-                    }
-                })
-            });
+            .map(
+                move |ws: Ws, subject: Option<String>, resume: Option<String>, state_channel| {
+                    let options = options.to_owned();
+
+                    ws.on_upgrade(move |connection| async move {
+                        if let Err(error) =
+                            Self::handle(connection, state_channel, options, subject, resume).await
+                        {
+                            error!("connection error -> {:?}", error)
+                        }
+                    })
+                },
+            )
+            .recover(Self::unauthorized);
+
+        let filter = websocket
+            .or(Self::metrics(self.metrics.to_owned()))
+            .or(Self::attachments(self.auth.to_owned(), blobs).recover(Self::unauthorized))
+            .or(search);
 
         info!("socket address -> {:?}", self.socket_address);
+        info!("websocket path -> /{}", self.path);
 
         let (_, server) =
             warp::serve(filter).bind_with_graceful_shutdown(self.socket_address, async move {
@@ -73,16 +118,172 @@ impl Server {
         Ok(())
     }
 
+    fn metrics(
+        metrics: Arc<Metrics>,
+    ) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp::path("metrics")
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move || {
+                let metrics = metrics.to_owned();
+
+                async move { metrics.render().await }
+            })
+    }
+
+    /// Serve stored attachment bytes by id, as a download with the content type the uploader
+    /// declared. Authenticated the same way as an upgrade. Blobs never change, so they can be
+    /// cached forever.
+    fn attachments(
+        auth: Arc<Auth>,
+        blobs: Arc<BlobStore>,
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+        warp::path!("attachments" / String)
+            .and(warp::get())
+            .and(Self::authenticate(auth))
+            .then(move |id: String, _: Option<String>| {
+                let blobs = blobs.to_owned();
+
+                async move {
+                    match blobs.get(&id).await {
+                        Ok(Some((bytes, content_type))) => warp::http::Response::builder()
+                            .header("content-type", content_type)
+                            .header("x-content-type-options", "nosniff")
+                            .header("content-disposition", "attachment")
+                            .header("cache-control", "private, max-age=31536000, immutable")
+                            .body(bytes.into())
+                            .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+                        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+                        Err(error) => {
+                            error!("attachment read error -> {:?}", error);
+
+                            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Search one room's history with `?q=`, optionally naming the `room`, which defaults to the
+    /// lobby, and paging with `offset` and `limit`. Needs a token, as an upgrade does, and only
+    /// answers for rooms the token's subject is in.
+    fn search(
+        auth: Arc<Auth>,
+        state: StateSender,
+        page_size: usize,
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+        warp::path!("api" / "search")
+            .and(warp::get())
+            .and(Self::authenticate(auth))
+            .and(warp::query::<HashMap<String, String>>())
+            .then(
+                move |subject: Option<String>, query: HashMap<String, String>| {
+                    let state = state.to_owned();
+
+                    async move {
+                        let number =
+                            |key: &str| query.get(key).map(|value| value.parse()).transpose();
+                        let (text, offset, limit) =
+                            match (query.get("q"), number("offset"), number("limit")) {
+                                (Some(text), Ok(offset), Ok(limit)) => (
+                                    text,
+                                    offset.unwrap_or_default(),
+                                    limit.unwrap_or(page_size).clamp(1, page_size),
+                                ),
+                                _ => return StatusCode::BAD_REQUEST.into_response(),
+                            };
+                        let room = query.get("room").map_or(DEFAULT_ROOM, String::as_str);
+                        let Some(subject) = subject else {
+                            return StatusCode::FORBIDDEN.into_response();
+                        };
+
+                        match search(&state, &subject, room, text, offset, limit).await {
+                            Ok(Some(page)) => warp::reply::json(&page).into_response(),
+                            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+                            Err(error) => {
+                                error!("search error -> {:?}", error);
+
+                                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                            }
+                        }
+                    }
+                },
+            )
+    }
+
+    /// Extract the authenticated subject from the upgrade request, rejecting it if the token is
+    /// missing or invalid while authentication is configured.
+    fn authenticate(
+        auth: Arc<Auth>,
+    ) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("authorization")
+            .and(warp::query::<HashMap<String, String>>())
+            .and_then(
+                move |authorization: Option<String>, query: HashMap<String, String>| {
+                    let auth = auth.to_owned();
+
+                    async move {
+                        let token = token(authorization.as_deref(), &query).await;
+
+                        match auth.authenticate(token).await {
+                            Ok(subject) => Ok(subject),
+                            Err(auth_error) => {
+                                info!("rejected upgrade -> {}", auth_error);
+
+                                Err(warp::reject::custom(Unauthorized(auth_error)))
+                            }
+                        }
+                    }
+                },
+            )
+    }
+
+    /// The token from `?resume=`, if the client is reconnecting.
+    fn resume() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+        warp::query::<HashMap<String, String>>()
+            .map(|query: HashMap<String, String>| query.get("resume").cloned())
+    }
+
+    async fn unauthorized(rejection: Rejection) -> Result<impl Reply, Rejection> {
+        match rejection.find::<Unauthorized>() {
+            Some(Unauthorized(auth_error)) => {
+                let reply =
+                    warp::reply::with_header(auth_error.to_string(), "www-authenticate", "Bearer");
+
+                Ok(warp::reply::with_status(reply, StatusCode::UNAUTHORIZED))
+            }
+            None => Err(rejection),
+        }
+    }
+
     async fn handle(
         connection: WebSocket,
         state_channel: StateSender,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        options: SessionOptions,
+        subject: Option<String>,
+        resume: Option<String>,
+    ) -> Result<(), RelayError> {
         let (mut sink, mut stream) = connection.split();
-        let (sink_sender, mut sink_receiver) = mpsc::channel(16);
-        let initial_state_sender = state_channel.clone();
-        let (session_id, uuid) = Server::create_account().await;
+        let (sink_sender, mut sink_receiver) =
+            outbound(options.sink_capacity, options.slow_consumer).await;
+        let suspended = match &resume {
+            Some(token) => resume_session(&state_channel, token, subject.as_deref()).await?,
+            None => None,
+        };
+        let (token, uuid) = Server::create_account(subject, suspended.as_ref()).await;
+
+        // An authenticated subject keeps the nickname it claimed on an earlier connection
+        let nickname = add_user(&state_channel, uuid.to_owned(), sink_sender.to_owned()).await?;
+
+        if options.resumable {
+            register_session(&state_channel, &token, &uuid).await?;
+        }
+
+        let metrics = options.metrics.to_owned();
+        let dropped = sink_sender.dropped_counter().await;
+        let outbound_sender = sink_sender.to_owned();
 
-        add_user(&state_channel, session_id.clone(), sink_sender).await?;
+        metrics.track(&uuid, dropped.to_owned()).await;
 
         tokio::spawn(async move {
             if let Err(error) = Server::incoming_connection(&mut sink_receiver, &mut sink).await {
@@ -90,80 +291,117 @@ impl Server {
             }
         });
 
-        tokio::spawn(async move {
-            if let Err(error) = Server::initial_messages(initial_state_sender, &uuid).await {
-                error!("initial connection tasks -> {:?}", error);
-            }
-        });
+        let heartbeat_interval = options.heartbeat_interval;
+        let mut session = Session::init(
+            uuid.to_owned(),
+            token,
+            state_channel,
+            sink_sender,
+            options,
+            nickname,
+            suspended,
+        )
+        .await;
 
-        while let Some(incoming) = stream.next().await {
-            match incoming {
-                Ok(message) => {
-                    if message.is_text() {
-                        info!("received text -> {:?}", &message);
-
-                        add_message(&state_channel, &message).await?;
-
-                        let connected_users = get_users(&state_channel).await?;
-                        let contents = String::from_utf8(message.to_owned().into_bytes())?;
-                        let message_object = Object::build(MessageKind::Message, contents).await;
-                        let websocket_message = message_object.to_message().await?;
-
-                        for connected_user in connected_users.values() {
-                            connected_user
-                                .send(WebSocketConnection::SendMessage(
-                                    websocket_message.to_owned(),
-                                ))
-                                .await?;
+        if let Err(error) = Server::receive(&mut session, &mut stream, heartbeat_interval).await {
+            error!("closing connection -> {}", error);
+        }
+
+        let closed = session.close().await;
+        let disconnected = outbound_sender.disconnected().await;
+
+        if outbound_sender.dropped().await > 0 {
+            info!(
+                "connection dropped {} frames -> {:?}",
+                outbound_sender.dropped().await,
+                &uuid,
+            );
+        }
+
+        metrics.untrack(&uuid, &dropped, disconnected).await;
+
+        closed
+    }
+
+    /// Start the session and feed it frames until the client closes, the stream ends or errors,
+    /// the heartbeat gives up, or handling a frame fails in a way the session can not recover
+    /// from. Whichever happens, the caller closes the session afterwards.
+    async fn receive(
+        session: &mut Session,
+        stream: &mut SplitStream<WebSocket>,
+        heartbeat_interval: Duration,
+    ) -> Result<(), RelayError> {
+        session.start().await?;
+
+        let mut heartbeat = time::interval(heartbeat_interval);
+
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        heartbeat.tick().await;
+
+        loop {
+            tokio::select! {
+                incoming = stream.next() => {
+                    let incoming = match incoming {
+                        Some(incoming) => incoming,
+                        None => {
+                            info!("stream ended");
+
+                            break;
                         }
-                    }
-                    if message.is_binary() {
-                        info!("received binary -> {:?}", &message);
+                    };
 
-                        unimplemented!();
-                    }
-                    if message.is_ping() {
-                        info!("received ping -> {:?}", &message);
+                    match incoming {
+                        Ok(message) => {
+                            let handled = if message.is_text() {
+                                info!("received text -> {:?}", &message);
 
-                        unimplemented!();
-                    }
-                    if message.is_pong() {
-                        info!("received pong -> {:?}", &message);
+                                session.text(message.to_str().unwrap_or_default()).await
+                            } else if message.is_binary() {
+                                info!("received binary -> {} bytes", message.as_bytes().len());
 
-                        unimplemented!();
-                    }
-                    if message.is_close() {
-                        info!("received close -> {:?}", &message);
+                                session.binary(message.as_bytes()).await
+                            } else if message.is_ping() {
+                                info!("received ping -> {:?}", &message);
 
-                        let current_user = get_user(&state_channel, &session_id).await?;
+                                session.pong(message.as_bytes()).await
+                            } else if message.is_pong() {
+                                info!("received pong -> {:?}", &message);
 
-                        current_user.send(WebSocketConnection::Close).await?;
+                                session.ponged().await;
 
-                        remove_user(&state_channel, &session_id).await?;
+                                Ok(())
+                            } else if message.is_close() {
+                                info!("received close -> {:?}", &message);
 
-                        let remaining_users = get_users(&state_channel).await?;
-                        let connected_users_count = Object::build(
-                            MessageKind::ConnectedUsers,
-                            remaining_users.len().to_string(),
-                        )
-                        .await;
-                        let connected_users_count_message =
-                            connected_users_count.to_message().await?;
-
-                        for remaining_user in remaining_users.values() {
-                            remaining_user
-                                .send(WebSocketConnection::SendMessage(
-                                    connected_users_count_message.to_owned(),
-                                ))
-                                .await?;
+                                break;
+                            } else {
+                                Ok(())
+                            };
+
+                            if let Err(error) = handled {
+                                session.recover(error).await?;
+                            }
+                        }
+                        Err(error) => {
+                            error!(
+                                "relay server incoming websocket connection error -> {:?}",
+                                error,
+                            );
+
+                            break;
                         }
                     }
                 }
-                Err(error) => {
-                    error!(
-                        "relay server incoming websocket connection error -> {:?}",
-                        error,
-                    );
+                _ = heartbeat.tick() => {
+                    if session.idle().await {
+                        info!("closing idle connection...");
+
+                        break;
+                    }
+
+                    if let Err(error) = session.ping().await {
+                        session.recover(error).await?;
+                    }
                 }
             }
         }
@@ -171,75 +409,43 @@ impl Server {
         Ok(())
     }
 
-    async fn create_account() -> (String, String) {
-        let session_id = Uuid::new_v4().to_string();
-        let uuid = session_id.to_owned();
-
-        (session_id, uuid)
+    /// A fresh resume token, and the uuid of a resumed session, the authenticated subject or a
+    /// new random uuid, in that order.
+    async fn create_account(
+        subject: Option<String>,
+        suspended: Option<&SuspendedSession>,
+    ) -> (String, String) {
+        let token = Uuid::new_v4().to_string();
+        let uuid = match suspended {
+            Some(suspended) => suspended.uuid.to_owned(),
+            None => subject.unwrap_or_else(|| Uuid::new_v4().to_string()),
+        };
+
+        (token, uuid)
     }
 
     async fn incoming_connection(
         sink_receiver: &mut WebSocketReceiver,
         sink: &mut SplitSink<WebSocket, Message>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), RelayError> {
         while let Some(incoming) = sink_receiver.recv().await {
             match incoming {
                 WebSocketConnection::SendMessage(message) => {
+                    // The socket only takes an owned frame, which it copies into its write
+                    // buffer anyway, so queued frames stay shared until the moment they are
+                    // written and only the last connection holding one avoids copying it.
+                    let message =
+                        Arc::try_unwrap(message).unwrap_or_else(|shared| (*shared).clone());
+
                     sink.send(message).await?;
                 }
                 WebSocketConnection::Close => {
                     sink.close().await?;
                 }
-            }
-        }
-
-        Ok(())
-    }
-
-    async fn initial_messages(
-        state: StateSender,
-        uuid: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let connected_users = get_users(&state).await?;
-        let older_messages = get_messages(&state).await?;
-
-        if let Some(current_user) = connected_users.get(uuid) {
-            let session_uuid = Object::build(MessageKind::Uuid, uuid.to_string()).await;
-            let session_uuid_message = session_uuid.to_message().await?;
-
-            current_user
-                .send(WebSocketConnection::SendMessage(session_uuid_message))
-                .await?;
-
-            for connected_user in connected_users.values() {
-                let connected_user_count = Object::build(
-                    MessageKind::ConnectedUsers,
-                    connected_users.len().to_string(),
-                )
-                .await;
-
-                let connected_user_count_message = connected_user_count.to_message().await?;
-
-                connected_user
-                    .send(WebSocketConnection::SendMessage(
-                        connected_user_count_message,
-                    ))
-                    .await?;
-            }
-
-            if older_messages.is_empty() {
-                info!("no older messages to send...");
-            } else {
-                info!("sending older messages ...");
+                WebSocketConnection::Disconnect => {
+                    let close = Message::close_with(SLOW_CONSUMER_CLOSE_CODE, "slow consumer");
 
-                for message in &older_messages {
-                    let contents = String::from_utf8(message.to_owned().into_bytes())?;
-                    let older_message = Object::build(MessageKind::Message, contents).await;
-                    let older_message_json = older_message.to_message().await?;
-
-                    current_user
-                        .send(WebSocketConnection::SendMessage(older_message_json))
-                        .await?;
+                    sink.send(close).await?;
                 }
             }
         }
@@ -251,89 +457,375 @@ impl Server {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::channels::{add_message, join_room, StateSender};
     use crate::channels::{StateRequest, StateResponse};
-    use std::collections::HashMap;
-    use std::str::FromStr;
+    use crate::config::SlowConsumer;
+    use crate::json::{Envelope, ErrorCode, ServerFrame, PROTOCOL_VERSION};
+    use crate::metrics::Metrics;
+    use crate::search::SearchPage;
+    use crate::state::State;
+    use crate::state::DEFAULT_ROOM;
+    use crate::store::{MemoryStore, MessageRecord};
+    use std::sync::Arc;
     use tokio::sync::{mpsc, oneshot, watch};
 
     #[tokio::test(flavor = "multi_thread")]
     async fn init() -> Result<(), Box<dyn std::error::Error>> {
-        let test_address = SocketAddr::from_str("127.0.0.1:1806")?;
+        let test_config = Config::default();
         let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
         let (test_send_shutdown_signal, test_receive_shutdown_signal) = watch::channel(1);
 
         drop(test_state_receiver);
         drop(test_send_shutdown_signal);
 
         let test_server = Server::init(
-            test_address,
+            &test_config,
             test_state_sender,
             test_receive_shutdown_signal,
+            Arc::new(Metrics::default()),
         )
-        .await;
+        .await?;
 
-        assert!(test_server.is_ok());
+        assert_eq!(test_server.socket_address.to_string(), "0.0.0.0:1806");
+        assert_eq!(test_server.path, "ws");
+        assert_eq!(test_server.options.sink_capacity, 16);
+        assert!(test_server.options.plain_text);
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn run() -> Result<(), Box<dyn std::error::Error>> {
-        let (test_state_sender, mut test_state_receiver) =
+    async fn metrics() -> Result<(), Box<dyn std::error::Error>> {
+        let test_metrics = Arc::new(Metrics::default());
+        let test_filter = Server::metrics(test_metrics);
+
+        let test_response = warp::test::request()
+            .path("/metrics")
+            .reply(&test_filter)
+            .await;
+
+        assert_eq!(test_response.status(), 200);
+        assert!(String::from_utf8(test_response.body().to_vec())?
+            .contains("relay_evicted_messages_total{reason=\"count\"} 0"));
+
+        let test_post = warp::test::request()
+            .method("POST")
+            .path("/metrics")
+            .reply(&test_filter)
+            .await;
+
+        assert_eq!(test_post.status(), 405);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn search() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
 
         tokio::spawn(async move {
-            let mut test_state_messages = Vec::with_capacity(5);
-            let mut test_state_users = HashMap::with_capacity(5);
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
 
-            while let Some((test_request, test_response)) = test_state_receiver.recv().await {
-                match test_request {
-                    StateRequest::AddMessage(test_new_message) => {
-                        test_state_messages.push(test_new_message);
-                    }
-                    StateRequest::AddUser((test_id, test_channel)) => {
-                        let test_none = test_state_users.insert(test_id.to_string(), test_channel);
+        for test_contents in ["a quick fox", "a lazy dog", "fox & hound"] {
+            let test_message = MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                uuid: String::from("test_uuid"),
+                contents: test_contents.to_owned(),
+                ..MessageRecord::default()
+            };
 
-                        assert!(test_none.is_none());
+            add_message(&test_state_sender, &test_message).await?;
+        }
 
-                        test_response.send(StateResponse::Ok).unwrap();
-                    }
-                    StateRequest::GetMessages => {
-                        let test_messages = test_state_messages.to_vec();
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
 
-                        test_response
-                            .send(StateResponse::Messages(test_messages))
-                            .unwrap();
-                    }
-                    StateRequest::GetUser(test_uuid) => match test_state_users.get(&test_uuid) {
-                        Some(test_user) => {
-                            test_response
-                                .send(StateResponse::User(test_user.to_owned()))
-                                .unwrap();
+        add_user(
+            &test_state_sender,
+            String::from("test_subject"),
+            test_websocket_sender,
+        )
+        .await?;
+        join_room(&test_state_sender, "test_subject", DEFAULT_ROOM).await?;
+
+        let test_path = std::env::temp_dir().join(format!("{}.tokens", Uuid::new_v4()));
+
+        std::fs::write(&test_path, "test_subject test_token\n")?;
+
+        let test_config = Config {
+            auth_token_file: Some(test_path.to_owned()),
+            ..Config::default()
+        };
+        let test_auth = Arc::new(Auth::init(&test_config).await?);
+
+        std::fs::remove_file(&test_path)?;
+
+        let test_filter = Server::search(test_auth, test_state_sender.to_owned(), 20);
+
+        let test_response = warp::test::request()
+            .path("/api/search?q=fox")
+            .header("authorization", "Bearer test_token")
+            .reply(&test_filter)
+            .await;
+
+        assert_eq!(test_response.status(), 200);
+
+        let test_page: SearchPage = serde_json::from_slice(test_response.body())?;
+
+        assert_eq!(test_page.room, DEFAULT_ROOM);
+        assert_eq!(test_page.total, 2);
+        assert_eq!(test_page.results[0].snippet, "<mark>fox</mark> &amp; hound");
+        assert_eq!(test_page.results[1].message.contents, "a quick fox");
+
+        let test_paged = warp::test::request()
+            .path("/api/search?q=fox&offset=1&limit=1")
+            .header("authorization", "Bearer test_token")
+            .reply(&test_filter)
+            .await;
+        let test_page: SearchPage = serde_json::from_slice(test_paged.body())?;
+
+        assert_eq!(test_page.total, 2);
+        assert_eq!(test_page.results.len(), 1);
+        assert_eq!(test_page.results[0].message.contents, "a quick fox");
+
+        let test_other_room = warp::test::request()
+            .path("/api/search?q=fox&room=test_room")
+            .header("authorization", "Bearer test_token")
+            .reply(&test_filter)
+            .await;
+
+        assert_eq!(test_other_room.status(), 404);
+
+        for test_path in ["/api/search", "/api/search?q=fox&limit=ten"] {
+            let test_invalid = warp::test::request()
+                .path(test_path)
+                .header("authorization", "Bearer test_token")
+                .reply(&test_filter)
+                .await;
+
+            assert_eq!(test_invalid.status(), 400);
+        }
+
+        let test_anonymous = warp::test::request()
+            .path("/api/search?q=fox")
+            .reply(&test_filter.recover(Server::unauthorized))
+            .await;
+
+        assert_eq!(test_anonymous.status(), 401);
+
+        let test_open_auth = Arc::new(Auth::init(&Config::default()).await?);
+        let test_open = warp::test::request()
+            .path("/api/search?q=fox")
+            .reply(&Server::search(test_open_auth, test_state_sender, 20))
+            .await;
+
+        assert_eq!(test_open.status(), 403);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn authenticate() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.tokens", Uuid::new_v4()));
+
+        std::fs::write(&test_path, "test_subject test_token\n")?;
+
+        let test_config = Config {
+            auth_token_file: Some(test_path.to_owned()),
+            ..Config::default()
+        };
+        let test_auth = Arc::new(Auth::init(&test_config).await?);
+
+        std::fs::remove_file(&test_path)?;
+
+        let test_filter = Server::authenticate(test_auth)
+            .map(|test_subject: Option<String>| test_subject.unwrap_or_default())
+            .recover(Server::unauthorized);
+
+        let test_missing = warp::test::request().path("/ws").reply(&test_filter).await;
+
+        assert_eq!(test_missing.status(), 401);
+        assert_eq!(test_missing.headers()["www-authenticate"], "Bearer");
+
+        let test_invalid = warp::test::request()
+            .path("/ws?token=test_wrong_token")
+            .reply(&test_filter)
+            .await;
+
+        assert_eq!(test_invalid.status(), 401);
+
+        let test_query = warp::test::request()
+            .path("/ws?token=test_token")
+            .reply(&test_filter)
+            .await;
+
+        assert_eq!(test_query.status(), 200);
+        assert_eq!(test_query.body(), "test_subject");
+
+        let test_bearer = warp::test::request()
+            .path("/ws")
+            .header("authorization", "Bearer test_token")
+            .reply(&test_filter)
+            .await;
+
+        assert_eq!(test_bearer.status(), 200);
+        assert_eq!(test_bearer.body(), "test_subject");
+
+        let test_open_filter =
+            Server::authenticate(Arc::new(Auth::init(&Config::default()).await?))
+                .map(|test_subject: Option<String>| format!("{:?}", test_subject));
+
+        let test_open = warp::test::request()
+            .path("/ws")
+            .reply(&test_open_filter)
+            .await;
+
+        assert_eq!(test_open.status(), 200);
+        assert_eq!(test_open.body(), "None");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn authenticated_session() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_config = Config {
+            auth_jwt_secret: Some(String::from("test_secret")),
+            ..Config::default()
+        };
+        let test_auth = Arc::new(Auth::init(&test_config).await?);
+        let test_options = SessionOptions::init(&test_config, Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws")
+            .and(ws())
+            .and(Server::authenticate(test_auth))
+            .and(test_state_channel)
+            .map(
+                move |ws: warp::ws::Ws, test_subject: Option<String>, test_state_channel| {
+                    let test_options = test_options.to_owned();
+
+                    ws.on_upgrade(move |test_connection| async move {
+                        if let Err(error) = Server::handle(
+                            test_connection,
+                            test_state_channel,
+                            test_options,
+                            test_subject,
+                            None,
+                        )
+                        .await
+                        {
+                            println!("there was an error : {:?}", error);
                         }
-                        None => assert!(test_state_users.get(&test_uuid).is_none()),
-                    },
-                    StateRequest::GetUsers => {
-                        test_response
-                            .send(StateResponse::Users(test_state_users.clone()))
-                            .unwrap();
-                    }
-                    StateRequest::RemoveUser(_) => {
-                        test_state_users.clear();
-                    }
-                    StateRequest::Shutdown => {
-                        unimplemented!();
-                    }
-                }
+                    })
+                },
+            )
+            .recover(Server::unauthorized);
+
+        assert!(warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await
+            .is_err());
+
+        let test_token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &serde_json::json!({
+                "sub": "test_subject",
+                "exp": crate::store::timestamp().await / 1000 + 3600,
+            }),
+            &jsonwebtoken::EncodingKey::from_secret(b"test_secret"),
+        )?;
+
+        let mut test_client = warp::test::ws()
+            .path("/ws")
+            .header("authorization", format!("Bearer {}", test_token))
+            .handshake(test_filter)
+            .await?;
+
+        let test_session = test_client.recv().await?;
+        let test_session_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_session.to_str().unwrap())?;
+
+        match test_session_response.frame {
+            ServerFrame::Session { uuid, resumed, .. } => {
+                assert_eq!(uuid, "test_subject");
+                assert!(!resumed);
+            }
+            test_frame => panic!("expected session frame -> {:?}", test_frame),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn run() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
             }
         });
 
+        let test_options =
+            SessionOptions::init(&Config::default(), Arc::new(Metrics::default())).await;
         let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
         let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
-            |ws: warp::ws::Ws, test_state_channel| {
-                ws.on_upgrade(|test_connection| async move {
-                    if let Err(error) = Server::handle(test_connection, test_state_channel).await {
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
                         println!("there was an error : {:?}", error);
                     }
                 })
@@ -342,25 +834,82 @@ mod tests {
 
         let mut test_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
 
-        let test_uuid = test_client.recv().await?;
-        let test_uuid_response: Object = serde_json::from_str(test_uuid.to_str().unwrap())?;
+        let test_session = test_client.recv().await?;
+        let test_session_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_session.to_str().unwrap())?;
+
+        assert_eq!(test_session_response.version, PROTOCOL_VERSION);
+        assert!(matches!(
+            test_session_response.frame,
+            ServerFrame::Session { .. },
+        ));
+
+        let test_room = test_client.recv().await?;
+        let test_room_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_room.to_str().unwrap())?;
+
+        assert_eq!(
+            test_room_response.frame,
+            ServerFrame::Room {
+                room: DEFAULT_ROOM.to_owned(),
+                has_more: false,
+            },
+        );
+
+        let test_snapshot = test_client.recv().await?;
+        let test_snapshot_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_snapshot.to_str().unwrap())?;
 
-        assert_eq!(test_uuid_response.kind, "uuid");
+        match test_snapshot_response.frame {
+            ServerFrame::PresenceSnapshot { room, users } => {
+                assert_eq!(room, DEFAULT_ROOM);
+                assert_eq!(users.len(), 1);
+                assert!(users[0].nickname.starts_with("guest-"));
+            }
+            test_frame => panic!("expected presence snapshot frame -> {:?}", test_frame),
+        }
 
         let test_connected_users = test_client.recv().await?;
-        let test_connected_users_response: Object =
+        let test_connected_users_response: Envelope<ServerFrame> =
             serde_json::from_str(test_connected_users.to_str().unwrap())?;
 
-        assert_eq!(test_connected_users_response.kind, "connected_users");
-        assert_eq!(test_connected_users_response.contents, "1");
+        assert_eq!(
+            test_connected_users_response.frame,
+            ServerFrame::ConnectedUsers {
+                room: DEFAULT_ROOM.to_owned(),
+                count: 1,
+            },
+        );
 
         test_client.send_text("test_message").await;
 
         let test_message = test_client.recv().await?;
-        let test_message_response: Object = serde_json::from_str(test_message.to_str().unwrap())?;
+        let test_message_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_message.to_str().unwrap())?;
+
+        match test_message_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.room, DEFAULT_ROOM);
+                assert_eq!(test_record.contents, "test_message");
+                assert!(test_record.timestamp > 0);
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
+
+        test_client
+            .send_text(r#"{"version":1,"type":"send_message","contents":"test_framed_message"}"#)
+            .await;
 
-        assert_eq!(test_message_response.kind, "message");
-        assert_eq!(test_message_response.contents, "test_message");
+        let test_framed_message = test_client.recv().await?;
+        let test_framed_message_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_framed_message.to_str().unwrap())?;
+
+        match test_framed_message_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.contents, "test_framed_message");
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
 
         test_client.send(Message::close()).await;
 
@@ -370,4 +919,1359 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resume() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_options =
+            SessionOptions::init(&Config::default(), Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws")
+            .and(ws())
+            .and(Server::resume())
+            .and(test_state_channel)
+            .map(
+                move |ws: warp::ws::Ws, test_resume: Option<String>, test_state_channel| {
+                    let test_options = test_options.to_owned();
+
+                    ws.on_upgrade(move |test_connection| async move {
+                        if let Err(error) = Server::handle(
+                            test_connection,
+                            test_state_channel,
+                            test_options,
+                            None,
+                            test_resume,
+                        )
+                        .await
+                        {
+                            println!("there was an error : {:?}", error);
+                        }
+                    })
+                },
+            );
+
+        let mut test_client = warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await?;
+
+        let test_session = test_client.recv().await?;
+        let test_session_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_session.to_str().unwrap())?;
+        let (test_uuid, test_token) = match test_session_response.frame {
+            ServerFrame::Session {
+                uuid,
+                token,
+                resumed,
+            } => {
+                assert!(!resumed);
+
+                (uuid, token)
+            }
+            test_frame => panic!("expected session frame -> {:?}", test_frame),
+        };
+
+        for _ in 0..3 {
+            test_client.recv().await?;
+        }
+
+        test_client
+            .send_text(r#"{"version":1,"type":"set_nickname","nickname":"test_nickname"}"#)
+            .await;
+        test_client.recv().await?;
+        test_client.send_text("test_seen_message").await;
+        test_client.recv().await?;
+        test_client.send(Message::close()).await;
+
+        assert!(test_client.recv_closed().await.is_ok());
+
+        let mut test_other_client = warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await?;
+
+        for _ in 0..5 {
+            test_other_client.recv().await?;
+        }
+
+        test_other_client.send_text("test_missed_message").await;
+        test_other_client.recv().await?;
+
+        let mut test_resumed_client = warp::test::ws()
+            .path(&format!("/ws?resume={}", test_token))
+            .handshake(test_filter.clone())
+            .await?;
+
+        let test_session = test_resumed_client.recv().await?;
+        let test_session_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_session.to_str().unwrap())?;
+
+        let test_resumed_token = match test_session_response.frame {
+            ServerFrame::Session {
+                uuid,
+                token,
+                resumed,
+            } => {
+                assert_eq!(uuid, test_uuid);
+                assert_ne!(token, test_token);
+                assert!(resumed);
+
+                token
+            }
+            test_frame => panic!("expected session frame -> {:?}", test_frame),
+        };
+
+        for _ in 0..3 {
+            test_resumed_client.recv().await?;
+        }
+
+        let test_missed = test_resumed_client.recv().await?;
+        let test_missed_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_missed.to_str().unwrap())?;
+
+        match test_missed_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.contents, "test_missed_message");
+                assert_eq!(test_record.sequence, 2);
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
+
+        test_resumed_client.send_text("test_resumed_message").await;
+
+        let test_resumed = test_resumed_client.recv().await?;
+        let test_resumed_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_resumed.to_str().unwrap())?;
+
+        match test_resumed_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.uuid, test_uuid);
+                assert_eq!(test_record.display_name, "test_nickname");
+                assert_eq!(test_record.contents, "test_resumed_message");
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
+
+        let mut test_takeover_client = warp::test::ws()
+            .path(&format!("/ws?resume={}", test_resumed_token))
+            .handshake(test_filter)
+            .await?;
+
+        let test_session = test_takeover_client.recv().await?;
+        let test_session_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_session.to_str().unwrap())?;
+
+        match test_session_response.frame {
+            ServerFrame::Session { uuid, resumed, .. } => {
+                assert_eq!(uuid, test_uuid);
+                assert!(resumed);
+            }
+            test_frame => panic!("expected session frame -> {:?}", test_frame),
+        }
+
+        assert!(test_resumed_client.recv_closed().await.is_ok());
+
+        for _ in 0..3 {
+            test_takeover_client.recv().await?;
+        }
+
+        let mut test_replayed = Vec::with_capacity(3);
+
+        for _ in 0..3 {
+            let test_message = test_takeover_client.recv().await?;
+            let test_message_response: Envelope<ServerFrame> =
+                serde_json::from_str(test_message.to_str().unwrap())?;
+
+            match test_message_response.frame {
+                ServerFrame::Message(test_record) => test_replayed.push(test_record.contents),
+                test_frame => panic!("expected message frame -> {:?}", test_frame),
+            }
+        }
+
+        assert_eq!(
+            test_replayed,
+            vec![
+                "test_seen_message",
+                "test_missed_message",
+                "test_resumed_message",
+            ],
+        );
+
+        test_takeover_client
+            .send_text("test_takeover_message")
+            .await;
+
+        let test_takeover = test_takeover_client.recv().await?;
+        let test_takeover_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_takeover.to_str().unwrap())?;
+
+        match test_takeover_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.uuid, test_uuid);
+                assert_eq!(test_record.display_name, "test_nickname");
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replay_has_more() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_config = Config {
+            history_page_size: 2,
+            ..Config::default()
+        };
+        let test_options = SessionOptions::init(&test_config, Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws")
+            .and(ws())
+            .and(Server::resume())
+            .and(test_state_channel)
+            .map(
+                move |ws: warp::ws::Ws, test_resume: Option<String>, test_state_channel| {
+                    let test_options = test_options.to_owned();
+
+                    ws.on_upgrade(move |test_connection| async move {
+                        if let Err(error) = Server::handle(
+                            test_connection,
+                            test_state_channel,
+                            test_options,
+                            None,
+                            test_resume,
+                        )
+                        .await
+                        {
+                            println!("there was an error : {:?}", error);
+                        }
+                    })
+                },
+            );
+
+        async fn test_frame(
+            test_client: &mut warp::test::WsClient,
+        ) -> Result<ServerFrame, Box<dyn std::error::Error>> {
+            let test_message = test_client.recv().await?;
+            let test_response: Envelope<ServerFrame> =
+                serde_json::from_str(test_message.to_str().unwrap())?;
+
+            Ok(test_response.frame)
+        }
+
+        async fn test_entered(
+            test_client: &mut warp::test::WsClient,
+            test_has_more: bool,
+            test_replayed: &[&str],
+        ) -> Result<String, Box<dyn std::error::Error>> {
+            let test_token = match test_frame(test_client).await? {
+                ServerFrame::Session { token, .. } => token,
+                test_frame => panic!("expected session frame -> {:?}", test_frame),
+            };
+
+            assert_eq!(
+                test_frame(test_client).await?,
+                ServerFrame::Room {
+                    room: DEFAULT_ROOM.to_owned(),
+                    has_more: test_has_more,
+                },
+            );
+
+            for _ in 0..2 {
+                test_client.recv().await?;
+            }
+
+            for test_contents in test_replayed {
+                match test_frame(test_client).await? {
+                    ServerFrame::Message(test_record) => {
+                        assert_eq!(test_record.contents, *test_contents)
+                    }
+                    test_frame => panic!("expected message frame -> {:?}", test_frame),
+                }
+            }
+
+            Ok(test_token)
+        }
+
+        let mut test_client = warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await?;
+        let test_token = test_entered(&mut test_client, false, &[]).await?;
+
+        for test_contents in ["test_one", "test_two", "test_three"] {
+            test_client.send_text(test_contents).await;
+            test_client.recv().await?;
+        }
+
+        test_client.send(Message::close()).await;
+
+        assert!(test_client.recv_closed().await.is_ok());
+
+        let mut test_other_client = warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await?;
+
+        test_entered(&mut test_other_client, true, &["test_two", "test_three"]).await?;
+
+        for test_contents in ["test_four", "test_five", "test_six"] {
+            test_other_client.send_text(test_contents).await;
+            test_other_client.recv().await?;
+        }
+
+        let mut test_resumed_client = warp::test::ws()
+            .path(&format!("/ws?resume={}", test_token))
+            .handshake(test_filter)
+            .await?;
+
+        test_entered(&mut test_resumed_client, true, &["test_five", "test_six"]).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn heartbeat() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_config = Config {
+            heartbeat_interval: std::time::Duration::from_millis(20),
+            heartbeat_timeout: std::time::Duration::from_millis(60),
+            ..Config::default()
+        };
+        let test_options = SessionOptions::init(&test_config, Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        println!("there was an error : {:?}", error);
+                    }
+                })
+            },
+        );
+
+        let mut test_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
+
+        for _ in 0..4 {
+            test_client.recv().await?;
+        }
+
+        test_client.send(Message::ping(b"test_ping".to_vec())).await;
+
+        let mut test_pings = 0;
+        let mut test_pong = false;
+
+        while test_pings < 5 {
+            let test_message = test_client.recv().await?;
+
+            if test_message.is_ping() {
+                test_pings += 1;
+            }
+
+            if test_message.is_pong() && test_message.as_bytes() == b"test_ping" {
+                test_pong = true;
+            }
+        }
+
+        assert!(test_pong);
+
+        test_client.send_text("test_message").await;
+
+        loop {
+            let test_message = test_client.recv().await?;
+
+            if test_message.is_text() {
+                assert!(test_message.to_str().unwrap().contains("test_message"));
+
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rooms() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_options =
+            SessionOptions::init(&Config::default(), Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        println!("there was an error : {:?}", error);
+                    }
+                })
+            },
+        );
+
+        let mut test_lobby_client = warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await?;
+
+        for _ in 0..4 {
+            test_lobby_client.recv().await?;
+        }
+
+        let mut test_room_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
+
+        for _ in 0..4 {
+            test_room_client.recv().await?;
+        }
+
+        let test_joined = test_lobby_client.recv().await?;
+        let test_joined_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_joined.to_str().unwrap())?;
+
+        match test_joined_response.frame {
+            ServerFrame::UserJoined(test_event) => {
+                assert_eq!(test_event.room, DEFAULT_ROOM);
+                assert!(test_event.nickname.starts_with("guest-"));
+                assert!(test_event.timestamp > 0);
+            }
+            test_frame => panic!("expected user joined frame -> {:?}", test_frame),
+        }
+
+        let test_lobby_count = test_lobby_client.recv().await?;
+        let test_lobby_count_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_lobby_count.to_str().unwrap())?;
+
+        assert_eq!(
+            test_lobby_count_response.frame,
+            ServerFrame::ConnectedUsers {
+                room: DEFAULT_ROOM.to_owned(),
+                count: 2,
+            },
+        );
+
+        test_room_client
+            .send_text(r#"{"version":1,"type":"join_room","room":"test_room"}"#)
+            .await;
+
+        let test_left = test_lobby_client.recv().await?;
+        let test_left_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_left.to_str().unwrap())?;
+
+        match test_left_response.frame {
+            ServerFrame::UserLeft(test_event) => assert_eq!(test_event.room, DEFAULT_ROOM),
+            test_frame => panic!("expected user left frame -> {:?}", test_frame),
+        }
+
+        let test_lobby_count = test_lobby_client.recv().await?;
+        let test_lobby_count_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_lobby_count.to_str().unwrap())?;
+
+        assert_eq!(
+            test_lobby_count_response.frame,
+            ServerFrame::ConnectedUsers {
+                room: DEFAULT_ROOM.to_owned(),
+                count: 1,
+            },
+        );
+
+        let test_room = test_room_client.recv().await?;
+        let test_room_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_room.to_str().unwrap())?;
+
+        assert_eq!(
+            test_room_response.frame,
+            ServerFrame::Room {
+                room: String::from("test_room"),
+                has_more: false,
+            },
+        );
+
+        let test_snapshot = test_room_client.recv().await?;
+        let test_snapshot_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_snapshot.to_str().unwrap())?;
+
+        match test_snapshot_response.frame {
+            ServerFrame::PresenceSnapshot { room, users } => {
+                assert_eq!(room, "test_room");
+                assert_eq!(users.len(), 1);
+            }
+            test_frame => panic!("expected presence snapshot frame -> {:?}", test_frame),
+        }
+
+        let test_room_count = test_room_client.recv().await?;
+        let test_room_count_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_room_count.to_str().unwrap())?;
+
+        assert_eq!(
+            test_room_count_response.frame,
+            ServerFrame::ConnectedUsers {
+                room: String::from("test_room"),
+                count: 1,
+            },
+        );
+
+        test_room_client.send_text("test_room_message").await;
+
+        let test_room_message = test_room_client.recv().await?;
+        let test_room_message_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_room_message.to_str().unwrap())?;
+
+        match test_room_message_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.room, "test_room");
+                assert_eq!(test_record.contents, "test_room_message");
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
+
+        test_lobby_client.send_text("test_lobby_message").await;
+
+        let test_lobby_message = test_lobby_client.recv().await?;
+        let test_lobby_message_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_lobby_message.to_str().unwrap())?;
+
+        match test_lobby_message_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.contents, "test_lobby_message");
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
+
+        test_room_client
+            .send_text(r#"{"version":1,"type":"leave_room"}"#)
+            .await;
+
+        let test_room = test_room_client.recv().await?;
+        let test_room_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_room.to_str().unwrap())?;
+
+        assert_eq!(
+            test_room_response.frame,
+            ServerFrame::Room {
+                room: DEFAULT_ROOM.to_owned(),
+                has_more: false,
+            },
+        );
+
+        let test_snapshot = test_room_client.recv().await?;
+        let test_snapshot_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_snapshot.to_str().unwrap())?;
+
+        match test_snapshot_response.frame {
+            ServerFrame::PresenceSnapshot { room, users } => {
+                assert_eq!(room, DEFAULT_ROOM);
+                assert_eq!(users.len(), 2);
+            }
+            test_frame => panic!("expected presence snapshot frame -> {:?}", test_frame),
+        }
+
+        let test_lobby_count = test_room_client.recv().await?;
+        let test_lobby_count_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_lobby_count.to_str().unwrap())?;
+
+        assert_eq!(
+            test_lobby_count_response.frame,
+            ServerFrame::ConnectedUsers {
+                room: DEFAULT_ROOM.to_owned(),
+                count: 2,
+            },
+        );
+
+        let test_history = test_room_client.recv().await?;
+        let test_history_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_history.to_str().unwrap())?;
+
+        match test_history_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.contents, "test_lobby_message");
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commands() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_options =
+            SessionOptions::init(&Config::default(), Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        println!("there was an error : {:?}", error);
+                    }
+                })
+            },
+        );
+
+        let mut test_client = warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await?;
+
+        for _ in 0..4 {
+            test_client.recv().await?;
+        }
+
+        let mut test_other_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
+
+        for _ in 0..4 {
+            test_other_client.recv().await?;
+        }
+
+        for _ in 0..2 {
+            test_client.recv().await?;
+        }
+
+        test_client
+            .send_text(r#"{"version":1,"type":"set_nickname","nickname":" test_nickname "}"#)
+            .await;
+
+        let test_nickname = test_client.recv().await?;
+        let test_nickname_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_nickname.to_str().unwrap())?;
+
+        match test_nickname_response.frame {
+            ServerFrame::Nickname { nickname, .. } => assert_eq!(nickname, "test_nickname"),
+            test_frame => panic!("expected nickname frame -> {:?}", test_frame),
+        }
+
+        let test_announcement = test_other_client.recv().await?;
+        let test_announcement_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_announcement.to_str().unwrap())?;
+
+        match test_announcement_response.frame {
+            ServerFrame::Nickname {
+                nickname, previous, ..
+            } => {
+                assert_eq!(nickname, "test_nickname");
+                assert!(previous.starts_with("guest-"));
+            }
+            test_frame => panic!("expected nickname frame -> {:?}", test_frame),
+        }
+
+        test_other_client
+            .send_text(r#"{"version":1,"type":"set_nickname","nickname":"TEST_NICKNAME"}"#)
+            .await;
+
+        let test_taken = test_other_client.recv().await?;
+        let test_taken_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_taken.to_str().unwrap())?;
+
+        match test_taken_response.frame {
+            ServerFrame::Error(test_frame_error) => {
+                assert_eq!(test_frame_error.code, ErrorCode::NicknameTaken)
+            }
+            test_frame => panic!("expected error frame -> {:?}", test_frame),
+        }
+
+        test_client
+            .send_text(r#"{"version":1,"type":"typing"}"#)
+            .await;
+
+        let test_typing = test_other_client.recv().await?;
+        let test_typing_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_typing.to_str().unwrap())?;
+
+        match test_typing_response.frame {
+            ServerFrame::Typing { room, .. } => assert_eq!(room, DEFAULT_ROOM),
+            test_frame => panic!("expected typing frame -> {:?}", test_frame),
+        }
+
+        test_client.send_text("test_message").await;
+        test_client.recv().await?;
+
+        let test_typing_stopped = test_other_client.recv().await?;
+        let test_typing_stopped_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_typing_stopped.to_str().unwrap())?;
+
+        match test_typing_stopped_response.frame {
+            ServerFrame::TypingStopped { room, .. } => assert_eq!(room, DEFAULT_ROOM),
+            test_frame => panic!("expected typing stopped frame -> {:?}", test_frame),
+        }
+
+        test_other_client.recv().await?;
+
+        test_other_client
+            .send_text(r#"{"version":1,"type":"fetch_history"}"#)
+            .await;
+
+        let test_history = test_other_client.recv().await?;
+        let test_history_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_history.to_str().unwrap())?;
+
+        let test_id = match test_history_response.frame {
+            ServerFrame::History {
+                room,
+                messages,
+                has_more,
+            } => {
+                assert_eq!(room, DEFAULT_ROOM);
+                assert!(!has_more);
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].contents, "test_message");
+                assert_eq!(messages[0].display_name, "test_nickname");
+                assert!(!messages[0].uuid.is_empty());
+
+                messages[0].id.to_owned()
+            }
+            test_frame => panic!("expected history frame -> {:?}", test_frame),
+        };
+
+        test_other_client
+            .send_text(&format!(
+                r#"{{"version":1,"type":"delete_message","id":"{}"}}"#,
+                test_id,
+            ))
+            .await;
+
+        let test_not_author = test_other_client.recv().await?;
+        let test_not_author_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_not_author.to_str().unwrap())?;
+
+        match test_not_author_response.frame {
+            ServerFrame::Error(test_frame_error) => {
+                assert_eq!(test_frame_error.code, ErrorCode::NotAuthor)
+            }
+            test_frame => panic!("expected error frame -> {:?}", test_frame),
+        }
+
+        test_client
+            .send_text(&format!(
+                r#"{{"version":1,"type":"edit_message","id":"{}","contents":"test_edit"}}"#,
+                test_id,
+            ))
+            .await;
+
+        for test_receiver in [&mut test_client, &mut test_other_client] {
+            let test_edited = test_receiver.recv().await?;
+            let test_edited_response: Envelope<ServerFrame> =
+                serde_json::from_str(test_edited.to_str().unwrap())?;
+
+            match test_edited_response.frame {
+                ServerFrame::MessageEdited(test_record) => {
+                    assert_eq!(test_record.id, test_id);
+                    assert_eq!(test_record.contents, "test_edit");
+                    assert!(test_record.edited_at.is_some());
+                }
+                test_frame => panic!("expected message edited frame -> {:?}", test_frame),
+            }
+        }
+
+        test_other_client
+            .send_text(r#"{"version":1,"type":"search","query":"EDIT"}"#)
+            .await;
+
+        let test_search = test_other_client.recv().await?;
+        let test_search_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_search.to_str().unwrap())?;
+
+        match test_search_response.frame {
+            ServerFrame::SearchResults(test_page) => {
+                assert_eq!(test_page.room, DEFAULT_ROOM);
+                assert_eq!(test_page.total, 1);
+                assert_eq!(test_page.results[0].message.id, test_id);
+                assert_eq!(test_page.results[0].snippet, "test_<mark>edit</mark>");
+            }
+            test_frame => panic!("expected search results frame -> {:?}", test_frame),
+        }
+
+        test_client
+            .send_text(&format!(
+                r#"{{"version":1,"type":"delete_message","id":"{}"}}"#,
+                test_id,
+            ))
+            .await;
+
+        for test_receiver in [&mut test_client, &mut test_other_client] {
+            let test_deleted = test_receiver.recv().await?;
+            let test_deleted_response: Envelope<ServerFrame> =
+                serde_json::from_str(test_deleted.to_str().unwrap())?;
+
+            match test_deleted_response.frame {
+                ServerFrame::MessageDeleted { room, id } => {
+                    assert_eq!(room, DEFAULT_ROOM);
+                    assert_eq!(id, test_id);
+                }
+                test_frame => panic!("expected message deleted frame -> {:?}", test_frame),
+            }
+        }
+
+        test_other_client
+            .send_text(r#"{"version":1,"type":"fetch_history"}"#)
+            .await;
+
+        let test_history = test_other_client.recv().await?;
+        let test_history_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_history.to_str().unwrap())?;
+
+        match test_history_response.frame {
+            ServerFrame::History { messages, .. } => {
+                assert!(messages[0].deleted);
+                assert!(messages[0].contents.is_empty());
+            }
+            test_frame => panic!("expected history frame -> {:?}", test_frame),
+        }
+
+        test_other_client
+            .send_text(r#"{"version":1,"type":"search","query":"edit"}"#)
+            .await;
+
+        let test_search = test_other_client.recv().await?;
+        let test_search_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_search.to_str().unwrap())?;
+
+        match test_search_response.frame {
+            ServerFrame::SearchResults(test_page) => assert_eq!(test_page.total, 0),
+            test_frame => panic!("expected search results frame -> {:?}", test_frame),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn errors() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_options =
+            SessionOptions::init(&Config::default(), Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        println!("there was an error : {:?}", error);
+                    }
+                })
+            },
+        );
+
+        let mut test_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
+
+        for _ in 0..4 {
+            test_client.recv().await?;
+        }
+
+        for (test_text, test_code) in [
+            (
+                r#"{"version":1,"type":"send_mess"#,
+                ErrorCode::MalformedFrame,
+            ),
+            (
+                r#"{"version":2,"type":"leave_room"}"#,
+                ErrorCode::UnsupportedVersion,
+            ),
+            (
+                r#"{"version":1,"type":"join_room","room":"test room"}"#,
+                ErrorCode::InvalidRoom,
+            ),
+        ] {
+            test_client.send_text(test_text).await;
+
+            let test_error = test_client.recv().await?;
+            let test_error_response: Envelope<ServerFrame> =
+                serde_json::from_str(test_error.to_str().unwrap())?;
+
+            match test_error_response.frame {
+                ServerFrame::Error(test_frame_error) => {
+                    assert_eq!(test_frame_error.code, test_code)
+                }
+                test_frame => panic!("expected error frame -> {:?}", test_frame),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn plain_text_disabled() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_config = Config {
+            plain_text: false,
+            ..Config::default()
+        };
+        let test_options = SessionOptions::init(&test_config, Arc::new(Metrics::default())).await;
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        println!("there was an error : {:?}", error);
+                    }
+                })
+            },
+        );
+
+        let mut test_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
+
+        for _ in 0..4 {
+            test_client.recv().await?;
+        }
+
+        test_client.send_text("test_message").await;
+
+        let test_error = test_client.recv().await?;
+        let test_error_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_error.to_str().unwrap())?;
+
+        match test_error_response.frame {
+            ServerFrame::Error(test_frame_error) => {
+                assert_eq!(test_frame_error.code, ErrorCode::PlainTextDisabled)
+            }
+            test_frame => panic!("expected error frame -> {:?}", test_frame),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn attachments() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let test_config = Config {
+            attachment_dir: test_dir.to_owned(),
+            attachment_max_bytes: 8,
+            ..Config::default()
+        };
+        let test_options = SessionOptions::init(&test_config, Arc::new(Metrics::default())).await;
+        let test_path = std::env::temp_dir().join(format!("{}.tokens", Uuid::new_v4()));
+
+        std::fs::write(&test_path, "test_subject test_token\n")?;
+
+        let test_auth_config = Config {
+            auth_token_file: Some(test_path.to_owned()),
+            ..Config::default()
+        };
+        let test_auth = Arc::new(Auth::init(&test_auth_config).await?);
+
+        std::fs::remove_file(&test_path)?;
+
+        let test_download = Server::attachments(test_auth, test_options.blobs.to_owned())
+            .recover(Server::unauthorized);
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        println!("there was an error : {:?}", error);
+                    }
+                })
+            },
+        );
+
+        let mut test_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
+
+        for _ in 0..4 {
+            test_client.recv().await?;
+        }
+
+        for (test_frame, test_code) in [
+            (
+                Message::binary(b"test".to_vec()),
+                ErrorCode::InvalidAttachment,
+            ),
+            (
+                Message::text(
+                    r#"{"version":1,"type":"begin_attachment","name":"test.bin","content_type":"application/octet-stream","size":9}"#,
+                ),
+                ErrorCode::AttachmentTooLarge,
+            ),
+        ] {
+            test_client.send(test_frame).await;
+
+            let test_error = test_client.recv().await?;
+            let test_error_response: Envelope<ServerFrame> =
+                serde_json::from_str(test_error.to_str().unwrap())?;
+
+            match test_error_response.frame {
+                ServerFrame::Error(test_frame_error) => {
+                    assert_eq!(test_frame_error.code, test_code)
+                }
+                test_frame => panic!("expected error frame -> {:?}", test_frame),
+            }
+        }
+
+        test_client
+            .send_text(
+                r#"{"version":1,"type":"begin_attachment","name":"test.png","content_type":"image/png","size":8,"contents":"test_caption"}"#,
+            )
+            .await;
+        test_client.send(Message::binary(b"test".to_vec())).await;
+        test_client.send(Message::binary(b"_png".to_vec())).await;
+
+        let test_message = test_client.recv().await?;
+        let test_message_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_message.to_str().unwrap())?;
+
+        let test_attachment = match test_message_response.frame {
+            ServerFrame::Message(test_record) => {
+                assert_eq!(test_record.contents, "test_caption");
+
+                test_record.attachment.expect("attachment")
+            }
+            test_frame => panic!("expected message frame -> {:?}", test_frame),
+        };
+
+        assert_eq!(test_attachment.id, BlobStore::id(b"test_png").await);
+        assert_eq!(test_attachment.name, "test.png");
+        assert_eq!(test_attachment.content_type, "image/png");
+        assert_eq!(test_attachment.size, 8);
+
+        let test_unauthenticated = warp::test::request()
+            .path(&format!("/attachments/{}", &test_attachment.id))
+            .reply(&test_download)
+            .await;
+
+        assert_eq!(test_unauthenticated.status(), 401);
+
+        let test_response = warp::test::request()
+            .path(&format!("/attachments/{}", &test_attachment.id))
+            .header("authorization", "Bearer test_token")
+            .reply(&test_download)
+            .await;
+
+        assert_eq!(test_response.status(), 200);
+        assert_eq!(test_response.headers()["content-type"], "image/png");
+        assert_eq!(test_response.headers()["x-content-type-options"], "nosniff");
+        assert_eq!(test_response.headers()["content-disposition"], "attachment");
+        assert_eq!(test_response.body().to_vec(), b"test_png".to_vec());
+
+        let test_missing = warp::test::request()
+            .path(&format!(
+                "/attachments/{}",
+                BlobStore::id(b"test_missing").await
+            ))
+            .header("authorization", "Bearer test_token")
+            .reply(&test_download)
+            .await;
+
+        assert_eq!(test_missing.status(), 404);
+
+        std::fs::remove_dir_all(&test_dir)?;
+        std::fs::write(&test_dir, b"test_not_a_dir")?;
+
+        test_client
+            .send_text(
+                r#"{"version":1,"type":"begin_attachment","name":"test.txt","content_type":"text/plain","size":4}"#,
+            )
+            .await;
+        test_client.send(Message::binary(b"test".to_vec())).await;
+
+        let test_error = test_client.recv().await?;
+        let test_error_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_error.to_str().unwrap())?;
+
+        match test_error_response.frame {
+            ServerFrame::Error(test_frame_error) => {
+                assert_eq!(test_frame_error.code, ErrorCode::StorageFailed)
+            }
+            test_frame => panic!("expected error frame -> {:?}", test_frame),
+        }
+
+        std::fs::remove_file(&test_dir)?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn abnormal_disconnect() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = test_state.run().await {
+                println!("there was an error : {:?}", error);
+            }
+        });
+
+        let test_options =
+            SessionOptions::init(&Config::default(), Arc::new(Metrics::default())).await;
+        let test_users_channel = test_state_sender.to_owned();
+        let test_state_channel = warp::any().map(move || test_state_sender.to_owned());
+        let test_filter = warp::path("ws").and(ws()).and(test_state_channel).map(
+            move |ws: warp::ws::Ws, test_state_channel| {
+                let test_options = test_options.to_owned();
+
+                ws.on_upgrade(move |test_connection| async move {
+                    if let Err(error) = Server::handle(
+                        test_connection,
+                        test_state_channel,
+                        test_options,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        println!("there was an error : {:?}", error);
+                    }
+                })
+            },
+        );
+
+        let mut test_client = warp::test::ws()
+            .path("/ws")
+            .handshake(test_filter.clone())
+            .await?;
+
+        for _ in 0..4 {
+            test_client.recv().await?;
+        }
+
+        let mut test_dropped_client = warp::test::ws().path("/ws").handshake(test_filter).await?;
+
+        let test_dropped_session = test_dropped_client.recv().await?;
+        let test_dropped_session_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_dropped_session.to_str().unwrap())?;
+        let test_dropped_uuid = match test_dropped_session_response.frame {
+            ServerFrame::Session { uuid, .. } => uuid,
+            test_frame => panic!("expected session frame -> {:?}", test_frame),
+        };
+
+        for _ in 0..3 {
+            test_dropped_client.recv().await?;
+        }
+
+        for _ in 0..2 {
+            test_client.recv().await?;
+        }
+
+        drop(test_dropped_client);
+
+        let test_left = test_client.recv().await?;
+        let test_left_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_left.to_str().unwrap())?;
+
+        match test_left_response.frame {
+            ServerFrame::UserLeft(test_event) => {
+                assert_eq!(test_event.room, DEFAULT_ROOM);
+                assert_eq!(test_event.uuid, test_dropped_uuid);
+                assert!(test_event.nickname.starts_with("guest-"));
+            }
+            test_frame => panic!("expected user left frame -> {:?}", test_frame),
+        }
+
+        let test_count = test_client.recv().await?;
+        let test_count_response: Envelope<ServerFrame> =
+            serde_json::from_str(test_count.to_str().unwrap())?;
+
+        assert_eq!(
+            test_count_response.frame,
+            ServerFrame::ConnectedUsers {
+                room: DEFAULT_ROOM.to_owned(),
+                count: 1,
+            },
+        );
+        assert_eq!(
+            crate::channels::get_users(&test_users_channel, DEFAULT_ROOM)
+                .await?
+                .len(),
+            1,
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,952 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use warp::ws::Message;
+
+use crate::{error, info};
+
+use crate::blobs::BlobStore;
+use crate::channels::{
+    add_message, broadcast, delete_message, direct_message, edit_message, get_conversation,
+    get_history, get_missed, get_presence, get_thread, get_users, join_room, leave_room, react,
+    remove_user, search, set_nickname, suspend_session, typing,
+};
+use crate::channels::{Audience, RelayError, StateSender, WebSocketConnection, WebSocketSender};
+use crate::config::{Config, SlowConsumer};
+use crate::json::{ClientFrame, ErrorCode, FrameError, ServerFrame};
+use crate::metrics::Metrics;
+use crate::state::{guest_name, SuspendedSession, DEFAULT_ROOM};
+use crate::store::{timestamp, Attachment, MessageRecord};
+
+/// Per connection settings, copied out of the config for each websocket upgrade.
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+    pub sink_capacity: usize,
+    pub slow_consumer: SlowConsumer,
+    /// Messages replayed on entering a room, and the most a fetched page holds
+    pub history_page_size: usize,
+    pub plain_text: bool,
+    /// Whether closed sessions are held on to for resumption
+    pub resumable: bool,
+    pub heartbeat_interval: Duration,
+    pub heartbeat_timeout: Duration,
+    pub attachment_max_bytes: usize,
+    pub blobs: Arc<BlobStore>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl SessionOptions {
+    pub async fn init(config: &Config, metrics: Arc<Metrics>) -> SessionOptions {
+        SessionOptions {
+            sink_capacity: config.sink_capacity,
+            slow_consumer: config.slow_consumer,
+            history_page_size: config.history_page_size,
+            plain_text: config.plain_text,
+            resumable: !config.session_grace_period.is_zero(),
+            heartbeat_interval: config.heartbeat_interval,
+            heartbeat_timeout: config.heartbeat_timeout,
+            attachment_max_bytes: config.attachment_max_bytes,
+            blobs: Arc::new(BlobStore::init(&config.attachment_dir).await),
+            metrics,
+        }
+    }
+}
+
+/// An attachment announced with `begin_attachment`, filled in by the binary frames that follow.
+struct PendingAttachment {
+    name: String,
+    content_type: String,
+    size: usize,
+    contents: String,
+    bytes: Vec<u8>,
+}
+
+/// Everything a single websocket connection knows about itself.
+///
+/// Text frames are parsed into a `ClientFrame` and dispatched to one handler per command.
+/// Problems with what the client sent are answered with an error frame, while the `Err` side of
+/// each handler is reserved for failures talking to state or the sink.
+pub struct Session {
+    uuid: String,
+    token: String,
+    room: String,
+    nickname: Option<String>,
+    state: StateSender,
+    sink_sender: WebSocketSender,
+    options: SessionOptions,
+    /// The last sequence a resumed session had seen, until the messages missed since have been
+    /// sent
+    resumed_after: Option<u64>,
+    last_pong: Instant,
+    pending_attachment: Option<PendingAttachment>,
+    closed: bool,
+}
+
+impl Session {
+    pub async fn init(
+        uuid: String,
+        token: String,
+        state: StateSender,
+        sink_sender: WebSocketSender,
+        options: SessionOptions,
+        nickname: Option<String>,
+        suspended: Option<SuspendedSession>,
+    ) -> Session {
+        let (room, resumed_after) = match suspended {
+            Some(suspended) => (suspended.room, Some(suspended.sequence)),
+            None => (DEFAULT_ROOM.to_owned(), None),
+        };
+
+        Session {
+            uuid,
+            token,
+            room,
+            nickname,
+            state,
+            sink_sender,
+            options,
+            resumed_after,
+            last_pong: Instant::now(),
+            pending_attachment: None,
+            closed: false,
+        }
+    }
+
+    pub async fn start(&mut self) -> Result<(), RelayError> {
+        join_room(&self.state, &self.uuid, &self.room).await?;
+
+        let session = ServerFrame::Session {
+            uuid: self.uuid.to_owned(),
+            token: self.token.to_owned(),
+            resumed: self.resumed_after.is_some(),
+        };
+
+        self.send(&session).await?;
+
+        match self.resumed_after.take() {
+            Some(sequence) => self.missed_messages(sequence).await,
+            None => self.room_messages().await,
+        }
+    }
+
+    pub async fn text(&mut self, text: &str) -> Result<(), RelayError> {
+        let frame = match text.trim_start().starts_with('{') {
+            true => ClientFrame::parse(text).await,
+            false if self.options.plain_text => Ok(ClientFrame::SendMessage {
+                contents: text.to_owned(),
+                reply_to: None,
+            }),
+            false => Err(FrameError::build(
+                ErrorCode::PlainTextDisabled,
+                "plain text messages are disabled, send a json frame instead",
+            )
+            .await),
+        };
+
+        match frame {
+            Ok(frame) => self.dispatch(frame).await,
+            Err(frame_error) => {
+                info!("rejected frame -> {:?}", &frame_error);
+
+                self.send_error(frame_error).await
+            }
+        }
+    }
+
+    pub async fn dispatch(&mut self, frame: ClientFrame) -> Result<(), RelayError> {
+        match frame {
+            ClientFrame::SendMessage { contents, reply_to } => {
+                self.send_message(contents, reply_to).await
+            }
+            ClientFrame::SetNickname { nickname } => self.set_nickname(nickname).await,
+            ClientFrame::JoinRoom { room } => self.join_room(room).await,
+            ClientFrame::LeaveRoom => self.join_room(DEFAULT_ROOM.to_owned()).await,
+            ClientFrame::Typing => self.typing().await,
+            ClientFrame::FetchHistory { before, limit } => self.fetch_history(before, limit).await,
+            ClientFrame::DirectMessage { to, contents } => self.direct_message(to, contents).await,
+            ClientFrame::FetchConversation { with } => self.fetch_conversation(with).await,
+            ClientFrame::FetchThread { id } => self.fetch_thread(id).await,
+            ClientFrame::EditMessage { id, contents } => self.edit_message(id, contents).await,
+            ClientFrame::DeleteMessage { id } => self.delete_message(id).await,
+            ClientFrame::Search {
+                query,
+                offset,
+                limit,
+            } => self.search(query, offset, limit).await,
+            ClientFrame::AddReaction { id, emoji } => self.react(id, emoji, true).await,
+            ClientFrame::RemoveReaction { id, emoji } => self.react(id, emoji, false).await,
+            ClientFrame::BeginAttachment {
+                name,
+                content_type,
+                size,
+                contents,
+            } => {
+                self.begin_attachment(name, content_type, size, contents)
+                    .await
+            }
+        }
+    }
+
+    /// Add a chunk to the pending attachment, publishing it once every byte has arrived.
+    pub async fn binary(&mut self, chunk: &[u8]) -> Result<(), RelayError> {
+        let mut pending = match self.pending_attachment.take() {
+            Some(pending) => pending,
+            None => {
+                let message = "binary frames must follow a begin_attachment frame";
+
+                return self
+                    .send_error(FrameError::build(ErrorCode::InvalidAttachment, message).await)
+                    .await;
+            }
+        };
+
+        if pending.bytes.len() + chunk.len() > pending.size {
+            let message = format!(
+                "attachment {:?} is larger than the {} bytes announced",
+                pending.name, pending.size,
+            );
+
+            return self
+                .send_error(FrameError::build(ErrorCode::InvalidAttachment, message).await)
+                .await;
+        }
+
+        pending.bytes.extend_from_slice(chunk);
+
+        if pending.bytes.len() < pending.size {
+            self.pending_attachment = Some(pending);
+
+            return Ok(());
+        }
+
+        let id = self
+            .options
+            .blobs
+            .put(&pending.bytes, &pending.content_type)
+            .await
+            .map_err(|error| RelayError::Storage(error.to_string()))?;
+        let attachment = Attachment {
+            id,
+            name: pending.name,
+            content_type: pending.content_type,
+            size: pending.size,
+        };
+
+        self.publish(pending.contents, Some(attachment), None).await
+    }
+
+    pub async fn ping(&self) -> Result<(), RelayError> {
+        self.sink_sender
+            .send(WebSocketConnection::SendMessage(Arc::new(Message::ping(
+                Vec::new(),
+            ))))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn pong(&self, payload: &[u8]) -> Result<(), RelayError> {
+        self.sink_sender
+            .send(WebSocketConnection::SendMessage(Arc::new(Message::pong(
+                payload,
+            ))))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn ponged(&mut self) {
+        self.last_pong = Instant::now();
+    }
+
+    /// Whether the client has gone without answering a ping for longer than the timeout.
+    pub async fn idle(&self) -> bool {
+        self.last_pong.elapsed() > self.options.heartbeat_timeout
+    }
+
+    /// Deal with a frame that could not be handled. Problems that only affect that frame are
+    /// logged or answered with an error frame, while anything that leaves the connection unable
+    /// to carry on is returned so that it is closed.
+    pub async fn recover(&mut self, error: RelayError) -> Result<(), RelayError> {
+        match error {
+            RelayError::UserNotFound(user) => {
+                let message = format!("user {} is not connected", user);
+
+                self.send_error(FrameError::build(ErrorCode::UserNotFound, message).await)
+                    .await
+            }
+            RelayError::MessageNotFound(id) => {
+                let message = format!("message {} does not exist or was deleted", id);
+
+                self.send_error(FrameError::build(ErrorCode::MessageNotFound, message).await)
+                    .await
+            }
+            RelayError::NotAuthor(id) => {
+                let message = format!("message {} was sent by someone else", id);
+
+                self.send_error(FrameError::build(ErrorCode::NotAuthor, message).await)
+                    .await
+            }
+            RelayError::Timeout(request) => {
+                error!("state timed out -> {}", request);
+
+                let message = "the server is busy, try again";
+
+                self.send_error(FrameError::build(ErrorCode::Timeout, message).await)
+                    .await
+            }
+            RelayError::Storage(_) => {
+                error!("dropping frame -> {}", error);
+
+                let message = "the frame could not be stored, try again";
+
+                self.send_error(FrameError::build(ErrorCode::StorageFailed, message).await)
+                    .await
+            }
+            RelayError::Serialization(_) => {
+                error!("dropping frame -> {}", error);
+
+                Ok(())
+            }
+            RelayError::StateChannelClosed
+            | RelayError::UnexpectedResponse(_)
+            | RelayError::WebSocketSend(_) => Err(error),
+        }
+    }
+
+    /// Suspend or remove this user and tell the room. Runs however the connection ended, so
+    /// only the first call does anything. State is left, suspended for resumption when enabled,
+    /// before closing the socket so that a quick reconnect never races the suspension.
+    pub async fn close(&mut self) -> Result<(), RelayError> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.closed = true;
+
+        let updated = match self.options.resumable {
+            true => {
+                let suspended = SuspendedSession {
+                    uuid: self.uuid.to_owned(),
+                    room: self.room.to_owned(),
+                    suspended_at: timestamp().await,
+                    sequence: 0,
+                };
+
+                suspend_session(&self.state, &self.token, suspended, &self.sink_sender).await
+            }
+            false => remove_user(&self.state, &self.uuid, &self.sink_sender).await,
+        };
+
+        if self
+            .sink_sender
+            .send(WebSocketConnection::Close)
+            .await
+            .is_err()
+        {
+            info!("sink already closed -> {:?}", &self.uuid);
+        }
+
+        updated?;
+
+        self.connected_users(vec![self.room.to_owned()]).await
+    }
+
+    async fn send_message(
+        &mut self,
+        contents: String,
+        reply_to: Option<String>,
+    ) -> Result<(), RelayError> {
+        self.publish(contents, None, reply_to).await
+    }
+
+    async fn begin_attachment(
+        &mut self,
+        name: String,
+        content_type: String,
+        size: usize,
+        contents: String,
+    ) -> Result<(), RelayError> {
+        if size > self.options.attachment_max_bytes {
+            let message = format!(
+                "attachment of {} bytes is over the {} byte limit",
+                size, self.options.attachment_max_bytes,
+            );
+
+            return self
+                .send_error(FrameError::build(ErrorCode::AttachmentTooLarge, message).await)
+                .await;
+        }
+
+        if size == 0
+            || name.trim().is_empty()
+            || content_type.trim().is_empty()
+            || warp::http::HeaderValue::from_str(content_type.trim()).is_err()
+        {
+            let message = "attachment needs a name, a valid content type and a size above 0";
+
+            return self
+                .send_error(FrameError::build(ErrorCode::InvalidAttachment, message).await)
+                .await;
+        }
+
+        if let Some(pending) = &self.pending_attachment {
+            info!("discarding unfinished attachment -> {:?}", &pending.name);
+        }
+
+        self.pending_attachment = Some(PendingAttachment {
+            name: name.trim().to_owned(),
+            content_type: content_type.trim().to_owned(),
+            size,
+            contents,
+            bytes: Vec::with_capacity(size),
+        });
+
+        Ok(())
+    }
+
+    /// Store a message in the current room and send it to everyone there, followed by the new
+    /// reply count if it was a reply.
+    async fn publish(
+        &mut self,
+        contents: String,
+        attachment: Option<Attachment>,
+        reply_to: Option<String>,
+    ) -> Result<(), RelayError> {
+        let record = MessageRecord {
+            room: self.room.to_owned(),
+            uuid: self.uuid.to_owned(),
+            display_name: self.display_name().await,
+            contents,
+            attachment,
+            reply_to,
+            ..MessageRecord::default()
+        };
+
+        let (record, replies) = match add_message(&self.state, &record).await? {
+            Some(accepted) => accepted,
+            None => return Ok(()),
+        };
+        let thread = record.reply_to.to_owned();
+
+        self.broadcast(&ServerFrame::Message(record), true).await?;
+
+        match (thread, replies) {
+            (Some(id), Some(replies)) => {
+                let frame = ServerFrame::ReplyCount {
+                    room: self.room.to_owned(),
+                    id,
+                    replies,
+                };
+
+                self.broadcast(&frame, true).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn set_nickname(&mut self, nickname: String) -> Result<(), RelayError> {
+        let nickname = nickname.trim().to_owned();
+
+        if !valid_nickname(&nickname).await {
+            let message = format!(
+                "nickname {:?} must be 1 to 32 characters without control characters",
+                nickname,
+            );
+
+            return self
+                .send_error(FrameError::build(ErrorCode::InvalidNickname, message).await)
+                .await;
+        }
+
+        if self.nickname.as_ref() == Some(&nickname) {
+            info!("nickname unchanged -> {:?}", nickname);
+
+            return Ok(());
+        }
+
+        if !set_nickname(&self.state, &self.uuid, &nickname).await? {
+            let message = format!("nickname {:?} is already taken", nickname);
+
+            return self
+                .send_error(FrameError::build(ErrorCode::NicknameTaken, message).await)
+                .await;
+        }
+
+        let previous = self.display_name().await;
+
+        self.nickname = Some(nickname.to_owned());
+
+        let frame = ServerFrame::Nickname {
+            uuid: self.uuid.to_owned(),
+            nickname,
+            previous,
+        };
+        let message = Arc::new(frame.to_message().await?);
+        let rooms = broadcast(&self.state, Audience::Everyone, message).await?;
+
+        self.connected_users(rooms).await
+    }
+
+    /// The nickname if one has been set, otherwise a guest name from the start of the uuid.
+    pub async fn display_name(&self) -> String {
+        match &self.nickname {
+            Some(nickname) => nickname.to_owned(),
+            None => guest_name(&self.uuid).await,
+        }
+    }
+
+    async fn join_room(&mut self, room: String) -> Result<(), RelayError> {
+        if !valid_room(&room).await {
+            let message = format!(
+                "room {:?} must be 1 to 64 characters without whitespace",
+                room,
+            );
+
+            return self
+                .send_error(FrameError::build(ErrorCode::InvalidRoom, message).await)
+                .await;
+        }
+
+        if self.room == room {
+            info!("already in room -> {:?}", room);
+
+            return Ok(());
+        }
+
+        leave_room(&self.state, &self.uuid, &self.room).await?;
+
+        self.connected_users(vec![self.room.to_owned()]).await?;
+
+        join_room(&self.state, &self.uuid, &room).await?;
+
+        self.room = room;
+
+        self.room_messages().await
+    }
+
+    async fn typing(&mut self) -> Result<(), RelayError> {
+        typing(&self.state, &self.uuid, &self.room).await
+    }
+
+    async fn fetch_thread(&mut self, id: String) -> Result<(), RelayError> {
+        let messages = get_thread(&self.state, &self.uuid, &id).await?;
+        let (room, id) = match messages.first() {
+            Some(first) => (
+                first.room.to_owned(),
+                first.reply_to.to_owned().unwrap_or(first.id.to_owned()),
+            ),
+            None => (self.room.to_owned(), id),
+        };
+        let frame = ServerFrame::Thread { room, id, messages };
+
+        self.send(&frame).await
+    }
+
+    /// Send a page of the room's history, of at most `history_page_size` messages.
+    async fn fetch_history(
+        &mut self,
+        before: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<(), RelayError> {
+        let page_size = self.options.history_page_size;
+        let limit = limit.unwrap_or(page_size).clamp(1, page_size);
+        let (messages, has_more) =
+            get_history(&self.state, &self.room, before.as_deref(), limit).await?;
+        let frame = ServerFrame::History {
+            room: self.room.to_owned(),
+            messages,
+            has_more,
+        };
+
+        self.send(&frame).await
+    }
+
+    /// Send a page of the messages in the room matching a query, of at most `history_page_size`.
+    async fn search(
+        &mut self,
+        query: String,
+        offset: Option<usize>,
+        limit: Option<usize>,
+    ) -> Result<(), RelayError> {
+        let page_size = self.options.history_page_size;
+        let limit = limit.unwrap_or(page_size).clamp(1, page_size);
+        let page = search(
+            &self.state,
+            &self.uuid,
+            &self.room,
+            &query,
+            offset.unwrap_or_default(),
+            limit,
+        )
+        .await?;
+
+        match page {
+            Some(page) => self.send(&ServerFrame::SearchResults(page)).await,
+            None => {
+                let message = format!("{} is not in room {}", self.uuid, self.room);
+
+                self.send_error(FrameError::build(ErrorCode::InvalidRoom, message).await)
+                    .await
+            }
+        }
+    }
+
+    /// Send a message to one user, named by uuid or nickname. State delivers it to them and
+    /// echoes it back here.
+    async fn direct_message(&mut self, to: String, contents: String) -> Result<(), RelayError> {
+        let record = MessageRecord {
+            room: String::new(),
+            uuid: self.uuid.to_owned(),
+            display_name: self.display_name().await,
+            contents,
+            recipient: Some(to.trim().to_owned()),
+            ..MessageRecord::default()
+        };
+
+        let rooms = direct_message(&self.state, &record).await?;
+
+        self.connected_users(rooms).await
+    }
+
+    async fn fetch_conversation(&mut self, with: String) -> Result<(), RelayError> {
+        let messages = get_conversation(&self.state, &self.uuid, &with).await?;
+        let frame = ServerFrame::Conversation { with, messages };
+
+        self.send(&frame).await
+    }
+
+    async fn edit_message(&mut self, id: String, contents: String) -> Result<(), RelayError> {
+        let record = edit_message(&self.state, &self.uuid, &id, &contents).await?;
+
+        self.amend(record.room.to_owned(), &ServerFrame::MessageEdited(record))
+            .await
+    }
+
+    async fn delete_message(&mut self, id: String) -> Result<(), RelayError> {
+        let record = delete_message(&self.state, &self.uuid, &id).await?;
+        let frame = ServerFrame::MessageDeleted {
+            room: record.room.to_owned(),
+            id: record.id,
+        };
+
+        self.amend(record.room, &frame).await
+    }
+
+    async fn react(&mut self, id: String, emoji: String, add: bool) -> Result<(), RelayError> {
+        let emoji = emoji.trim().to_owned();
+
+        if !valid_reaction(&emoji).await {
+            let message = format!(
+                "reaction {:?} must be 1 to 16 characters without whitespace",
+                emoji,
+            );
+
+            return self
+                .send_error(FrameError::build(ErrorCode::InvalidReaction, message).await)
+                .await;
+        }
+
+        let event = match react(&self.state, &self.uuid, &id, &emoji, add).await? {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        let room = event.room.to_owned();
+        let frame = match add {
+            true => ServerFrame::ReactionAdded(event),
+            false => ServerFrame::ReactionRemoved(event),
+        };
+
+        self.amend(room, &frame).await
+    }
+
+    /// Tell everyone in the room a message was sent in that it has changed, which may not be
+    /// the room this user is in now.
+    async fn amend(&self, room: String, frame: &ServerFrame) -> Result<(), RelayError> {
+        let message = Arc::new(frame.to_message().await?);
+        let rooms = broadcast(&self.state, Audience::Room(room), message).await?;
+
+        self.connected_users(rooms).await
+    }
+
+    async fn send(&self, frame: &ServerFrame) -> Result<(), RelayError> {
+        let message = frame.to_message().await?;
+
+        self.sink_sender
+            .send(WebSocketConnection::SendMessage(Arc::new(message)))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn send_error(&self, frame_error: FrameError) -> Result<(), RelayError> {
+        self.send(&ServerFrame::Error(frame_error)).await
+    }
+
+    /// Send a frame to everyone in the current room, optionally including this session. The
+    /// frame is serialized once and queued for every member by state.
+    async fn broadcast(&self, frame: &ServerFrame, include_self: bool) -> Result<(), RelayError> {
+        let message = Arc::new(frame.to_message().await?);
+        let audience = match include_self {
+            true => Audience::Room(self.room.to_owned()),
+            false => Audience::RoomExcept((self.room.to_owned(), self.uuid.to_owned())),
+        };
+        let rooms = broadcast(&self.state, audience, message).await?;
+
+        self.connected_users(rooms).await
+    }
+
+    /// Enter the room, replaying its latest `history_page_size` messages. Older ones are left for
+    /// `fetch_history`, which the `room` frame says whether there are.
+    async fn room_messages(&self) -> Result<(), RelayError> {
+        let (older_messages, has_more) = get_history(
+            &self.state,
+            &self.room,
+            None,
+            self.options.history_page_size,
+        )
+        .await?;
+        let current_room = ServerFrame::Room {
+            room: self.room.to_owned(),
+            has_more,
+        };
+
+        self.send(&current_room).await?;
+        self.presence_snapshot().await?;
+
+        self.connected_users(vec![self.room.to_owned()]).await?;
+
+        if older_messages.is_empty() {
+            info!("no older messages to send...");
+        } else {
+            info!("sending older messages ...");
+
+            for message in older_messages {
+                self.send(&ServerFrame::Message(message)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like `room_messages`, but only replaying what arrived after the session was suspended.
+    /// If more was missed than a page holds, the latest page is replayed and the `room` frame
+    /// reports the gap through `has_more`, so that the rest can be fetched with `fetch_history`.
+    async fn missed_messages(&self, sequence: u64) -> Result<(), RelayError> {
+        let (missed, has_more) = get_missed(
+            &self.state,
+            &self.room,
+            sequence,
+            self.options.history_page_size,
+        )
+        .await?;
+        let current_room = ServerFrame::Room {
+            room: self.room.to_owned(),
+            has_more,
+        };
+
+        self.send(&current_room).await?;
+        self.presence_snapshot().await?;
+
+        self.connected_users(vec![self.room.to_owned()]).await?;
+
+        for message in missed {
+            self.send(&ServerFrame::Message(message)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn presence_snapshot(&self) -> Result<(), RelayError> {
+        let snapshot = ServerFrame::PresenceSnapshot {
+            room: self.room.to_owned(),
+            users: get_presence(&self.state, &self.room).await?,
+        };
+
+        self.send(&snapshot).await
+    }
+
+    /// Send each room its count of connected users. Users found to have gone away while the
+    /// counts are delivered are reaped by state, and the rooms they were in are counted again.
+    async fn connected_users(&self, mut rooms: Vec<String>) -> Result<(), RelayError> {
+        while let Some(room) = rooms.pop() {
+            let count = get_users(&self.state, &room).await?.len();
+            let connected_users_count = ServerFrame::ConnectedUsers {
+                room: room.to_owned(),
+                count,
+            };
+            let message = Arc::new(connected_users_count.to_message().await?);
+
+            for reaped in broadcast(&self.state, Audience::Room(room), message).await? {
+                if !rooms.contains(&reaped) {
+                    rooms.push(reaped);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn valid_room(room: &str) -> bool {
+    !room.is_empty()
+        && room.chars().count() <= 64
+        && !room
+            .chars()
+            .any(|character| character.is_whitespace() || character.is_control())
+}
+
+async fn valid_nickname(nickname: &str) -> bool {
+    !nickname.is_empty()
+        && nickname.chars().count() <= 32
+        && !nickname.chars().any(|character| character.is_control())
+}
+
+async fn valid_reaction(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.chars().count() <= 16
+        && !emoji
+            .chars()
+            .any(|character| character.is_control() || character.is_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn session_options() -> Result<(), Box<dyn std::error::Error>> {
+        let test_config = Config {
+            sink_capacity: 8,
+            history_page_size: 20,
+            plain_text: false,
+            ..Config::default()
+        };
+
+        let test_options = SessionOptions::init(&test_config, Arc::new(Metrics::default())).await;
+
+        assert_eq!(test_options.sink_capacity, 8);
+        assert_eq!(test_options.history_page_size, 20);
+        assert!(!test_options.plain_text);
+        assert!(test_options.resumable);
+        assert_eq!(test_options.heartbeat_interval, Duration::from_secs(30));
+        assert_eq!(test_options.heartbeat_timeout, Duration::from_secs(90));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn display_name() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, _test_state_receiver) = tokio::sync::mpsc::channel(1);
+        let (test_sink_sender, _test_sink_receiver) =
+            crate::channels::outbound(1, SlowConsumer::DropOldest).await;
+        let test_options =
+            SessionOptions::init(&Config::default(), Arc::new(Metrics::default())).await;
+
+        let mut test_session = Session::init(
+            String::from("0123456789abcdef"),
+            String::from("test_token"),
+            StateSender::init(test_state_sender, Duration::from_secs(5)).await,
+            test_sink_sender,
+            test_options,
+            None,
+            None,
+        )
+        .await;
+
+        assert_eq!(test_session.display_name().await, "guest-01234567");
+        assert_eq!(guest_name("sübject-ünïcode").await, "guest-sübject-");
+
+        test_session.nickname = Some(String::from("test_nickname"));
+
+        assert_eq!(test_session.display_name().await, "test_nickname");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn heartbeat() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, _test_state_receiver) = tokio::sync::mpsc::channel(1);
+        let (test_sink_sender, mut test_sink_receiver) =
+            crate::channels::outbound(4, SlowConsumer::DropOldest).await;
+        let test_config = Config {
+            heartbeat_interval: Duration::from_millis(10),
+            heartbeat_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let test_options = SessionOptions::init(&test_config, Arc::new(Metrics::default())).await;
+
+        let mut test_session = Session::init(
+            String::from("test_uuid"),
+            String::from("test_token"),
+            StateSender::init(test_state_sender, Duration::from_secs(5)).await,
+            test_sink_sender,
+            test_options,
+            None,
+            None,
+        )
+        .await;
+
+        test_session.ping().await?;
+        test_session.pong(b"test_payload").await?;
+
+        match test_sink_receiver.recv().await {
+            Some(WebSocketConnection::SendMessage(test_message)) => assert!(test_message.is_ping()),
+            _ => panic!("expected ping"),
+        }
+
+        match test_sink_receiver.recv().await {
+            Some(WebSocketConnection::SendMessage(test_message)) => {
+                assert!(test_message.is_pong());
+                assert_eq!(test_message.as_bytes(), b"test_payload");
+            }
+            _ => panic!("expected pong"),
+        }
+
+        assert!(!test_session.idle().await);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        assert!(test_session.idle().await);
+
+        test_session.ponged().await;
+
+        assert!(!test_session.idle().await);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn valid_room() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(super::valid_room("test_room").await);
+        assert!(!super::valid_room("").await);
+        assert!(!super::valid_room("test room").await);
+        assert!(!super::valid_room(&"r".repeat(65)).await);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn valid_nickname() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(super::valid_nickname("test nickname").await);
+        assert!(!super::valid_nickname("").await);
+        assert!(!super::valid_nickname("test\nnickname").await);
+        assert!(!super::valid_nickname(&"n".repeat(33)).await);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn valid_reaction() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(super::valid_reaction("👍").await);
+        assert!(super::valid_reaction("👩‍👩‍👧").await);
+        assert!(super::valid_reaction(":thumbsup:").await);
+        assert!(!super::valid_reaction("").await);
+        assert!(!super::valid_reaction("test reaction").await);
+        assert!(!super::valid_reaction(&"r".repeat(17)).await);
+
+        Ok(())
+    }
+}
@@ -1,291 +1,3882 @@
-use std::collections::HashMap;
-use warp::filters::ws::Message;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use warp::ws::Message;
 
 use crate::{error, info};
 
-use crate::channels::{ConnectedUsers, StateReceiver, WebSocketSender};
+use crate::channels::{Audience, ConnectedUsers, RelayError, StateReceiver};
+use crate::channels::{Delivery, WebSocketConnection, WebSocketSender};
 use crate::channels::{StateRequest, StateResponse};
+use crate::config::Config;
+use crate::json::{Presence, PresenceEvent, ReactionEvent, ServerFrame};
+use crate::metrics::{Eviction, Metrics};
+use crate::search::{snippet, SearchIndex, SearchPage, SearchResult};
+use crate::store::{timestamp, MessageRecord, MessageStore, Reaction, SequenceMark};
+
+use uuid::Uuid;
+
+/// The room every session is placed in when it connects, and returned to when it leaves a room.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Lines in the message log no longer describing a message held, whether superseded by a newer
+/// version or evicted, past which the log is compacted while running.
+const COMPACTION_LINES: usize = 1000;
+
+/// Limits on how much history each room keeps, with the oldest messages evicted first.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    pub max_messages: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub async fn init(config: &Config) -> Retention {
+        Retention {
+            max_messages: config.retention_max_messages,
+            max_bytes: config.retention_max_bytes,
+            max_age: config.retention_max_age,
+        }
+    }
+}
+
+/// What is kept of a session after its socket closes, so that a reconnect presenting the same
+/// token within the grace period can carry on as the same user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuspendedSession {
+    pub uuid: String,
+    pub room: String,
+    /// Milliseconds since the unix epoch that the session was suspended at
+    pub suspended_at: u64,
+    /// Sequence of the latest message in the room when the session was suspended
+    pub sequence: u64,
+}
+
+pub struct Room {
+    messages: VecDeque<MessageRecord>,
+    bytes: usize,
+    /// Sequence of the latest message, which carries on past evictions
+    sequence: u64,
+    members: HashSet<String>,
+    /// Members typing, and when each last said so
+    typing: HashMap<String, Instant>,
+    /// Words in the messages held, kept in step as they are added, changed and evicted
+    index: SearchIndex,
+}
+
+impl Room {
+    pub async fn init(history_capacity: usize) -> Room {
+        let messages = VecDeque::with_capacity(history_capacity);
+        let bytes = 0;
+        let sequence = 0;
+        let members = HashSet::with_capacity(10);
+        let typing = HashMap::with_capacity(10);
+        let index = SearchIndex::init().await;
+
+        Room {
+            messages,
+            bytes,
+            sequence,
+            members,
+            typing,
+            index,
+        }
+    }
+
+    /// Stamp a newly accepted message with an id, the next sequence and the time.
+    async fn accept(&self, message: &mut MessageRecord) {
+        message.id = Uuid::new_v4().to_string();
+        message.sequence = self.sequence + 1;
+        message.timestamp = timestamp().await;
+    }
+
+    /// Swap a message for a newer version of itself, handing the record back if the message is
+    /// not held.
+    async fn replace(&mut self, record: MessageRecord) -> Option<MessageRecord> {
+        match self
+            .messages
+            .iter_mut()
+            .find(|message| message.id == record.id)
+        {
+            Some(message) => {
+                self.bytes = self.bytes - message.contents.len() + record.contents.len();
+                self.index.insert(&record).await;
+                *message = record;
+
+                None
+            }
+            None => Some(record),
+        }
+    }
+
+    /// Add a message to history. Messages stored before ids and sequences were assigned are
+    /// given them as they load.
+    async fn push(&mut self, mut record: MessageRecord) {
+        if record.id.is_empty() {
+            record.id = Uuid::new_v4().to_string();
+        }
+
+        if record.sequence == 0 {
+            record.sequence = self.sequence + 1;
+        }
+
+        self.sequence = self.sequence.max(record.sequence);
+        self.bytes += record.contents.len();
+        self.index.insert(&record).await;
+        self.messages.push_back(record);
+    }
+
+    async fn evict(&mut self, retention: &Retention, metrics: &Metrics) -> usize {
+        let mut evicted = 0;
+
+        if let Some(max_age) = retention.max_age {
+            let oldest = timestamp().await.saturating_sub(max_age.as_millis() as u64);
+
+            while self
+                .messages
+                .front()
+                .is_some_and(|record| record.timestamp < oldest)
+            {
+                self.pop_front().await;
+                metrics.evicted(Eviction::Age).await;
+                evicted += 1;
+            }
+        }
+
+        if let Some(max_messages) = retention.max_messages {
+            while self.messages.len() > max_messages {
+                self.pop_front().await;
+                metrics.evicted(Eviction::Count).await;
+                evicted += 1;
+            }
+        }
+
+        if let Some(max_bytes) = retention.max_bytes {
+            while self.bytes > max_bytes {
+                self.pop_front().await;
+                metrics.evicted(Eviction::Bytes).await;
+                evicted += 1;
+            }
+        }
+
+        evicted
+    }
+
+    async fn pop_front(&mut self) {
+        if let Some(record) = self.messages.pop_front() {
+            self.bytes -= record.contents.len();
+            self.index.remove(&record.id).await;
+        }
+    }
+}
 
 pub struct State {
-    messages: Vec<Message>,
+    history_capacity: usize,
+    retention: Retention,
+    rooms: HashMap<String, Room>,
+    /// Direct messages between each pair of users, keyed by their uuids in order
+    conversations: HashMap<(String, String), Room>,
     users: ConnectedUsers,
+    /// Claimed nicknames, lowercased so that uniqueness ignores case, and the session holding each
+    nicknames: HashMap<String, String>,
+    /// Claimed nicknames as they were set, by the session holding each
+    names: HashMap<String, String>,
+    /// Sessions waiting to be resumed, by resume token
+    sessions: HashMap<String, SuspendedSession>,
+    /// Resume tokens issued to connections still open, and the uuid each belongs to
+    live_sessions: HashMap<String, String>,
+    session_grace_period: Duration,
+    typing_timeout: Duration,
     receiver: StateReceiver,
+    store: Box<dyn MessageStore>,
+    /// Lines appended to the store since it was last compacted, or loaded from it
+    logged: usize,
+    /// What `logged` has to reach before the store is next checked for lines to compact
+    compact_at: usize,
+    metrics: Arc<Metrics>,
 }
 
 impl State {
-    pub async fn init(receiver: StateReceiver) -> State {
-        let messages = Vec::with_capacity(100);
+    pub async fn init(
+        receiver: StateReceiver,
+        config: &Config,
+        mut store: Box<dyn MessageStore>,
+        metrics: Arc<Metrics>,
+    ) -> Result<State, Box<dyn std::error::Error>> {
+        let history_capacity = config.history_capacity;
+        let retention = Retention::init(config).await;
+        let mut rooms = HashMap::with_capacity(10);
+        let mut conversations = HashMap::with_capacity(10);
         let users = HashMap::with_capacity(10);
+        let nicknames = HashMap::with_capacity(10);
+        let names = HashMap::with_capacity(10);
+        let sessions = HashMap::with_capacity(10);
+        let live_sessions = HashMap::with_capacity(10);
+        let session_grace_period = config.session_grace_period;
+        let typing_timeout = config.typing_timeout;
+
+        rooms.insert(DEFAULT_ROOM.to_owned(), Room::init(history_capacity).await);
+
+        for mark in store.sequences()? {
+            let room = match mark.conversation {
+                Some(key) => {
+                    if !conversations.contains_key(&key) {
+                        conversations.insert(key.to_owned(), Room::init(history_capacity).await);
+                    }
 
-        State {
-            messages,
+                    conversations.get_mut(&key)
+                }
+                None => {
+                    if !rooms.contains_key(&mark.room) {
+                        rooms.insert(mark.room.to_owned(), Room::init(history_capacity).await);
+                    }
+
+                    rooms.get_mut(&mark.room)
+                }
+            };
+
+            if let Some(room) = room {
+                room.sequence = room.sequence.max(mark.sequence);
+            }
+        }
+
+        let records = store.load()?;
+        let logged = records.len();
+
+        for record in records {
+            if let Some(recipient) = &record.recipient {
+                let key = conversation_key(&record.uuid, recipient).await;
+
+                if !conversations.contains_key(&key) {
+                    conversations.insert(key.to_owned(), Room::init(history_capacity).await);
+                }
+
+                if let Some(conversation) = conversations.get_mut(&key) {
+                    if let Some(record) = conversation.replace(record).await {
+                        conversation.push(record).await;
+                    }
+                }
+
+                continue;
+            }
+
+            if !rooms.contains_key(&record.room) {
+                rooms.insert(record.room.to_owned(), Room::init(history_capacity).await);
+            }
+
+            // Edits and deletions are appended as whole records, which stand in for the original
+            if let Some(room) = rooms.get_mut(&record.room) {
+                if let Some(record) = room.replace(record).await {
+                    room.push(record).await;
+                }
+            }
+        }
+
+        let mut evicted = 0;
+
+        for room in rooms.values_mut().chain(conversations.values_mut()) {
+            evicted += room.evict(&retention, &metrics).await;
+        }
+
+        if evicted > 0 {
+            info!("evicted {} messages from loaded history", evicted);
+        }
+
+        let mut state = State {
+            history_capacity,
+            retention,
+            rooms,
+            conversations,
             users,
+            nicknames,
+            names,
+            sessions,
+            live_sessions,
+            session_grace_period,
+            typing_timeout,
             receiver,
+            store,
+            logged,
+            compact_at: 0,
+            metrics,
+        };
+
+        // Edits, reactions and replies are each logged as a whole record, so any line superseded
+        // or evicted since the last run is dropped whether or not retention removed anything
+        if state.logged > state.held().await {
+            state.compact().await?;
         }
+
+        state.compact_at = state.logged + COMPACTION_LINES;
+
+        Ok(state)
     }
 
-    pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        while let Some((request, response)) = self.receiver.recv().await {
-            match request {
-                StateRequest::AddMessage(message) => self.add_message(message).await?,
-                StateRequest::AddUser((uuid, connection)) => {
-                    self.add_user(uuid, connection).await?;
+    pub async fn run(&mut self) -> Result<(), RelayError> {
+        let mut typing_sweep = time::interval(self.typing_timeout / 5);
+
+        typing_sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let (request, response) = tokio::select! {
+                received = self.receiver.recv() => match received {
+                    Some(received) => received,
+                    None => break,
+                },
+                _ = typing_sweep.tick() => {
+                    if let Err(error) = self.expire_typing().await {
+                        error!("typing expiry failed -> {}", error);
+                    }
 
-                    if let Err(error) = response.send(StateResponse::Ok) {
-                        error!("add user response -> {:?}", error);
+                    continue;
+                }
+            };
+
+            let state_response = match request {
+                StateRequest::AddMessage(message) => match self.rooms.contains_key(&message.room) {
+                    true => match self.add_message(message).await {
+                        Ok(Some(message)) => match self.count_reply(&message).await {
+                            Some(replies) => Ok(StateResponse::Reply((message, replies))),
+                            None => Ok(StateResponse::Message(message)),
+                        },
+                        Ok(None) => Ok(StateResponse::NotFound),
+                        Err(error) => Err(error),
+                    },
+                    false => Ok(StateResponse::NotFound),
+                },
+                StateRequest::AddReaction((uuid, id, emoji)) => {
+                    self.react(&uuid, &id, &emoji, true).await
+                }
+                StateRequest::AddUser((uuid, connection)) => self
+                    .add_user(uuid, connection)
+                    .await
+                    .map(StateResponse::Nickname),
+                StateRequest::Broadcast((audience, message)) => self
+                    .broadcast(&audience, &message)
+                    .await
+                    .map(StateResponse::Rooms),
+                StateRequest::DeleteMessage((uuid, id)) => {
+                    self.amend_message(&uuid, &id, None).await
+                }
+                StateRequest::EditMessage((uuid, id, contents)) => {
+                    self.amend_message(&uuid, &id, Some(contents)).await
+                }
+                StateRequest::DirectMessage(message) => {
+                    self.direct_message(message).await.map(StateResponse::Rooms)
+                }
+                StateRequest::GetConversation((uuid, with)) => self
+                    .get_conversation(&uuid, &with)
+                    .await
+                    .map(StateResponse::Messages),
+                StateRequest::GetMissed((room, after, limit)) => Ok(StateResponse::History(
+                    self.get_missed(&room, after, limit).await,
+                )),
+                StateRequest::GetPresence(room) => match self.rooms.contains_key(&room) {
+                    true => Ok(StateResponse::Presence(self.get_presence(&room).await)),
+                    false => Ok(StateResponse::NotFound),
+                },
+                StateRequest::GetUsers(room) => {
+                    Ok(StateResponse::Users(self.get_users(&room).await))
+                }
+                StateRequest::GetHistory((room, before, limit)) => {
+                    match self.get_history(&room, before.as_deref(), limit).await {
+                        Some(page) => Ok(StateResponse::History(page)),
+                        None => Ok(StateResponse::NotFound),
                     }
                 }
-                StateRequest::GetMessages => {
-                    let messages = self.get_messages().await?;
+                StateRequest::GetThread((uuid, id)) => {
+                    let thread = self.get_thread(&uuid, &id).await;
 
-                    if let Err(error) = response.send(StateResponse::Messages(messages)) {
-                        error!("get messages response -> {:?}", error);
+                    match thread.is_empty() {
+                        true => Ok(StateResponse::NotFound),
+                        false => Ok(StateResponse::Messages(thread)),
                     }
                 }
-                StateRequest::GetUser(uuid) => {
-                    match self.users.get(&uuid) {
-                        Some(user) => {
-                            if let Err(error) = response.send(StateResponse::User(user.to_owned()))
-                            {
-                                error!("get user response -> {:?}", error);
-                            }
-                        }
-                        None => error!("requsted user not found!"),
+                StateRequest::JoinRoom((uuid, room)) => {
+                    self.join_room(uuid, room).await.map(|()| StateResponse::Ok)
+                }
+                StateRequest::LeaveRoom((uuid, room)) => match self.rooms.contains_key(&room) {
+                    true => self
+                        .leave_room(&uuid, &room)
+                        .await
+                        .map(|()| StateResponse::Ok),
+                    false => Ok(StateResponse::NotFound),
+                },
+                StateRequest::RemoveReaction((uuid, id, emoji)) => {
+                    self.react(&uuid, &id, &emoji, false).await
+                }
+                StateRequest::RemoveUser((uuid, connection)) => {
+                    let connected = self.users.contains_key(&uuid);
+
+                    match self.replaced(&uuid, &connection).await {
+                        true => Ok(StateResponse::Ok),
+                        false => self.remove_user(&uuid).await.map(|()| match connected {
+                            true => StateResponse::Ok,
+                            false => StateResponse::NotFound,
+                        }),
                     }
                 }
-                StateRequest::GetUsers => {
-                    let users = self.get_users().await;
+                StateRequest::RegisterSession((token, uuid)) => {
+                    self.live_sessions.insert(token, uuid);
 
-                    if let Err(error) = response.send(StateResponse::Users(users)) {
-                        error!("get user response -> {:?}", error);
+                    Ok(StateResponse::Ok)
+                }
+                StateRequest::ResumeSession((token, subject)) => {
+                    match self.resume_session(&token, subject.as_deref()).await {
+                        Some(suspended) => Ok(StateResponse::Suspended(suspended)),
+                        None => Ok(StateResponse::NotFound),
                     }
                 }
-                StateRequest::RemoveUser(uuid) => {
-                    self.remove_user(&uuid).await?;
-
-                    if let Err(error) = response.send(StateResponse::Ok) {
-                        error!("remove user response -> {:?}", error);
+                StateRequest::SuspendSession((token, suspended, connection)) => {
+                    match self.replaced(&suspended.uuid, &connection).await {
+                        true => Ok(StateResponse::Ok),
+                        false => self
+                            .suspend_session(token, suspended)
+                            .await
+                            .map(|()| StateResponse::Ok),
+                    }
+                }
+                StateRequest::SetNickname((uuid, nickname)) => {
+                    match self.set_nickname(uuid, &nickname).await {
+                        true => Ok(StateResponse::Ok),
+                        false => Ok(StateResponse::NicknameTaken),
+                    }
+                }
+                StateRequest::Search((uuid, room, query, offset, limit)) => {
+                    match self.member(&uuid, &room).await {
+                        true => Ok(StateResponse::Search(
+                            self.search(room, query, offset, limit).await,
+                        )),
+                        false => Ok(StateResponse::NotFound),
                     }
                 }
                 StateRequest::Shutdown => {
                     self.receiver.close();
+
+                    Ok(StateResponse::Ok)
+                }
+                StateRequest::Typing((uuid, room)) => match self.rooms.contains_key(&room) {
+                    true => self.typing(uuid, &room).await.map(|()| StateResponse::Ok),
+                    false => Ok(StateResponse::NotFound),
+                },
+            };
+
+            let state_response = match state_response {
+                Ok(state_response) => state_response,
+                Err(RelayError::UserNotFound(_)) => StateResponse::NotFound,
+                Err(error @ RelayError::MessageNotFound(_)) => StateResponse::Error(error),
+                Err(error) => {
+                    error!("state request failed -> {}", error);
+
+                    StateResponse::Error(error)
+                }
+            };
+
+            if let Err(error) = response.send(state_response) {
+                error!("state response -> {:?}", error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Messages held across every room and conversation.
+    async fn held(&self) -> usize {
+        self.rooms
+            .values()
+            .chain(self.conversations.values())
+            .map(|room| room.messages.len())
+            .sum()
+    }
+
+    /// Rewrite the store with only the messages held, oldest first, and the sequence each room
+    /// and conversation has reached.
+    async fn compact(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut retained: Vec<MessageRecord> = self
+            .rooms
+            .values()
+            .chain(self.conversations.values())
+            .flat_map(|room| room.messages.iter().cloned())
+            .collect();
+
+        retained.sort_by_key(|record| record.timestamp);
+
+        let sequences = sequence_marks(&self.rooms, &self.conversations).await;
+
+        self.store.compact(&retained, &sequences)?;
+        self.logged = retained.len();
+
+        Ok(())
+    }
+
+    /// Count a line appended to the store, compacting it once enough lines have been superseded
+    /// or evicted. Checks are spaced out by `COMPACTION_LINES` appends, so that a log growing
+    /// only with new messages is not counted over on every one.
+    async fn logged(&mut self) {
+        self.logged += 1;
+
+        if self.logged < self.compact_at {
+            return;
+        }
+
+        for room in self
+            .rooms
+            .values_mut()
+            .chain(self.conversations.values_mut())
+        {
+            room.evict(&self.retention, &self.metrics).await;
+        }
+
+        if self.logged - self.held().await >= COMPACTION_LINES {
+            if let Err(error) = self.compact().await {
+                error!("message store compaction -> {}", error);
+            }
+        }
+
+        self.compact_at = self.logged + COMPACTION_LINES;
+    }
+
+    /// Stamp and store a message, returning it as it should be sent to the room.
+    async fn add_message(
+        &mut self,
+        mut message: MessageRecord,
+    ) -> Result<Option<MessageRecord>, RelayError> {
+        self.stop_typing(&message.uuid, &message.room).await?;
+
+        if let Some(reply_to) = message.reply_to.take() {
+            match self.room_message(&reply_to).await {
+                Some(parent) if parent.room == message.room => {
+                    message.reply_to = Some(parent.reply_to.unwrap_or(parent.id));
+                }
+                _ => return Err(RelayError::MessageNotFound(reply_to)),
+            }
+        }
+
+        let room = match self.rooms.get_mut(&message.room) {
+            Some(room) => room,
+            None => {
+                error!("message for unknown room -> {:?}", message.room);
+
+                return Ok(None);
+            }
+        };
+
+        room.accept(&mut message).await;
+
+        self.store
+            .append(&message)
+            .map_err(|error| RelayError::Storage(error.to_string()))?;
+
+        room.push(message.to_owned()).await;
+        room.evict(&self.retention, &self.metrics).await;
+
+        self.logged().await;
+
+        Ok(Some(message))
+    }
+
+    /// Register a user's connection, returning the nickname the uuid still holds. A second
+    /// connection for a uuid already connected, such as another upgrade by the same authenticated
+    /// subject, replaces the first, which is closed and taken out of its rooms.
+    async fn add_user(
+        &mut self,
+        uuid: String,
+        websocket_sender: WebSocketSender,
+    ) -> Result<Option<String>, RelayError> {
+        if self.replaced(&uuid, &websocket_sender).await {
+            info!("replacing connection -> {:?}", &uuid);
+
+            if let Some(previous) = self.users.get(&uuid) {
+                previous.push(WebSocketConnection::Close).await;
+            }
+
+            self.disconnect(&uuid).await?;
+        }
+
+        let nickname = self.names.get(&uuid).cloned();
+
+        match self.users.insert(uuid, websocket_sender) {
+            Some(key) => {
+                info!("updating user -> {:?}", key);
+            }
+            None => {
+                info!("adding new user...");
+            }
+        }
+
+        Ok(nickname)
+    }
+
+    /// Whether a user is connected through some other connection than this one. Requests made
+    /// on behalf of a connection that has been replaced leave the user alone.
+    async fn replaced(&self, uuid: &str, connection: &WebSocketSender) -> bool {
+        match self.users.get(uuid) {
+            Some(user) => !user.same_connection(connection).await,
+            None => false,
+        }
+    }
+
+    /// Queue a message for everyone in the audience without waiting on any of them. Users found
+    /// to have closed their sink are reaped, returning the rooms they were in.
+    async fn broadcast(
+        &mut self,
+        audience: &Audience,
+        message: &Arc<Message>,
+    ) -> Result<Vec<String>, RelayError> {
+        let (room, skip) = match audience {
+            Audience::Everyone => (None, None),
+            Audience::Room(room) => (Some(room), None),
+            Audience::RoomExcept((room, uuid)) => (Some(room), Some(uuid)),
+        };
+        let mut open = true;
+
+        match room {
+            Some(room) => {
+                if let Some(entry) = self.rooms.get(room) {
+                    for uuid in &entry.members {
+                        if Some(uuid) == skip {
+                            continue;
+                        }
+
+                        if let Some(user) = self.users.get(uuid) {
+                            open &= deliver(uuid, user, message).await;
+                        }
+                    }
+                }
+            }
+            None => {
+                for (uuid, user) in &self.users {
+                    open &= deliver(uuid, user, message).await;
+                }
+            }
+        }
+
+        match open {
+            true => Ok(Vec::new()),
+            false => self.reap_users().await,
+        }
+    }
+
+    /// Edit a message in a room for its author, or tombstone it when there are no contents,
+    /// returning the new version. Deleted messages can not be changed again.
+    async fn amend_message(
+        &mut self,
+        uuid: &str,
+        id: &str,
+        contents: Option<String>,
+    ) -> Result<StateResponse, RelayError> {
+        let mut message = match self.room_message(id).await {
+            Some(message) => message,
+            None => return Ok(StateResponse::NotFound),
+        };
+
+        if message.uuid != uuid {
+            return Ok(StateResponse::NotAuthor);
+        }
+
+        match contents {
+            Some(contents) => {
+                message.contents = contents;
+                message.edited_at = Some(timestamp().await);
+            }
+            None => {
+                message.contents = String::new();
+                message.attachment = None;
+                message.reactions.clear();
+                message.deleted = true;
+            }
+        }
+
+        self.update_message(message.to_owned()).await?;
+
+        Ok(StateResponse::Message(message))
+    }
+
+    /// Add or remove a user's reaction to a message in a room, returning the change, or `Ok` if
+    /// there was nothing to change.
+    async fn react(
+        &mut self,
+        uuid: &str,
+        id: &str,
+        emoji: &str,
+        add: bool,
+    ) -> Result<StateResponse, RelayError> {
+        let mut message = match self.room_message(id).await {
+            Some(message) if self.member(uuid, &message.room).await => message,
+            _ => return Ok(StateResponse::NotFound),
+        };
+        let index = match message
+            .reactions
+            .iter()
+            .position(|reaction| reaction.emoji == emoji)
+        {
+            Some(index) => index,
+            None if add => {
+                message.reactions.push(Reaction {
+                    emoji: emoji.to_owned(),
+                    ..Reaction::default()
+                });
+
+                message.reactions.len() - 1
+            }
+            None => return Ok(StateResponse::Ok),
+        };
+        let reaction = &mut message.reactions[index];
+
+        match (add, reaction.users.iter().any(|user| user == uuid)) {
+            (true, false) => reaction.users.push(uuid.to_owned()),
+            (false, true) => reaction.users.retain(|user| user != uuid),
+            _ => return Ok(StateResponse::Ok),
+        }
+
+        reaction.count = reaction.users.len();
+
+        let event = ReactionEvent {
+            room: message.room.to_owned(),
+            id: message.id.to_owned(),
+            emoji: emoji.to_owned(),
+            uuid: uuid.to_owned(),
+            count: reaction.count,
+        };
+
+        message.reactions.retain(|reaction| reaction.count > 0);
+
+        self.update_message(message).await?;
+
+        Ok(StateResponse::Reacted(event))
+    }
+
+    /// Count a reply against the first message of its thread, returning the new count if that
+    /// message is still held.
+    async fn count_reply(&mut self, message: &MessageRecord) -> Option<usize> {
+        let reply_to = message.reply_to.as_ref()?;
+        let mut parent = self
+            .rooms
+            .get(&message.room)?
+            .messages
+            .iter()
+            .find(|parent| &parent.id == reply_to)?
+            .to_owned();
+
+        parent.replies += 1;
+
+        let replies = parent.replies;
+
+        // The reply itself is already stored, so failing it now would only invite a duplicate
+        if let Err(error) = self.update_message(parent).await {
+            error!("reply count -> {}", error);
+
+            return None;
+        }
+
+        Some(replies)
+    }
+
+    /// The first message of a thread followed by its replies, by the id of any of them. Parts
+    /// of a thread that have been evicted are left out.
+    async fn get_thread(&mut self, uuid: &str, id: &str) -> Vec<MessageRecord> {
+        for room in self.rooms.values() {
+            if !room.members.contains(uuid) {
+                continue;
+            }
+
+            if let Some(message) = room.messages.iter().find(|message| message.id == id) {
+                let root = message.reply_to.as_deref().unwrap_or(&message.id);
+
+                return room
+                    .messages
+                    .iter()
+                    .filter(|message| {
+                        message.id == root || message.reply_to.as_deref() == Some(root)
+                    })
+                    .cloned()
+                    .collect();
+            }
+        }
+
+        Vec::new()
+    }
+
+    /// Whether a user is in a room, and so can see and react to its messages.
+    async fn member(&self, uuid: &str, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|room| room.members.contains(uuid))
+    }
+
+    /// A message still standing in any room, by its id.
+    async fn room_message(&self, id: &str) -> Option<MessageRecord> {
+        self.rooms
+            .values()
+            .flat_map(|room| room.messages.iter())
+            .find(|message| message.id == id && !message.deleted)
+            .cloned()
+    }
+
+    /// Store a new version of a message and swap it in for the one in its room, leaving the
+    /// room as it was if the store fails.
+    async fn update_message(&mut self, message: MessageRecord) -> Result<(), RelayError> {
+        self.store
+            .append(&message)
+            .map_err(|error| RelayError::Storage(error.to_string()))?;
+
+        if let Some(room) = self.rooms.get_mut(&message.room) {
+            room.replace(message).await;
+        }
+
+        self.logged().await;
+
+        Ok(())
+    }
+
+    /// Store a direct message and deliver it to its recipient, named by uuid or nickname, and
+    /// back to its sender.
+    async fn direct_message(
+        &mut self,
+        mut message: MessageRecord,
+    ) -> Result<Vec<String>, RelayError> {
+        let target = message.recipient.to_owned().unwrap_or_default();
+        let recipient = match self.resolve(&target).await {
+            Some(recipient) => recipient,
+            None => return Err(RelayError::UserNotFound(target)),
+        };
+        let key = conversation_key(&message.uuid, &recipient).await;
+
+        message.room = String::new();
+        message.recipient = Some(recipient.to_owned());
+
+        if !self.conversations.contains_key(&key) {
+            self.conversations
+                .insert(key.to_owned(), Room::init(self.history_capacity).await);
+        }
+
+        if let Some(conversation) = self.conversations.get(&key) {
+            conversation.accept(&mut message).await;
+        }
+
+        self.store
+            .append(&message)
+            .map_err(|error| RelayError::Storage(error.to_string()))?;
+
+        let frame = Arc::new(
+            ServerFrame::DirectMessage(message.to_owned())
+                .to_message()
+                .await?,
+        );
+
+        let mut open = true;
+
+        for uuid in [&message.uuid, &recipient] {
+            if let Some(user) = self.users.get(uuid) {
+                open &= deliver(uuid, user, &frame).await;
+            }
+
+            if message.uuid == recipient {
+                break;
+            }
+        }
+
+        if let Some(conversation) = self.conversations.get_mut(&key) {
+            conversation.push(message).await;
+            conversation.evict(&self.retention, &self.metrics).await;
+        }
+
+        self.logged().await;
+
+        match open {
+            true => Ok(Vec::new()),
+            false => self.reap_users().await,
+        }
+    }
+
+    async fn get_conversation(
+        &mut self,
+        uuid: &str,
+        with: &str,
+    ) -> Result<Vec<MessageRecord>, RelayError> {
+        let with = match self.nicknames.get(&with.to_lowercase()) {
+            Some(holder) => holder.to_owned(),
+            None => with.to_owned(),
+        };
+
+        match self
+            .conversations
+            .get_mut(&conversation_key(uuid, &with).await)
+        {
+            Some(conversation) => {
+                conversation.evict(&self.retention, &self.metrics).await;
+
+                Ok(conversation.messages.iter().cloned().collect())
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Up to `limit` messages ending just before `before`, or at the latest message, and whether
+    /// any older ones are left. Returns `None` if `before` is not in the room's history.
+    async fn get_history(
+        &mut self,
+        room: &str,
+        before: Option<&str>,
+        limit: usize,
+    ) -> Option<(Vec<MessageRecord>, bool)> {
+        let room = match self.rooms.get_mut(room) {
+            Some(room) => room,
+            None => return before.is_none().then(|| (Vec::new(), false)),
+        };
+
+        room.evict(&self.retention, &self.metrics).await;
+
+        let end = match before {
+            Some(before) => room
+                .messages
+                .iter()
+                .position(|message| message.id == before)?,
+            None => room.messages.len(),
+        };
+        let start = end.saturating_sub(limit);
+
+        Some((
+            room.messages.range(start..end).cloned().collect(),
+            start > 0,
+        ))
+    }
+
+    /// Up to `limit` of the latest messages in a room with a later sequence than `after`, and
+    /// whether any earlier ones past `after` were left out. Unknown rooms have missed nothing.
+    async fn get_missed(
+        &mut self,
+        room: &str,
+        after: u64,
+        limit: usize,
+    ) -> (Vec<MessageRecord>, bool) {
+        let room = match self.rooms.get_mut(room) {
+            Some(room) => room,
+            None => return (Vec::new(), false),
+        };
+
+        room.evict(&self.retention, &self.metrics).await;
+
+        let first = room
+            .messages
+            .partition_point(|message| message.sequence <= after);
+        let start = first.max(room.messages.len().saturating_sub(limit));
+
+        (
+            room.messages.range(start..).cloned().collect(),
+            start > first,
+        )
+    }
+
+    /// Up to `limit` of the messages in a room matching a query, best first, skipping `offset`.
+    /// Unknown rooms have no matches.
+    async fn search(
+        &mut self,
+        room: String,
+        query: String,
+        offset: usize,
+        limit: usize,
+    ) -> SearchPage {
+        let mut page = SearchPage {
+            offset,
+            ..SearchPage::default()
+        };
+
+        if let Some(history) = self.rooms.get_mut(&room) {
+            history.evict(&self.retention, &self.metrics).await;
+
+            let ranked = history.index.search(&query).await;
+
+            page.total = ranked.len();
+
+            for (id, score) in ranked.into_iter().skip(offset).take(limit) {
+                if let Some(message) = history.messages.iter().find(|message| message.id == id) {
+                    let result = SearchResult {
+                        snippet: snippet(&message.contents, &query).await,
+                        message: message.to_owned(),
+                        score,
+                    };
+
+                    page.results.push(result);
+                }
+            }
+        }
+
+        page.room = room;
+        page.query = query;
+
+        page
+    }
+
+    /// Everyone in a room, ordered by nickname.
+    async fn get_presence(&self, room: &str) -> Vec<Presence> {
+        let mut presence = Vec::with_capacity(10);
+
+        if let Some(room) = self.rooms.get(room) {
+            for uuid in &room.members {
+                presence.push(Presence {
+                    uuid: uuid.to_owned(),
+                    nickname: self.display_name(uuid).await,
+                });
+            }
+        }
+
+        presence.sort_by(|a, b| a.nickname.cmp(&b.nickname).then(a.uuid.cmp(&b.uuid)));
+
+        presence
+    }
+
+    async fn get_users(&self, room: &str) -> ConnectedUsers {
+        let mut users = HashMap::with_capacity(10);
+
+        if let Some(room) = self.rooms.get(room) {
+            for uuid in &room.members {
+                if let Some(user) = self.users.get(uuid) {
+                    users.insert(uuid.to_owned(), user.to_owned());
+                }
+            }
+        }
+
+        users
+    }
+
+    async fn join_room(&mut self, uuid: String, room: String) -> Result<(), RelayError> {
+        if !self.users.contains_key(&uuid) {
+            return Err(RelayError::UserNotFound(uuid));
+        }
+
+        if !self.rooms.contains_key(&room) {
+            info!("creating room -> {:?}", &room);
+
+            self.rooms
+                .insert(room.to_owned(), Room::init(self.history_capacity).await);
+        }
+
+        let joined = match self.rooms.get_mut(&room) {
+            Some(entry) => entry.members.insert(uuid.to_owned()),
+            None => false,
+        };
+
+        if joined {
+            self.announce(&room, &uuid, ServerFrame::UserJoined).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn leave_room(&mut self, uuid: &str, room: &str) -> Result<(), RelayError> {
+        self.stop_typing(uuid, room).await?;
+
+        let left = match self.rooms.get_mut(room) {
+            Some(entry) => entry.members.remove(uuid),
+            None => false,
+        };
+
+        if left {
+            self.announce(room, uuid, ServerFrame::UserLeft).await?;
+        }
+
+        // Rooms that have seen a message are kept, so that their sequence never starts over
+        if let Some(entry) = self.rooms.get(room) {
+            if entry.members.is_empty() && entry.sequence == 0 && room != DEFAULT_ROOM {
+                info!("closing empty room -> {:?}", room);
+
+                self.rooms.remove(room);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_user(&mut self, uuid: &str) -> Result<(), RelayError> {
+        self.disconnect(uuid).await?;
+
+        self.nicknames.retain(|_, holder| holder != uuid);
+        self.names.remove(uuid);
+
+        Ok(())
+    }
+
+    /// Disconnect users whose sink has closed without the connection cleaning up after itself,
+    /// returning the rooms they were in.
+    async fn reap_users(&mut self) -> Result<Vec<String>, RelayError> {
+        let mut closed = Vec::with_capacity(self.users.len());
+
+        for (uuid, user) in &self.users {
+            if user.is_closed().await {
+                closed.push(uuid.to_owned());
+            }
+        }
+
+        let mut rooms = Vec::with_capacity(closed.len());
+
+        for uuid in closed {
+            for (name, room) in &self.rooms {
+                if room.members.contains(&uuid) && !rooms.contains(name) {
+                    rooms.push(name.to_owned());
+                }
+            }
+
+            info!("reaping closed connection -> {:?}", &uuid);
+
+            self.disconnect(&uuid).await?;
+        }
+
+        Ok(rooms)
+    }
+
+    /// Drop a user's connection and room memberships, leaving their nickname claimed.
+    async fn disconnect(&mut self, uuid: &str) -> Result<(), RelayError> {
+        if let Some(entry) = self.users.remove(uuid) {
+            info!("removing user -> {:?}", entry);
+        }
+
+        self.live_sessions.retain(|_, holder| holder != uuid);
+
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.members.contains(uuid))
+            .map(|(name, _)| name.to_owned())
+            .collect();
+
+        for room in rooms {
+            self.leave_room(uuid, &room).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_nickname(&mut self, uuid: String, nickname: &str) -> bool {
+        self.expire_sessions().await;
+
+        let key = nickname.to_lowercase();
+
+        if let Some(holder) = self.nicknames.get(&key) {
+            if holder != &uuid {
+                info!("nickname already taken -> {:?}", nickname);
+
+                return false;
+            }
+        }
+
+        self.nicknames.retain(|_, holder| holder != &uuid);
+        self.nicknames.insert(key, uuid.to_owned());
+        self.names.insert(uuid, nickname.to_owned());
+
+        true
+    }
+
+    /// Disconnect a user while holding on to their identity until the grace period runs out.
+    async fn suspend_session(
+        &mut self,
+        token: String,
+        mut suspended: SuspendedSession,
+    ) -> Result<(), RelayError> {
+        self.expire_sessions().await;
+
+        if let Some(room) = self.rooms.get(&suspended.room) {
+            suspended.sequence = room.sequence;
+        }
+
+        self.disconnect(&suspended.uuid).await?;
+
+        info!("suspending session -> {:?}", &suspended.uuid);
+
+        self.sessions.insert(token, suspended);
+
+        Ok(())
+    }
+
+    /// Take back a suspended session, or take over one whose connection is still open because
+    /// its socket has not yet been noticed as gone. When the upgrade was authenticated, the
+    /// session must belong to the same subject.
+    async fn resume_session(
+        &mut self,
+        token: &str,
+        subject: Option<&str>,
+    ) -> Option<SuspendedSession> {
+        self.expire_sessions().await;
+
+        let uuid = match self.sessions.get(token) {
+            Some(suspended) => &suspended.uuid,
+            None => self.live_sessions.get(token)?,
+        };
+
+        if subject.is_some_and(|subject| subject != uuid) {
+            info!("session belongs to another subject -> {:?}", subject);
+
+            return None;
+        }
+
+        if let Some(suspended) = self.sessions.remove(token) {
+            info!("resuming session -> {:?}", &suspended.uuid);
+
+            return Some(suspended);
+        }
+
+        let uuid = self.live_sessions.remove(token)?;
+
+        info!("taking over session -> {:?}", &uuid);
+
+        // What reached the old socket is unknown, so the latest messages are sent again
+        Some(SuspendedSession {
+            room: self
+                .rooms
+                .iter()
+                .find(|(_, room)| room.members.contains(&uuid))
+                .map_or(DEFAULT_ROOM.to_owned(), |(name, _)| name.to_owned()),
+            suspended_at: timestamp().await,
+            sequence: 0,
+            uuid,
+        })
+    }
+
+    /// The uuid of a connected user, named by either their uuid or nickname.
+    async fn resolve(&self, target: &str) -> Option<String> {
+        if self.users.contains_key(target) {
+            return Some(target.to_owned());
+        }
+
+        self.nicknames
+            .get(&target.to_lowercase())
+            .filter(|holder| self.users.contains_key(*holder))
+            .cloned()
+    }
+
+    /// The nickname a user has claimed, otherwise their guest name.
+    async fn display_name(&self, uuid: &str) -> String {
+        match self.names.get(uuid) {
+            Some(nickname) => nickname.to_owned(),
+            None => guest_name(uuid).await,
+        }
+    }
+
+    /// Tell everyone else in a room that a user has joined or left it.
+    async fn announce(
+        &self,
+        room: &str,
+        uuid: &str,
+        frame: fn(PresenceEvent) -> ServerFrame,
+    ) -> Result<(), RelayError> {
+        let event = PresenceEvent {
+            room: room.to_owned(),
+            uuid: uuid.to_owned(),
+            nickname: self.display_name(uuid).await,
+            timestamp: timestamp().await,
+        };
+
+        self.relay(room, uuid, &frame(event)).await
+    }
+
+    /// Send a frame about a user to everyone else in a room. Sinks found to be closed are left
+    /// for the next broadcast to reap.
+    async fn relay(&self, room: &str, uuid: &str, frame: &ServerFrame) -> Result<(), RelayError> {
+        let members = match self.rooms.get(room) {
+            Some(entry) if entry.members.iter().any(|member| member != uuid) => &entry.members,
+            _ => return Ok(()),
+        };
+        let message = Arc::new(frame.to_message().await?);
+
+        for member in members {
+            if member == uuid {
+                continue;
+            }
+
+            if let Some(user) = self.users.get(member) {
+                deliver(member, user, &message).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mark a member of a room as typing, telling everyone else there when they start. Typing is
+    /// only ever held here, never in the room's messages.
+    async fn typing(&mut self, uuid: String, room: &str) -> Result<(), RelayError> {
+        let started = match self.rooms.get_mut(room) {
+            Some(entry) if entry.members.contains(&uuid) => entry
+                .typing
+                .insert(uuid.to_owned(), Instant::now())
+                .is_none(),
+            _ => {
+                info!("typing outside of room -> {:?}", room);
+
+                return Ok(());
+            }
+        };
+
+        if started {
+            let frame = ServerFrame::Typing {
+                room: room.to_owned(),
+                uuid: uuid.to_owned(),
+            };
+
+            self.relay(room, &uuid, &frame).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn stop_typing(&mut self, uuid: &str, room: &str) -> Result<(), RelayError> {
+        let stopped = match self.rooms.get_mut(room) {
+            Some(entry) => entry.typing.remove(uuid).is_some(),
+            None => false,
+        };
+
+        if stopped {
+            let frame = ServerFrame::TypingStopped {
+                room: room.to_owned(),
+                uuid: uuid.to_owned(),
+            };
+
+            self.relay(room, uuid, &frame).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop anyone who has gone the typing timeout without refreshing.
+    async fn expire_typing(&mut self) -> Result<(), RelayError> {
+        let mut expired = Vec::new();
+
+        for (name, room) in &self.rooms {
+            for (uuid, refreshed) in &room.typing {
+                if refreshed.elapsed() >= self.typing_timeout {
+                    expired.push((uuid.to_owned(), name.to_owned()));
+                }
+            }
+        }
+
+        for (uuid, room) in expired {
+            self.stop_typing(&uuid, &room).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forget suspended sessions older than the grace period, releasing their nicknames unless
+    /// the same user has since connected again.
+    async fn expire_sessions(&mut self) {
+        let now = timestamp().await;
+        let grace_period = self.session_grace_period.as_millis() as u64;
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, suspended)| now.saturating_sub(suspended.suspended_at) > grace_period)
+            .map(|(token, _)| token.to_owned())
+            .collect();
+
+        for token in expired {
+            if let Some(suspended) = self.sessions.remove(&token) {
+                info!("session expired -> {:?}", &suspended.uuid);
+
+                if !self.users.contains_key(&suspended.uuid) {
+                    self.nicknames.retain(|_, holder| holder != &suspended.uuid);
+                    self.names.remove(&suspended.uuid);
+                }
+            }
+        }
+    }
+}
+
+/// Conversations are shared by both users, so their uuids are put in order.
+async fn conversation_key(uuid: &str, other: &str) -> (String, String) {
+    match uuid <= other {
+        true => (uuid.to_owned(), other.to_owned()),
+        false => (other.to_owned(), uuid.to_owned()),
+    }
+}
+
+/// The sequence reached by each room and conversation that has seen a message.
+async fn sequence_marks(
+    rooms: &HashMap<String, Room>,
+    conversations: &HashMap<(String, String), Room>,
+) -> Vec<SequenceMark> {
+    let rooms = rooms.iter().map(|(room, history)| SequenceMark {
+        room: room.to_owned(),
+        conversation: None,
+        sequence: history.sequence,
+    });
+    let conversations = conversations.iter().map(|(key, history)| SequenceMark {
+        room: String::new(),
+        conversation: Some(key.to_owned()),
+        sequence: history.sequence,
+    });
+
+    rooms
+        .chain(conversations)
+        .filter(|mark| mark.sequence > 0)
+        .collect()
+}
+
+/// The name used for a user who has not set a nickname, from the start of their uuid.
+pub async fn guest_name(uuid: &str) -> String {
+    format!("guest-{}", uuid.chars().take(8).collect::<String>())
+}
+
+/// Queue a message on a user's sink, returning `false` if the sink has closed or the user is
+/// being disconnected for falling behind. Drops are logged as their count doubles.
+async fn deliver(uuid: &str, user: &WebSocketSender, message: &Arc<Message>) -> bool {
+    match user
+        .push(WebSocketConnection::SendMessage(Arc::clone(message)))
+        .await
+    {
+        Delivery::Queued => true,
+        Delivery::Dropped => {
+            let dropped = user.dropped().await;
+
+            if dropped.is_power_of_two() {
+                info!("slow consumer has dropped {} frames -> {:?}", dropped, uuid);
+            }
+
+            true
+        }
+        Delivery::Disconnected => {
+            info!("disconnecting slow consumer -> {:?}", uuid);
+
+            false
+        }
+        Delivery::Closed => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::{outbound, StateSender};
+    use crate::config::SlowConsumer;
+    use crate::store::{FileStore, MemoryStore};
+    use std::str::FromStr;
+    use tokio::sync::{mpsc, oneshot};
+    use uuid::Uuid;
+
+    impl State {
+        /// Every message a room holds once retention has been applied.
+        async fn get_messages(&mut self, room: &str) -> Result<Vec<MessageRecord>, RelayError> {
+            match self.rooms.get_mut(room) {
+                Some(room) => {
+                    room.evict(&self.retention, &self.metrics).await;
+
+                    Ok(room.messages.iter().cloned().collect())
+                }
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn init() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        assert_eq!(test_state.rooms.len(), 1);
+
+        let test_default_room = test_state.rooms.get(DEFAULT_ROOM).expect("default room");
+
+        assert!(test_default_room.messages.is_empty());
+        assert!(test_default_room.messages.capacity() >= 100);
+        assert_eq!(test_default_room.bytes, 0);
+        assert!(test_default_room.members.is_empty());
+
+        assert!(test_state.users.is_empty());
+        assert!(test_state.users.capacity() >= 10);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn init_loads_history() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let mut test_store = FileStore::init(&test_path).await?;
+
+        for (test_room, test_contents) in [
+            (DEFAULT_ROOM, "test_message_one"),
+            ("test_room", "test_message_two"),
+            (DEFAULT_ROOM, "test_message_three"),
+        ] {
+            test_store.append(&MessageRecord {
+                room: test_room.to_owned(),
+                contents: test_contents.to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })?;
+        }
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(test_store),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        assert_eq!(test_state.rooms.len(), 2);
+
+        let test_messages = test_state.get_messages(DEFAULT_ROOM).await?;
+
+        assert_eq!(test_messages.len(), 2);
+        assert_eq!(test_messages[0].contents, "test_message_one");
+        assert_eq!(test_messages[1].contents, "test_message_three");
+        assert_eq!(test_messages[1].sequence, 2);
+        assert!(!test_messages[1].id.is_empty());
+
+        test_state
+            .add_message(MessageRecord {
+                room: String::from("test_room"),
+                contents: "test_message_four".to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })
+            .await?;
+
+        drop(test_state);
+
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let test_store = FileStore::init(&test_path).await?;
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(test_store),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+        let test_room_messages = test_state.get_messages("test_room").await?;
+
+        assert_eq!(test_room_messages.len(), 2);
+        assert_eq!(test_room_messages[1].contents, "test_message_four");
+        assert_eq!(test_room_messages[1].sequence, 2);
+
+        std::fs::remove_file(&test_path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn add_message() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        assert!(test_state.rooms[DEFAULT_ROOM].messages.is_empty());
+        assert_eq!(test_state.rooms[DEFAULT_ROOM].messages.len(), 0);
+
+        let test_accepted = test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                contents: "test_message".to_owned(),
+                ..MessageRecord::default()
+            })
+            .await?
+            .expect("accepted");
+
+        assert!(!test_state.rooms[DEFAULT_ROOM].messages.is_empty());
+        assert_eq!(test_state.rooms[DEFAULT_ROOM].messages.len(), 1);
+        assert_eq!(test_state.rooms[DEFAULT_ROOM].messages[0], test_accepted);
+        assert!(Uuid::from_str(&test_accepted.id).is_ok());
+        assert_eq!(test_accepted.sequence, 1);
+        assert!(test_accepted.timestamp > 0);
+
+        let test_next = test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                contents: "test_next_message".to_owned(),
+                ..MessageRecord::default()
+            })
+            .await?
+            .expect("accepted");
+
+        assert_ne!(test_next.id, test_accepted.id);
+        assert_eq!(test_next.sequence, 2);
+        assert!(test_next.timestamp >= test_accepted.timestamp);
+
+        test_state
+            .add_message(MessageRecord {
+                room: String::from("test_unknown_room"),
+                contents: "test_unknown_room_message".to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })
+            .await?;
+
+        assert!(!test_state.rooms.contains_key("test_unknown_room"));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn add_user() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        assert!(test_state.users.is_empty());
+        assert_eq!(test_state.users.len(), 0);
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let (test_websocket_sender, test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        drop(test_websocket_receiver);
+
+        test_state
+            .add_user(test_uuid, test_websocket_sender)
+            .await?;
+
+        assert!(!test_state.users.is_empty());
+        assert_eq!(test_state.users.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn add_user_reconnect() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+        let test_uuid = String::from("test_subject");
+        let (test_sink, _test_receiver) = outbound(16, SlowConsumer::DropOldest).await;
+
+        assert_eq!(
+            test_state.add_user(test_uuid.to_owned(), test_sink).await?,
+            None,
+        );
+        assert!(
+            test_state
+                .set_nickname(test_uuid.to_owned(), "Test_Nickname")
+                .await
+        );
+
+        test_state
+            .suspend_session(
+                String::from("test_token"),
+                SuspendedSession {
+                    uuid: test_uuid.to_owned(),
+                    room: DEFAULT_ROOM.to_owned(),
+                    suspended_at: timestamp().await,
+                    sequence: 0,
+                },
+            )
+            .await?;
+
+        // Connecting again without the resume token still finds the nickname claimed
+        let (test_sink, _test_receiver) = outbound(16, SlowConsumer::DropOldest).await;
+
+        assert_eq!(
+            test_state.add_user(test_uuid.to_owned(), test_sink).await?,
+            Some(String::from("Test_Nickname")),
+        );
+
+        let (test_sink, _test_replacing_receiver) = outbound(16, SlowConsumer::DropOldest).await;
+
+        assert_eq!(
+            test_state.add_user(test_uuid.to_owned(), test_sink).await?,
+            Some(String::from("Test_Nickname")),
+        );
+        assert_eq!(test_state.display_name(&test_uuid).await, "Test_Nickname");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_history() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let mut test_ids = Vec::with_capacity(5);
+
+        for test_contents in ["one", "two", "three", "four", "five"] {
+            let test_accepted = test_state
+                .add_message(MessageRecord {
+                    room: DEFAULT_ROOM.to_owned(),
+                    contents: test_contents.to_owned(),
+                    ..MessageRecord::default()
+                })
+                .await?
+                .expect("accepted");
+
+            test_ids.push(test_accepted.id);
+        }
+
+        let test_page_ids = |test_page: &[MessageRecord]| -> Vec<String> {
+            test_page
+                .iter()
+                .map(|test_message| test_message.id.to_owned())
+                .collect()
+        };
+
+        let (test_page, test_has_more) = test_state
+            .get_history(DEFAULT_ROOM, None, 2)
+            .await
+            .expect("page");
+
+        assert_eq!(test_page_ids(&test_page), test_ids[3..5]);
+        assert!(test_has_more);
+
+        let (test_page, test_has_more) = test_state
+            .get_history(DEFAULT_ROOM, Some(&test_ids[3]), 2)
+            .await
+            .expect("page");
+
+        assert_eq!(test_page_ids(&test_page), test_ids[1..3]);
+        assert!(test_has_more);
+
+        let (test_page, test_has_more) = test_state
+            .get_history(DEFAULT_ROOM, Some(&test_ids[1]), 2)
+            .await
+            .expect("page");
+
+        assert_eq!(test_page_ids(&test_page), test_ids[0..1]);
+        assert!(!test_has_more);
+
+        let (test_page, test_has_more) = test_state
+            .get_history(DEFAULT_ROOM, None, 10)
+            .await
+            .expect("page");
+
+        assert_eq!(test_page.len(), 5);
+        assert!(!test_has_more);
+
+        assert_eq!(
+            test_state
+                .get_history(DEFAULT_ROOM, Some("test_unknown_id"), 2)
+                .await,
+            None,
+        );
+        assert_eq!(
+            test_state.get_history("test_unknown_room", None, 2).await,
+            Some((Vec::new(), false)),
+        );
+        assert_eq!(
+            test_state
+                .get_history("test_unknown_room", Some(&test_ids[0]), 2)
+                .await,
+            None,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_missed() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        for test_contents in ["one", "two", "three", "four", "five"] {
+            test_state
+                .add_message(MessageRecord {
+                    room: DEFAULT_ROOM.to_owned(),
+                    contents: test_contents.to_owned(),
+                    ..MessageRecord::default()
+                })
+                .await?;
+        }
+
+        let test_sequences = |test_page: &[MessageRecord]| -> Vec<u64> {
+            test_page
+                .iter()
+                .map(|test_message| test_message.sequence)
+                .collect()
+        };
+
+        let (test_page, test_has_more) = test_state.get_missed(DEFAULT_ROOM, 1, 10).await;
+
+        assert_eq!(test_sequences(&test_page), vec![2, 3, 4, 5]);
+        assert!(!test_has_more);
+
+        let (test_page, test_has_more) = test_state.get_missed(DEFAULT_ROOM, 1, 2).await;
+
+        assert_eq!(test_sequences(&test_page), vec![4, 5]);
+        assert!(test_has_more);
+
+        let (test_page, test_has_more) = test_state.get_missed(DEFAULT_ROOM, 3, 2).await;
+
+        assert_eq!(test_sequences(&test_page), vec![4, 5]);
+        assert!(!test_has_more);
+        assert_eq!(
+            test_state.get_missed(DEFAULT_ROOM, 5, 10).await,
+            (Vec::new(), false),
+        );
+        assert_eq!(
+            test_state.get_missed("test_unknown_room", 0, 10).await,
+            (Vec::new(), false),
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_messages() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        test_state
+            .add_user(test_uuid.to_owned(), test_websocket_sender)
+            .await?;
+
+        test_state
+            .join_room(test_uuid, String::from("test_room"))
+            .await?;
+
+        test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                contents: "test_message_one".to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })
+            .await?;
+        test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                contents: "test_message_two".to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })
+            .await?;
+        test_state
+            .add_message(MessageRecord {
+                room: String::from("test_room"),
+                contents: "test_message_three".to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })
+            .await?;
+
+        let test_messages = test_state.get_messages(DEFAULT_ROOM).await?;
+
+        assert_eq!(test_messages.len(), 2);
+        assert_eq!(test_messages[0].contents, "test_message_one");
+        assert_eq!(test_messages[1].contents, "test_message_two");
+
+        let test_room_messages = test_state.get_messages("test_room").await?;
+
+        assert_eq!(test_room_messages.len(), 1);
+        assert_eq!(test_room_messages[0].contents, "test_message_three");
+
+        assert!(test_state
+            .get_messages("test_unknown_room")
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_users() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        assert!(test_state.users.is_empty());
+        assert_eq!(test_state.users.len(), 0);
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        test_state
+            .add_user(test_uuid.to_owned(), test_websocket_sender)
+            .await?;
+
+        assert!(test_state.get_users(DEFAULT_ROOM).await.is_empty());
+
+        test_state
+            .join_room(test_uuid, DEFAULT_ROOM.to_owned())
+            .await?;
+
+        assert!(test_state.get_users("test_room").await.is_empty());
+
+        let test_users = test_state.get_users(DEFAULT_ROOM).await;
+
+        assert!(!test_users.is_empty());
+        assert_eq!(test_users.len(), 1);
+
+        for (test_uuid, test_websocket_connection) in test_users.iter() {
+            assert_eq!(
+                Uuid::from_str(test_uuid)
+                    .expect("uuid from &str")
+                    .get_version_num(),
+                4
+            );
+
+            assert!(!test_websocket_connection.is_closed().await);
+            assert_eq!(test_websocket_connection.dropped().await, 0);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remove_user() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        assert!(test_state.users.is_empty());
+        assert_eq!(test_state.users.len(), 0);
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let test_remove_user = test_uuid.clone();
+        let (test_websocket_sender, test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        drop(test_websocket_receiver);
+
+        test_state
+            .add_user(test_uuid.to_owned(), test_websocket_sender)
+            .await?;
+        test_state
+            .join_room(test_uuid, String::from("test_room"))
+            .await?;
+
+        assert!(!test_state.users.is_empty());
+        assert_eq!(test_state.users.len(), 1);
+        assert_eq!(test_state.rooms["test_room"].members.len(), 1);
+
+        test_state.remove_user(&test_remove_user).await?;
+
+        assert!(test_state.users.is_empty());
+        assert_eq!(test_state.users.len(), 0);
+        assert!(!test_state.rooms.contains_key("test_room"));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replace_connection() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        tokio::spawn(async move { test_state.run().await });
+
+        let (test_first_sender, mut test_first_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+        let (test_second_sender, _test_second_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        crate::channels::add_user(
+            &test_state_sender,
+            String::from("test_subject"),
+            test_first_sender.to_owned(),
+        )
+        .await?;
+        crate::channels::join_room(&test_state_sender, "test_subject", "test_room").await?;
+        crate::channels::add_user(
+            &test_state_sender,
+            String::from("test_subject"),
+            test_second_sender.to_owned(),
+        )
+        .await?;
+
+        assert!(matches!(
+            test_first_receiver.recv().await,
+            Some(WebSocketConnection::Close),
+        ));
+        assert!(crate::channels::get_users(&test_state_sender, "test_room")
+            .await?
+            .is_empty());
+
+        crate::channels::join_room(&test_state_sender, "test_subject", DEFAULT_ROOM).await?;
+        crate::channels::remove_user(&test_state_sender, "test_subject", &test_first_sender)
+            .await?;
+
+        let test_users = crate::channels::get_users(&test_state_sender, DEFAULT_ROOM).await?;
+
+        assert_eq!(test_users.len(), 1);
+        assert!(
+            test_users["test_subject"]
+                .same_connection(&test_second_sender)
+                .await
+        );
+
+        let test_suspended = SuspendedSession {
+            uuid: String::from("test_subject"),
+            room: DEFAULT_ROOM.to_owned(),
+            suspended_at: timestamp().await,
+            sequence: 0,
+        };
+
+        crate::channels::suspend_session(
+            &test_state_sender,
+            "test_token",
+            test_suspended,
+            &test_first_sender,
+        )
+        .await?;
+
+        assert_eq!(
+            crate::channels::get_users(&test_state_sender, DEFAULT_ROOM)
+                .await?
+                .len(),
+            1,
+        );
+        assert_eq!(
+            crate::channels::resume_session(&test_state_sender, "test_token", None).await?,
+            None,
+        );
+
+        crate::channels::remove_user(&test_state_sender, "test_subject", &test_second_sender)
+            .await?;
+
+        assert!(crate::channels::get_users(&test_state_sender, DEFAULT_ROOM)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn responses() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let test_task = tokio::spawn(async move { test_state.run().await });
+
+        let test_message = MessageRecord {
+            room: String::from("test_closed_room"),
+            ..MessageRecord::default()
+        };
+
+        crate::channels::add_message(&test_state_sender, &test_message).await?;
+        crate::channels::leave_room(&test_state_sender, "test_uuid", "test_closed_room").await?;
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        crate::channels::remove_user(&test_state_sender, "test_uuid", &test_websocket_sender)
+            .await?;
+
+        assert_eq!(
+            crate::channels::resume_session(&test_state_sender, "test_token", None).await?,
+            None,
+        );
+        assert_eq!(
+            crate::channels::join_room(&test_state_sender, "test_uuid", "test_room").await,
+            Err(RelayError::UserNotFound(String::from("test_uuid"))),
+        );
+        assert!(
+            crate::channels::get_history(&test_state_sender, "test_closed_room", None, 10)
+                .await?
+                .0
+                .is_empty()
+        );
+
+        crate::channels::shutdown(&test_state_sender).await?;
+
+        assert!(test_task.await?.is_ok());
+        assert_eq!(
+            crate::channels::get_users(&test_state_sender, DEFAULT_ROOM)
+                .await
+                .unwrap_err(),
+            RelayError::StateChannelClosed,
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn broadcast() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let mut test_receivers = HashMap::with_capacity(4);
+
+        for (test_uuid, test_room, test_capacity) in [
+            ("test_uuid_one", "test_room", 16),
+            ("test_uuid_two", "test_room", 16),
+            ("test_uuid_lobby", DEFAULT_ROOM, 16),
+            ("test_uuid_full", DEFAULT_ROOM, 1),
+        ] {
+            let (test_websocket_sender, test_websocket_receiver) =
+                outbound(test_capacity, SlowConsumer::DropOldest).await;
+
+            test_state
+                .add_user(test_uuid.to_owned(), test_websocket_sender)
+                .await?;
+            test_state
+                .join_room(test_uuid.to_owned(), test_room.to_owned())
+                .await?;
+            test_receivers.insert(test_uuid, test_websocket_receiver);
+        }
+
+        let (test_closed_sender, test_closed_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        drop(test_closed_receiver);
+
+        test_state
+            .add_user(String::from("test_uuid_closed"), test_closed_sender)
+            .await?;
+        test_state
+            .join_room(String::from("test_uuid_closed"), String::from("test_room"))
+            .await?;
+
+        for test_receiver in test_receivers.values_mut() {
+            while let Ok(Some(_)) =
+                tokio::time::timeout(Duration::from_millis(10), test_receiver.recv()).await
+            {
+            }
+        }
+
+        let test_message = Arc::new(Message::text("test_message"));
+
+        assert_eq!(
+            test_state
+                .broadcast(&Audience::Room(String::from("test_room")), &test_message)
+                .await?,
+            vec![String::from("test_room")],
+        );
+        assert!(!test_state.users.contains_key("test_uuid_closed"));
+
+        test_state
+            .broadcast(
+                &Audience::RoomExcept((String::from("test_room"), String::from("test_uuid_one"))),
+                &test_message,
+            )
+            .await?;
+        test_state
+            .broadcast(&Audience::Everyone, &test_message)
+            .await?;
+        test_state
+            .broadcast(&Audience::Everyone, &test_message)
+            .await?;
+
+        for (test_uuid, test_expected) in [
+            (
+                "test_uuid_one",
+                vec!["test_message", "user_left", "test_message", "test_message"],
+            ),
+            (
+                "test_uuid_two",
+                vec![
+                    "test_message",
+                    "user_left",
+                    "test_message",
+                    "test_message",
+                    "test_message",
+                ],
+            ),
+            ("test_uuid_lobby", vec!["test_message", "test_message"]),
+            ("test_uuid_full", vec!["test_message"]),
+        ] {
+            let test_receiver = test_receivers.get_mut(test_uuid).expect("receiver");
+
+            for test_contents in test_expected {
+                match test_receiver.recv().await {
+                    Some(WebSocketConnection::SendMessage(test_received)) => {
+                        assert!(test_received
+                            .to_str()
+                            .is_ok_and(|test_text| test_text.contains(test_contents)));
+                    }
+                    test_frame => panic!("expected message -> {:?}", test_frame),
+                }
+            }
+
+            assert!(
+                tokio::time::timeout(Duration::from_millis(10), test_receiver.recv())
+                    .await
+                    .is_err()
+            );
+        }
+
+        assert_eq!(test_state.users["test_uuid_full"].dropped().await, 1);
+
+        Ok(())
+    }
+
+    /// Compares the path broadcasts used to take, where a session cloned the room's senders out
+    /// of state and awaited a bounded `mpsc` send of its own copy of the message to each of them
+    /// in turn, against `State::broadcast`. Queues hold every frame timed, so that only presence
+    /// from setting the room up is dropped, and both paths must deliver all of them. Run with `cargo test --release fan_out -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn fan_out() -> Result<(), Box<dyn std::error::Error>> {
+        let test_frames = 100;
+
+        for test_connections in [1_000, 10_000] {
+            let (test_state_sender, test_state_receiver) =
+                mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+            drop(test_state_sender);
+
+            let mut test_state = State::init(
+                test_state_receiver,
+                &Config::default(),
+                Box::new(MemoryStore::init().await),
+                Arc::new(Metrics::default()),
+            )
+            .await?;
+            let mut test_sinks = HashMap::with_capacity(test_connections);
+            let mut test_receivers = Vec::with_capacity(test_connections);
+            let mut test_tasks = Vec::with_capacity(test_connections * 2);
+
+            for test_connection in 0..test_connections {
+                let test_uuid = test_connection.to_string();
+                let (test_sink_sender, mut test_sink_receiver) =
+                    mpsc::channel::<Message>(test_frames);
+                let (test_websocket_sender, test_websocket_receiver) =
+                    outbound(test_frames, SlowConsumer::DropOldest).await;
+
+                test_sinks.insert(test_uuid.to_owned(), test_sink_sender);
+                test_state
+                    .add_user(test_uuid.to_owned(), test_websocket_sender)
+                    .await?;
+                test_state
+                    .join_room(test_uuid, DEFAULT_ROOM.to_owned())
+                    .await?;
+                test_tasks.push(tokio::spawn(async move {
+                    let mut test_received = 0;
+
+                    while test_sink_receiver.recv().await.is_some() {
+                        test_received += 1;
+                    }
+
+                    test_received
+                }));
+                test_receivers.push(test_websocket_receiver);
+            }
+
+            for mut test_websocket_receiver in test_receivers {
+                test_tasks.push(tokio::spawn(async move {
+                    let mut test_received = 0;
+
+                    while let Some(test_frame) = test_websocket_receiver.recv().await {
+                        if let WebSocketConnection::SendMessage(test_message) = test_frame {
+                            test_received += usize::from(test_message.as_bytes().len() == 256);
+                        }
+                    }
+
+                    test_received
+                }));
+            }
+
+            let test_message = Message::text("x".repeat(256));
+            let test_started = std::time::Instant::now();
+
+            for _ in 0..test_frames {
+                let test_users = test_sinks.clone();
+
+                for test_user in test_users.values() {
+                    test_user.send(test_message.to_owned()).await?;
+                }
+            }
+
+            let test_sequential = test_started.elapsed();
+            let test_shared = Arc::new(test_message);
+            let test_started = std::time::Instant::now();
+
+            for _ in 0..test_frames {
+                test_state
+                    .broadcast(&Audience::Room(DEFAULT_ROOM.to_owned()), &test_shared)
+                    .await?;
+            }
+
+            let test_concurrent = test_started.elapsed();
+
+            test_sinks.clear();
+            test_state.users.clear();
+
+            for test_task in test_tasks {
+                assert_eq!(test_task.await?, test_frames);
+            }
+
+            println!(
+                "{} connections, {} frames -> awaited mpsc {:?}, state broadcast {:?}",
+                test_connections, test_frames, test_sequential, test_concurrent,
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn presence() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let (test_watcher_sender, mut test_watcher_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+        let (test_visitor_sender, _test_visitor_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        test_state
+            .add_user(String::from("test_watcher"), test_watcher_sender)
+            .await?;
+        test_state
+            .add_user(String::from("test_visitor_uuid"), test_visitor_sender)
+            .await?;
+        test_state
+            .join_room(String::from("test_watcher"), String::from("test_room"))
+            .await?;
+
+        assert!(
+            test_state
+                .set_nickname(String::from("test_watcher"), "Test_Watcher")
+                .await
+        );
+
+        test_state
+            .join_room(String::from("test_visitor_uuid"), String::from("test_room"))
+            .await?;
+
+        assert_eq!(
+            test_state.get_presence("test_room").await,
+            vec![
+                Presence {
+                    uuid: String::from("test_watcher"),
+                    nickname: String::from("Test_Watcher"),
+                },
+                Presence {
+                    uuid: String::from("test_visitor_uuid"),
+                    nickname: String::from("guest-test_vis"),
+                },
+            ],
+        );
+
+        test_state.remove_user("test_visitor_uuid").await?;
+
+        for test_expected in ["user_joined", "user_left"] {
+            let test_frame = match test_watcher_receiver.recv().await {
+                Some(WebSocketConnection::SendMessage(test_message)) => test_message,
+                test_frame => panic!("expected message -> {:?}", test_frame),
+            };
+            let test_event: crate::json::Envelope<ServerFrame> =
+                serde_json::from_str(test_frame.to_str().expect("text"))?;
+
+            match (test_expected, test_event.frame) {
+                ("user_joined", ServerFrame::UserJoined(test_event))
+                | ("user_left", ServerFrame::UserLeft(test_event)) => {
+                    assert_eq!(test_event.room, "test_room");
+                    assert_eq!(test_event.uuid, "test_visitor_uuid");
+                    assert_eq!(test_event.nickname, "guest-test_vis");
+                    assert!(test_event.timestamp > 0);
+                }
+                test_frame => panic!("expected {} -> {:?}", test_expected, test_frame),
+            }
+        }
+
+        assert_eq!(test_state.get_presence("test_room").await.len(), 1);
+
+        test_state.remove_user("test_watcher").await?;
+
+        assert!(test_state.names.is_empty());
+
+        Ok(())
+    }
+
+    /// Keeps nothing, and fails every append once `failing` is set.
+    struct FailingStore {
+        failing: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl MessageStore for FailingStore {
+        fn load(&mut self) -> Result<Vec<MessageRecord>, Box<dyn std::error::Error>> {
+            Ok(Vec::new())
+        }
+
+        fn sequences(&mut self) -> Result<Vec<SequenceMark>, Box<dyn std::error::Error>> {
+            Ok(Vec::new())
+        }
+
+        fn append(&mut self, _record: &MessageRecord) -> Result<(), Box<dyn std::error::Error>> {
+            match self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                true => Err("test_disk_full".into()),
+                false => Ok(()),
+            }
+        }
+
+        fn compact(
+            &mut self,
+            _records: &[MessageRecord],
+            _sequences: &[SequenceMark],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn store_failure() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let test_failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(FailingStore {
+                failing: Arc::clone(&test_failing),
+            }),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+        let (test_sink, mut test_receiver) = outbound(16, SlowConsumer::DropOldest).await;
+
+        test_state
+            .add_user(String::from("test_recipient"), test_sink)
+            .await?;
+
+        let test_stored = test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                uuid: String::from("test_uuid"),
+                contents: String::from("test_stored"),
+                ..MessageRecord::default()
+            })
+            .await?
+            .expect("accepted");
+
+        test_failing.store(true, std::sync::atomic::Ordering::SeqCst);
+
+        assert!(matches!(
+            test_state
+                .add_message(MessageRecord {
+                    room: DEFAULT_ROOM.to_owned(),
+                    uuid: String::from("test_uuid"),
+                    contents: String::from("test_lost"),
+                    ..MessageRecord::default()
+                })
+                .await,
+            Err(RelayError::Storage(_)),
+        ));
+        assert!(matches!(
+            test_state
+                .amend_message(
+                    "test_uuid",
+                    &test_stored.id,
+                    Some(String::from("test_edit"))
+                )
+                .await,
+            Err(RelayError::Storage(_)),
+        ));
+        assert!(matches!(
+            test_state
+                .direct_message(MessageRecord {
+                    uuid: String::from("test_uuid"),
+                    contents: String::from("test_lost"),
+                    recipient: Some(String::from("test_recipient")),
+                    ..MessageRecord::default()
+                })
+                .await,
+            Err(RelayError::Storage(_)),
+        ));
+        assert_eq!(
+            test_state.get_messages(DEFAULT_ROOM).await?,
+            vec![test_stored.to_owned()],
+        );
+        assert!(test_state
+            .get_conversation("test_recipient", "test_uuid")
+            .await?
+            .is_empty());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), test_receiver.recv())
+                .await
+                .is_err()
+        );
+
+        test_failing.store(false, std::sync::atomic::Ordering::SeqCst);
+
+        let test_next = test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                uuid: String::from("test_uuid"),
+                contents: String::from("test_next"),
+                ..MessageRecord::default()
+            })
+            .await?
+            .expect("accepted");
+
+        assert_eq!(test_next.sequence, test_stored.sequence + 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn direct_message() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let mut test_receivers = Vec::with_capacity(3);
+
+        for test_uuid in ["test_sender", "test_recipient", "test_bystander"] {
+            let (test_sink, test_receiver) = outbound(16, SlowConsumer::DropOldest).await;
+
+            test_state.add_user(test_uuid.to_owned(), test_sink).await?;
+            test_state
+                .join_room(test_uuid.to_owned(), String::from("test_room"))
+                .await?;
+            test_receivers.push(test_receiver);
+        }
+
+        assert!(
+            test_state
+                .set_nickname(String::from("test_recipient"), "Test_Recipient")
+                .await
+        );
+
+        for test_receiver in test_receivers.iter_mut() {
+            while tokio::time::timeout(Duration::from_millis(10), test_receiver.recv())
+                .await
+                .is_ok()
+            {}
+        }
+
+        let test_message = MessageRecord {
+            room: String::new(),
+            uuid: String::from("test_sender"),
+            display_name: String::from("guest-test_sen"),
+            contents: String::from("test_contents"),
+            recipient: Some(String::from("test_recipient")),
+            ..MessageRecord::default()
+        };
+
+        test_state.direct_message(test_message.to_owned()).await?;
+        test_state
+            .direct_message(MessageRecord {
+                recipient: Some(String::from("test_RECIPIENT")),
+                ..test_message.to_owned()
+            })
+            .await?;
+
+        for test_receiver in test_receivers.iter_mut().take(2) {
+            for _ in 0..2 {
+                let test_frame = match test_receiver.recv().await {
+                    Some(WebSocketConnection::SendMessage(test_message)) => test_message,
+                    test_frame => panic!("expected message -> {:?}", test_frame),
+                };
+                let test_envelope: crate::json::Envelope<ServerFrame> =
+                    serde_json::from_str(test_frame.to_str().expect("text"))?;
+
+                match test_envelope.frame {
+                    ServerFrame::DirectMessage(test_record) => {
+                        assert_eq!(test_record.uuid, "test_sender");
+                        assert_eq!(test_record.recipient.as_deref(), Some("test_recipient"));
+                        assert_eq!(test_record.contents, "test_contents");
+                    }
+                    test_frame => panic!("expected direct message -> {:?}", test_frame),
                 }
             }
         }
 
-        Ok(())
-    }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), test_receivers[2].recv())
+                .await
+                .is_err()
+        );
+
+        assert_eq!(
+            test_state
+                .direct_message(MessageRecord {
+                    recipient: Some(String::from("test_unknown")),
+                    ..test_message.to_owned()
+                })
+                .await,
+            Err(RelayError::UserNotFound(String::from("test_unknown"))),
+        );
+
+        let test_conversation = test_state
+            .get_conversation("test_recipient", "test_sender")
+            .await?;
 
-    async fn add_message(&mut self, message: Message) -> Result<(), Box<dyn std::error::Error>> {
-        self.messages.push(message);
+        assert_eq!(test_conversation.len(), 2);
+        assert_eq!(test_conversation[0].sequence, 1);
+        assert_eq!(test_conversation[1].sequence, 2);
+        assert_ne!(test_conversation[0].id, test_conversation[1].id);
+        assert_eq!(
+            test_state
+                .get_conversation("test_sender", "test_recipient")
+                .await?,
+            test_conversation,
+        );
+        assert_eq!(
+            test_state
+                .get_conversation("test_sender", "test_recipient")
+                .await?,
+            test_state
+                .get_conversation("test_sender", "Test_Recipient")
+                .await?,
+        );
+        assert!(test_state
+            .get_conversation("test_bystander", "test_sender")
+            .await?
+            .is_empty());
+        assert!(test_state.get_messages("test_room").await?.is_empty());
+
+        drop(test_receivers.remove(1));
+
+        assert_eq!(
+            test_state.direct_message(test_message.to_owned()).await?,
+            vec![String::from("test_room")],
+        );
+        assert!(!test_state.users.contains_key("test_recipient"));
+        assert_eq!(test_state.rooms["test_room"].members.len(), 2);
 
         Ok(())
     }
 
-    async fn add_user(
-        &mut self,
-        uuid: String,
-        websocket_sender: WebSocketSender,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        match self.users.insert(uuid, websocket_sender) {
-            Some(key) => {
-                info!("updating user -> {:?}", key);
-            }
-            None => {
-                info!("adding new user...");
+    #[tokio::test(flavor = "multi_thread")]
+    async fn typing() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_config = Config {
+            typing_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &test_config,
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let (test_watcher_sender, mut test_watcher_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+        let (test_typist_sender, _test_typist_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        test_state
+            .add_user(String::from("test_typist"), test_typist_sender)
+            .await?;
+        test_state
+            .join_room(String::from("test_typist"), String::from("test_room"))
+            .await?;
+        test_state
+            .add_user(String::from("test_watcher"), test_watcher_sender)
+            .await?;
+        test_state
+            .join_room(String::from("test_watcher"), String::from("test_room"))
+            .await?;
+
+        test_state
+            .typing(String::from("test_typist"), "test_room")
+            .await?;
+        test_state
+            .typing(String::from("test_typist"), "test_room")
+            .await?;
+        test_state
+            .add_message(MessageRecord {
+                room: String::from("test_room"),
+                uuid: String::from("test_typist"),
+                contents: String::from("test_message"),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })
+            .await?;
+        test_state
+            .typing(String::from("test_typist"), "test_room")
+            .await?;
+
+        assert_eq!(test_state.rooms["test_room"].messages.len(), 1);
+        assert_eq!(test_state.rooms["test_room"].typing.len(), 1);
+
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+        let test_run = tokio::spawn(async move { test_state.run().await });
+
+        for test_expected in [
+            "\"typing\"",
+            "\"typing_stopped\"",
+            "\"typing\"",
+            "\"typing_stopped\"",
+        ] {
+            let test_frame =
+                tokio::time::timeout(Duration::from_secs(1), test_watcher_receiver.recv()).await?;
+
+            match test_frame {
+                Some(WebSocketConnection::SendMessage(test_message)) => {
+                    assert!(test_message
+                        .to_str()
+                        .is_ok_and(|test_text| test_text.contains(test_expected)));
+                }
+                test_frame => panic!("expected {} -> {:?}", test_expected, test_frame),
             }
         }
 
+        crate::channels::shutdown(&test_state_sender).await?;
+
+        assert!(test_run.await?.is_ok());
+
         Ok(())
     }
 
-    async fn get_messages(&self) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
-        info!("getting messages...");
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reap_users() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
 
-        Ok(self.messages.to_vec())
-    }
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
 
-    async fn get_users(&self) -> ConnectedUsers {
-        self.users.clone()
-    }
+        let (test_open_sender, _test_open_receiver) = outbound(16, SlowConsumer::DropOldest).await;
+        let (test_closed_sender, test_closed_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
 
-    async fn remove_user(&mut self, uuid: &str) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(entry) = self.users.remove(uuid) {
-            info!("removing user -> {:?}", entry);
+        drop(test_closed_receiver);
+
+        test_state
+            .add_user(String::from("test_open_uuid"), test_open_sender)
+            .await?;
+        test_state
+            .add_user(String::from("test_closed_uuid"), test_closed_sender)
+            .await?;
+
+        for test_uuid in ["test_open_uuid", "test_closed_uuid"] {
+            test_state
+                .join_room(test_uuid.to_owned(), String::from("test_room"))
+                .await?;
         }
 
+        assert!(
+            test_state
+                .set_nickname(String::from("test_closed_uuid"), "test_nickname")
+                .await
+        );
+        assert_eq!(
+            test_state.reap_users().await?,
+            vec![String::from("test_room")],
+        );
+        assert_eq!(test_state.users.len(), 1);
+        assert!(test_state.users.contains_key("test_open_uuid"));
+        assert_eq!(test_state.get_users("test_room").await.len(), 1);
+        assert!(
+            !test_state
+                .set_nickname(String::from("test_open_uuid"), "test_nickname")
+                .await
+        );
+        assert!(test_state.reap_users().await?.is_empty());
+
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::str::FromStr;
-    use tokio::sync::{mpsc, oneshot};
-    use uuid::Uuid;
+    #[tokio::test(flavor = "multi_thread")]
+    async fn set_nickname() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let test_other_uuid = uuid::Uuid::new_v4().to_string();
+
+        assert!(
+            test_state
+                .set_nickname(test_uuid.to_owned(), "test_nickname")
+                .await
+        );
+        assert!(
+            test_state
+                .set_nickname(test_uuid.to_owned(), "test_nickname")
+                .await
+        );
+        assert!(
+            !test_state
+                .set_nickname(test_other_uuid.to_owned(), "TEST_NICKNAME")
+                .await
+        );
+
+        assert!(
+            test_state
+                .set_nickname(test_uuid.to_owned(), "test_renamed")
+                .await
+        );
+        assert_eq!(test_state.nicknames.len(), 1);
+        assert!(
+            test_state
+                .set_nickname(test_other_uuid.to_owned(), "test_nickname")
+                .await
+        );
+
+        test_state.remove_user(&test_other_uuid).await?;
+
+        assert_eq!(test_state.nicknames.len(), 1);
+        assert_eq!(test_state.nicknames["test_renamed"], test_uuid);
+
+        Ok(())
+    }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn init() -> Result<(), Box<dyn std::error::Error>> {
+    async fn suspend_session() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
 
         drop(test_state_sender);
 
-        let test_state = State::init(test_state_receiver).await;
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        test_state
+            .add_user(test_uuid.to_owned(), test_websocket_sender)
+            .await?;
+        test_state
+            .join_room(test_uuid.to_owned(), String::from("test_room"))
+            .await?;
+
+        assert!(
+            test_state
+                .set_nickname(test_uuid.to_owned(), "test_nickname")
+                .await
+        );
+
+        let test_suspended = SuspendedSession {
+            uuid: test_uuid.to_owned(),
+            room: String::from("test_room"),
+            suspended_at: timestamp().await,
+            sequence: 0,
+        };
 
-        assert!(test_state.messages.is_empty());
-        assert_eq!(test_state.messages.capacity(), 100);
+        test_state
+            .suspend_session(String::from("test_token"), test_suspended.to_owned())
+            .await?;
 
         assert!(test_state.users.is_empty());
-        assert!(test_state.users.capacity() >= 10);
+        assert!(!test_state.rooms.contains_key("test_room"));
+        assert!(
+            !test_state
+                .set_nickname(String::from("test_other_uuid"), "test_nickname")
+                .await
+        );
+
+        assert!(test_state
+            .resume_session("test_token", Some("test_other_uuid"))
+            .await
+            .is_none());
+        assert!(test_state
+            .resume_session("test_unknown_token", None)
+            .await
+            .is_none());
+        assert_eq!(
+            test_state.resume_session("test_token", None).await,
+            Some(test_suspended.to_owned()),
+        );
+        assert!(test_state
+            .resume_session("test_token", None)
+            .await
+            .is_none());
+
+        test_state
+            .suspend_session(
+                String::from("test_expired_token"),
+                SuspendedSession {
+                    suspended_at: test_suspended.suspended_at - 121_000,
+                    ..test_suspended
+                },
+            )
+            .await?;
+
+        assert!(test_state
+            .resume_session("test_expired_token", None)
+            .await
+            .is_none());
+        assert!(
+            test_state
+                .set_nickname(String::from("test_other_uuid"), "test_nickname")
+                .await
+        );
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn add_message() -> Result<(), Box<dyn std::error::Error>> {
+    async fn join_room() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
 
         drop(test_state_sender);
 
-        let mut test_state = State::init(test_state_receiver).await;
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let test_uuid = uuid::Uuid::new_v4().to_string();
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        assert_eq!(
+            test_state
+                .join_room(test_uuid.to_owned(), DEFAULT_ROOM.to_owned())
+                .await,
+            Err(RelayError::UserNotFound(test_uuid.to_owned())),
+        );
+
+        test_state
+            .add_user(test_uuid.to_owned(), test_websocket_sender)
+            .await?;
 
-        assert!(test_state.messages.is_empty());
-        assert_eq!(test_state.messages.len(), 0);
+        test_state
+            .join_room(test_uuid.to_owned(), DEFAULT_ROOM.to_owned())
+            .await?;
 
-        let test_message = Message::text("test_message");
+        assert_eq!(test_state.rooms.len(), 1);
+        assert!(test_state.rooms[DEFAULT_ROOM].members.contains(&test_uuid));
 
-        test_state.add_message(test_message).await?;
+        test_state
+            .join_room(test_uuid.to_owned(), String::from("test_room"))
+            .await?;
 
-        assert!(!test_state.messages.is_empty());
-        assert_eq!(test_state.messages.len(), 1);
+        assert_eq!(test_state.rooms.len(), 2);
+        assert!(test_state.rooms["test_room"].members.contains(&test_uuid));
+        assert!(test_state.rooms["test_room"].messages.capacity() >= 100);
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn add_user() -> Result<(), Box<dyn std::error::Error>> {
+    async fn leave_room() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
 
         drop(test_state_sender);
 
-        let mut test_state = State::init(test_state_receiver).await;
-
-        assert!(test_state.users.is_empty());
-        assert_eq!(test_state.users.len(), 0);
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
 
         let test_uuid = uuid::Uuid::new_v4().to_string();
-        let (test_websocket_sender, test_websocket_receiver) = mpsc::channel(16);
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
 
-        drop(test_websocket_receiver);
+        test_state
+            .add_user(test_uuid.to_owned(), test_websocket_sender)
+            .await?;
 
         test_state
-            .add_user(test_uuid, test_websocket_sender)
+            .join_room(test_uuid.to_owned(), DEFAULT_ROOM.to_owned())
+            .await?;
+        test_state
+            .join_room(test_uuid.to_owned(), String::from("test_room"))
+            .await?;
+        test_state
+            .join_room(test_uuid.to_owned(), String::from("test_history_room"))
+            .await?;
+        test_state
+            .add_message(MessageRecord {
+                room: String::from("test_history_room"),
+                contents: "test_message".to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            })
             .await?;
 
-        assert!(!test_state.users.is_empty());
-        assert_eq!(test_state.users.len(), 1);
+        assert_eq!(test_state.rooms.len(), 3);
+
+        test_state.leave_room(&test_uuid, DEFAULT_ROOM).await?;
+        test_state.leave_room(&test_uuid, "test_room").await?;
+        test_state
+            .leave_room(&test_uuid, "test_history_room")
+            .await?;
+
+        assert_eq!(test_state.rooms.len(), 2);
+        assert!(test_state.rooms[DEFAULT_ROOM].members.is_empty());
+        assert!(!test_state.rooms.contains_key("test_room"));
+        assert!(test_state.rooms["test_history_room"].members.is_empty());
+        assert_eq!(test_state.rooms["test_history_room"].messages.len(), 1);
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn get_messages() -> Result<(), Box<dyn std::error::Error>> {
+    async fn retention() -> Result<(), Box<dyn std::error::Error>> {
+        let test_metrics = Metrics::default();
+        let mut test_room = Room::init(100).await;
+
+        for test_contents in ["one", "two", "three", "four"] {
+            test_room
+                .push(MessageRecord {
+                    room: String::from("test_room"),
+                    contents: test_contents.to_owned(),
+                    timestamp: timestamp().await,
+                    ..MessageRecord::default()
+                })
+                .await;
+        }
+
+        assert_eq!(test_room.bytes, 15);
+        assert_eq!(
+            test_room.evict(&Retention::default(), &test_metrics).await,
+            0
+        );
+
+        let test_count = Retention {
+            max_messages: Some(3),
+            ..Retention::default()
+        };
+
+        assert_eq!(test_room.evict(&test_count, &test_metrics).await, 1);
+        assert_eq!(test_room.messages.len(), 3);
+        assert_eq!(test_room.messages[0].contents, "two");
+        assert_eq!(test_room.bytes, 12);
+
+        let test_bytes = Retention {
+            max_bytes: Some(9),
+            ..Retention::default()
+        };
+
+        assert_eq!(test_room.evict(&test_bytes, &test_metrics).await, 1);
+        assert_eq!(test_room.messages.len(), 2);
+        assert_eq!(test_room.messages[0].contents, "three");
+        assert_eq!(test_room.bytes, 9);
+
+        test_room.messages[0].timestamp -= 120_000;
+
+        let test_age = Retention {
+            max_age: Some(Duration::from_secs(60)),
+            ..Retention::default()
+        };
+
+        assert_eq!(test_room.evict(&test_age, &test_metrics).await, 1);
+        assert_eq!(test_room.messages.len(), 1);
+        assert_eq!(test_room.messages[0].contents, "four");
+        assert_eq!(test_room.bytes, 4);
+
+        assert_eq!(test_metrics.evicted_total(Eviction::Count).await, 1);
+        assert_eq!(test_metrics.evicted_total(Eviction::Bytes).await, 1);
+        assert_eq!(test_metrics.evicted_total(Eviction::Age).await, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn amend_message() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
 
         drop(test_state_sender);
 
-        let mut test_state = State::init(test_state_receiver).await;
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(FileStore::init(&test_path).await?),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let mut test_ids = Vec::with_capacity(2);
+
+        for test_contents in ["test_message_one", "test_message_two"] {
+            let test_accepted = test_state
+                .add_message(MessageRecord {
+                    room: DEFAULT_ROOM.to_owned(),
+                    uuid: String::from("test_author"),
+                    contents: test_contents.to_owned(),
+                    ..MessageRecord::default()
+                })
+                .await?
+                .expect("accepted");
+
+            test_ids.push(test_accepted.id);
+        }
+
+        assert!(matches!(
+            test_state
+                .amend_message("test_other", &test_ids[0], Some(String::from("test_edit")))
+                .await?,
+            StateResponse::NotAuthor,
+        ));
+        assert!(matches!(
+            test_state
+                .amend_message("test_author", "test_unknown_id", None)
+                .await?,
+            StateResponse::NotFound,
+        ));
+
+        let test_edited = match test_state
+            .amend_message("test_author", &test_ids[0], Some(String::from("test_edit")))
+            .await?
+        {
+            StateResponse::Message(test_message) => test_message,
+            test_response => panic!("expected message -> {:?}", test_response),
+        };
+
+        assert_eq!(test_edited.contents, "test_edit");
+        assert_eq!(test_edited.sequence, 1);
+        assert!(test_edited.edited_at.is_some());
+
+        match test_state
+            .amend_message("test_author", &test_ids[1], None)
+            .await?
+        {
+            StateResponse::Message(test_message) => {
+                assert!(test_message.deleted);
+                assert!(test_message.contents.is_empty());
+            }
+            test_response => panic!("expected message -> {:?}", test_response),
+        }
+
+        assert!(matches!(
+            test_state
+                .amend_message("test_author", &test_ids[1], Some(String::from("test_edit")))
+                .await?,
+            StateResponse::NotFound,
+        ));
+        assert_eq!(test_state.rooms[DEFAULT_ROOM].bytes, "test_edit".len(),);
+
+        let test_messages = test_state.get_messages(DEFAULT_ROOM).await?;
 
-        let test_message_one = Message::text("test_message_one");
-        let test_message_two = Message::text("test_message_two");
-        let test_message_three = Message::text("test_message_three");
+        drop(test_state);
 
-        test_state.add_message(test_message_one).await?;
-        test_state.add_message(test_message_two).await?;
-        test_state.add_message(test_message_three).await?;
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
 
-        let test_messages = test_state.get_messages().await?;
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(FileStore::init(&test_path).await?),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
 
-        assert_eq!(test_messages[0].to_str().unwrap(), "test_message_one");
-        assert_eq!(test_messages[1].to_str().unwrap(), "test_message_two");
-        assert_eq!(test_messages[2].to_str().unwrap(), "test_message_three");
+        assert_eq!(test_messages.len(), 2);
+        assert_eq!(test_messages[0], test_edited);
+        assert!(test_messages[1].deleted);
+        assert_eq!(test_state.get_messages(DEFAULT_ROOM).await?, test_messages);
+
+        std::fs::remove_file(&test_path)?;
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn get_users() -> Result<(), Box<dyn std::error::Error>> {
+    async fn search() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
 
         drop(test_state_sender);
 
-        let mut test_state = State::init(test_state_receiver).await;
+        let test_config = Config {
+            retention_max_messages: Some(3),
+            ..Config::default()
+        };
+        let mut test_state = State::init(
+            test_state_receiver,
+            &test_config,
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let mut test_ids = Vec::with_capacity(4);
+
+        for test_contents in ["fox one", "the fox two fox", "dog three", "fox four"] {
+            let test_accepted = test_state
+                .add_message(MessageRecord {
+                    room: DEFAULT_ROOM.to_owned(),
+                    uuid: String::from("test_author"),
+                    contents: test_contents.to_owned(),
+                    ..MessageRecord::default()
+                })
+                .await?
+                .expect("accepted");
+
+            test_ids.push(test_accepted.id);
+        }
 
-        assert!(test_state.users.is_empty());
-        assert_eq!(test_state.users.len(), 0);
+        let test_page = test_state
+            .search(DEFAULT_ROOM.to_owned(), String::from("Fox"), 0, 10)
+            .await;
+
+        assert_eq!(test_page.room, DEFAULT_ROOM);
+        assert_eq!(test_page.query, "Fox");
+        assert_eq!(test_page.total, 2);
+        assert_eq!(test_page.results[0].message.id, test_ids[1]);
+        assert_eq!(
+            test_page.results[0].snippet,
+            "the <mark>fox</mark> two <mark>fox</mark>",
+        );
+        assert_eq!(test_page.results[1].message.id, test_ids[3]);
+        assert!(test_page.results[0].score > test_page.results[1].score);
+
+        let test_page = test_state
+            .search(DEFAULT_ROOM.to_owned(), String::from("fox"), 1, 1)
+            .await;
+
+        assert_eq!(test_page.offset, 1);
+        assert_eq!(test_page.total, 2);
+        assert_eq!(test_page.results.len(), 1);
+        assert_eq!(test_page.results[0].message.id, test_ids[3]);
 
-        let test_uuid = uuid::Uuid::new_v4().to_string();
-        let (test_websocket_sender, _test_websocket_receiver) = mpsc::channel(16);
+        test_state
+            .amend_message("test_author", &test_ids[1], None)
+            .await?;
+        test_state
+            .amend_message("test_author", &test_ids[2], Some(String::from("a fox")))
+            .await?;
+
+        let test_page = test_state
+            .search(DEFAULT_ROOM.to_owned(), String::from("fox"), 0, 10)
+            .await;
+        let test_found: Vec<&str> = test_page
+            .results
+            .iter()
+            .map(|test_result| test_result.message.id.as_str())
+            .collect();
+
+        assert_eq!(test_page.total, 2);
+        assert_eq!(test_found, vec![test_ids[3].as_str(), test_ids[2].as_str()]);
+        assert_eq!(
+            test_state
+                .search(DEFAULT_ROOM.to_owned(), String::from("dog"), 0, 10)
+                .await
+                .total,
+            0,
+        );
+        assert!(test_state
+            .search(String::from("test_room"), String::from("fox"), 0, 10)
+            .await
+            .results
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn react() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let mut test_receivers = Vec::with_capacity(3);
+
+        for (test_uuid, test_room) in [
+            ("test_uuid_one", DEFAULT_ROOM),
+            ("test_uuid_two", DEFAULT_ROOM),
+            ("test_outsider", "test_room"),
+        ] {
+            let (test_websocket_sender, test_websocket_receiver) =
+                outbound(16, SlowConsumer::DropOldest).await;
+
+            test_state
+                .add_user(test_uuid.to_owned(), test_websocket_sender)
+                .await?;
+            test_state
+                .join_room(test_uuid.to_owned(), test_room.to_owned())
+                .await?;
+            test_receivers.push(test_websocket_receiver);
+        }
+
+        let test_id = test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                uuid: String::from("test_author"),
+                contents: String::from("test_message"),
+                ..MessageRecord::default()
+            })
+            .await?
+            .expect("accepted")
+            .id;
+
+        for (test_uuid, test_emoji, test_add, test_count) in [
+            ("test_uuid_one", "👍", true, Some(1)),
+            ("test_uuid_two", "👍", true, Some(2)),
+            ("test_uuid_two", "👍", true, None),
+            ("test_uuid_one", "🎉", true, Some(1)),
+            ("test_uuid_one", "👍", false, Some(1)),
+            ("test_uuid_one", "👍", false, None),
+            ("test_uuid_one", "🚀", false, None),
+        ] {
+            match test_state
+                .react(test_uuid, &test_id, test_emoji, test_add)
+                .await?
+            {
+                StateResponse::Reacted(test_event) => {
+                    assert_eq!(test_event.room, DEFAULT_ROOM);
+                    assert_eq!(test_event.id, test_id);
+                    assert_eq!(test_event.emoji, test_emoji);
+                    assert_eq!(test_event.uuid, test_uuid);
+                    assert_eq!(Some(test_event.count), test_count);
+                }
+                StateResponse::Ok => assert_eq!(test_count, None),
+                test_response => panic!("expected reaction -> {:?}", test_response),
+            }
+        }
+
+        assert!(matches!(
+            test_state
+                .react("test_uuid_one", "test_unknown_id", "👍", true)
+                .await?,
+            StateResponse::NotFound,
+        ));
+        assert!(matches!(
+            test_state
+                .react("test_outsider", &test_id, "👍", true)
+                .await?,
+            StateResponse::NotFound,
+        ));
+
+        let test_messages = test_state.get_messages(DEFAULT_ROOM).await?;
+
+        assert_eq!(
+            test_messages[0].reactions,
+            vec![
+                Reaction {
+                    emoji: String::from("👍"),
+                    count: 1,
+                    users: vec![String::from("test_uuid_two")],
+                },
+                Reaction {
+                    emoji: String::from("🎉"),
+                    count: 1,
+                    users: vec![String::from("test_uuid_one")],
+                },
+            ],
+        );
 
         test_state
-            .add_user(test_uuid, test_websocket_sender)
+            .react("test_uuid_two", &test_id, "👍", false)
             .await?;
 
-        let test_users = test_state.get_users().await;
+        assert_eq!(
+            test_state.get_messages(DEFAULT_ROOM).await?[0]
+                .reactions
+                .len(),
+            1,
+        );
 
-        assert!(!test_users.is_empty());
-        assert_eq!(test_users.len(), 1);
+        test_state
+            .amend_message("test_author", &test_id, None)
+            .await?;
+
+        assert!(test_state.get_messages(DEFAULT_ROOM).await?[0]
+            .reactions
+            .is_empty());
+        assert!(matches!(
+            test_state
+                .react("test_uuid_one", &test_id, "👍", true)
+                .await?,
+            StateResponse::NotFound,
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn threads() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+        let test_state_sender = StateSender::init(test_state_sender, Duration::from_secs(5)).await;
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &Config::default(),
+            Box::new(MemoryStore::init().await),
+            Arc::new(Metrics::default()),
+        )
+        .await?;
+
+        let (test_websocket_sender, _test_websocket_receiver) =
+            outbound(16, SlowConsumer::DropOldest).await;
+
+        test_state
+            .add_user(String::from("test_uuid"), test_websocket_sender)
+            .await?;
+        test_state
+            .join_room(String::from("test_uuid"), String::from("test_room"))
+            .await?;
+
+        let test_task = tokio::spawn(async move { test_state.run().await });
+
+        let test_message = MessageRecord {
+            room: DEFAULT_ROOM.to_owned(),
+            uuid: String::from("test_uuid"),
+            contents: String::from("test_message"),
+            ..MessageRecord::default()
+        };
+        let (test_root, test_replies) =
+            crate::channels::add_message(&test_state_sender, &test_message)
+                .await?
+                .expect("accepted");
+
+        assert_eq!(test_replies, None);
+
+        let (test_reply, test_replies) = crate::channels::add_message(
+            &test_state_sender,
+            &MessageRecord {
+                reply_to: Some(test_root.id.to_owned()),
+                ..test_message.to_owned()
+            },
+        )
+        .await?
+        .expect("accepted");
+
+        assert_eq!(test_reply.reply_to.as_deref(), Some(test_root.id.as_str()));
+        assert_eq!(test_replies, Some(1));
+
+        let (test_nested, test_replies) = crate::channels::add_message(
+            &test_state_sender,
+            &MessageRecord {
+                reply_to: Some(test_reply.id.to_owned()),
+                ..test_message.to_owned()
+            },
+        )
+        .await?
+        .expect("accepted");
+
+        assert_eq!(test_nested.reply_to.as_deref(), Some(test_root.id.as_str()));
+        assert_eq!(test_replies, Some(2));
+
+        crate::channels::add_message(&test_state_sender, &test_message).await?;
+
+        for test_reply_to in [String::from("test_unknown_id"), test_root.id.to_owned()] {
+            let test_room = match test_reply_to == test_root.id {
+                true => String::from("test_room"),
+                false => DEFAULT_ROOM.to_owned(),
+            };
 
-        for (test_uuid, test_websocket_connection) in test_users.iter() {
             assert_eq!(
-                Uuid::from_str(test_uuid)
-                    .expect("uuid from &str")
-                    .get_version_num(),
-                4
+                crate::channels::add_message(
+                    &test_state_sender,
+                    &MessageRecord {
+                        room: test_room,
+                        reply_to: Some(test_reply_to.to_owned()),
+                        ..test_message.to_owned()
+                    },
+                )
+                .await,
+                Err(RelayError::MessageNotFound(test_reply_to)),
             );
+        }
+
+        crate::channels::join_room(&test_state_sender, "test_uuid", DEFAULT_ROOM).await?;
+
+        let test_thread =
+            crate::channels::get_thread(&test_state_sender, "test_uuid", &test_nested.id).await?;
+
+        assert_eq!(test_thread.len(), 3);
+        assert_eq!(test_thread[0].id, test_root.id);
+        assert_eq!(test_thread[0].replies, 2);
+        assert_eq!(test_thread[1], test_reply);
+        assert_eq!(test_thread[2], test_nested);
+        assert_eq!(
+            crate::channels::get_thread(&test_state_sender, "test_uuid", &test_root.id).await?,
+            test_thread,
+        );
+        assert_eq!(
+            crate::channels::get_thread(&test_state_sender, "test_uuid", "test_unknown_id").await,
+            Err(RelayError::MessageNotFound(String::from("test_unknown_id"))),
+        );
+        assert_eq!(
+            crate::channels::get_thread(&test_state_sender, "test_outsider", &test_root.id).await,
+            Err(RelayError::MessageNotFound(test_root.id.to_owned())),
+        );
+        assert_eq!(
+            crate::channels::get_history(&test_state_sender, DEFAULT_ROOM, None, 10)
+                .await?
+                .0
+                .len(),
+            4,
+        );
+
+        crate::channels::shutdown(&test_state_sender).await?;
+
+        assert!(test_task.await?.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn add_message_retention() -> Result<(), Box<dyn std::error::Error>> {
+        let (test_state_sender, test_state_receiver) =
+            mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+        drop(test_state_sender);
 
-            assert!(!test_websocket_connection.is_closed());
-            assert_eq!(test_websocket_connection.capacity(), 16);
+        let test_config = Config {
+            retention_max_messages: Some(2),
+            ..Config::default()
+        };
+        let test_metrics = Arc::new(Metrics::default());
+
+        let mut test_state = State::init(
+            test_state_receiver,
+            &test_config,
+            Box::new(MemoryStore::init().await),
+            test_metrics.to_owned(),
+        )
+        .await?;
+
+        for test_contents in ["test_message_one", "test_message_two", "test_message_three"] {
+            test_state
+                .add_message(MessageRecord {
+                    room: DEFAULT_ROOM.to_owned(),
+                    contents: test_contents.to_owned(),
+                    timestamp: timestamp().await,
+                    ..MessageRecord::default()
+                })
+                .await?;
         }
 
+        let test_messages = test_state.get_messages(DEFAULT_ROOM).await?;
+
+        assert_eq!(test_messages.len(), 2);
+        assert_eq!(test_messages[0].contents, "test_message_two");
+        assert_eq!(test_messages[1].contents, "test_message_three");
+        assert_eq!(test_metrics.evicted_total(Eviction::Count).await, 1);
+
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn remove_user() -> Result<(), Box<dyn std::error::Error>> {
+    async fn init_applies_retention() -> Result<(), Box<dyn std::error::Error>> {
         let (test_state_sender, test_state_receiver) =
             mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
 
         drop(test_state_sender);
 
-        let mut test_state = State::init(test_state_receiver).await;
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let mut test_store = FileStore::init(&test_path).await?;
+
+        for (test_contents, test_timestamp) in [
+            ("test_message_expired", 0),
+            ("test_message_one", timestamp().await),
+            ("test_message_two", timestamp().await),
+        ] {
+            test_store.append(&MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                contents: test_contents.to_owned(),
+                timestamp: test_timestamp,
+                ..MessageRecord::default()
+            })?;
+        }
+
+        let test_config = Config {
+            retention_max_age: Some(Duration::from_secs(3600)),
+            ..Config::default()
+        };
+        let test_metrics = Arc::new(Metrics::default());
 
-        assert!(test_state.users.is_empty());
-        assert_eq!(test_state.users.len(), 0);
+        let mut test_state = State::init(
+            test_state_receiver,
+            &test_config,
+            Box::new(test_store),
+            test_metrics.to_owned(),
+        )
+        .await?;
 
-        let test_uuid = uuid::Uuid::new_v4().to_string();
-        let test_remove_user = test_uuid.clone();
-        let (test_websocket_sender, test_websocket_receiver) = mpsc::channel(16);
+        let test_messages = test_state.get_messages(DEFAULT_ROOM).await?;
 
-        drop(test_websocket_receiver);
+        assert_eq!(test_messages.len(), 2);
+        assert_eq!(test_messages[0].contents, "test_message_one");
+        assert_eq!(test_metrics.evicted_total(Eviction::Age).await, 1);
 
-        test_state
-            .add_user(test_uuid, test_websocket_sender)
+        let test_compacted = FileStore::init(&test_path).await?.load()?;
+
+        assert_eq!(test_compacted.len(), 2);
+        assert_eq!(test_compacted[0].contents, "test_message_one");
+
+        std::fs::remove_file(&test_path)?;
+        std::fs::remove_file(format!("{}.sequences", test_path.display()))?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compaction() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let test_init = |test_path: std::path::PathBuf| async move {
+            let (test_state_sender, test_state_receiver) =
+                mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+            drop(test_state_sender);
+
+            State::init(
+                test_state_receiver,
+                &Config::default(),
+                Box::new(FileStore::init(&test_path).await?),
+                Arc::new(Metrics::default()),
+            )
+            .await
+        };
+        let test_lines = |test_path: &std::path::Path| -> Result<usize, std::io::Error> {
+            Ok(std::fs::read_to_string(test_path)?.lines().count())
+        };
+
+        let mut test_state = test_init(test_path.to_owned()).await?;
+        let mut test_message = test_state
+            .add_message(MessageRecord {
+                room: DEFAULT_ROOM.to_owned(),
+                contents: String::from("test_contents"),
+                ..MessageRecord::default()
+            })
+            .await?
+            .expect("message");
+
+        for test_edit in 1..=COMPACTION_LINES * 3 {
+            test_message.contents = format!("test_contents_{}", test_edit);
+            test_state.update_message(test_message.to_owned()).await?;
+
+            assert!(test_lines(&test_path)? <= COMPACTION_LINES * 2);
+        }
+
+        drop(test_state);
+
+        test_message.contents = String::from("test_contents_final");
+
+        let mut test_store = FileStore::init(&test_path).await?;
+
+        test_store.append(&test_message)?;
+
+        assert!(test_lines(&test_path)? > 1);
+
+        let mut test_state = test_init(test_path.to_owned()).await?;
+        let test_messages = test_state.get_messages(DEFAULT_ROOM).await?;
+
+        assert_eq!(test_messages, vec![test_message]);
+        assert_eq!(test_lines(&test_path)?, 1);
+
+        std::fs::remove_file(&test_path)?;
+        std::fs::remove_file(format!("{}.sequences", test_path.display()))?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn init_keeps_sequences() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let mut test_store = FileStore::init(&test_path).await?;
+
+        for test_sequence in 1..=3 {
+            test_store.append(&MessageRecord {
+                room: String::from("test_room"),
+                sequence: test_sequence,
+                contents: String::from("test_expired"),
+                ..MessageRecord::default()
+            })?;
+            test_store.append(&MessageRecord {
+                uuid: String::from("test_sender"),
+                sequence: test_sequence,
+                contents: String::from("test_expired"),
+                recipient: Some(String::from("test_recipient")),
+                ..MessageRecord::default()
+            })?;
+        }
+
+        let test_config = Config {
+            retention_max_age: Some(Duration::from_secs(3600)),
+            ..Config::default()
+        };
+        let test_conversation = conversation_key("test_sender", "test_recipient").await;
+
+        for _ in 0..2 {
+            let (test_state_sender, test_state_receiver) =
+                mpsc::channel::<(StateRequest, oneshot::Sender<StateResponse>)>(64);
+
+            drop(test_state_sender);
+
+            let test_state = State::init(
+                test_state_receiver,
+                &test_config,
+                Box::new(FileStore::init(&test_path).await?),
+                Arc::new(Metrics::default()),
+            )
             .await?;
 
-        assert!(!test_state.users.is_empty());
-        assert_eq!(test_state.users.len(), 1);
+            assert!(test_state.rooms["test_room"].messages.is_empty());
+            assert_eq!(test_state.rooms["test_room"].sequence, 3);
+            assert!(test_state.conversations[&test_conversation]
+                .messages
+                .is_empty());
+            assert_eq!(test_state.conversations[&test_conversation].sequence, 3);
+        }
 
-        test_state.remove_user(&test_remove_user).await?;
+        assert!(FileStore::init(&test_path).await?.load()?.is_empty());
 
-        assert!(test_state.users.is_empty());
-        assert_eq!(test_state.users.len(), 0);
+        std::fs::remove_file(&test_path)?;
+        std::fs::remove_file(format!("{}.sequences", test_path.display()))?;
 
         Ok(())
     }
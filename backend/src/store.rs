@@ -0,0 +1,442 @@
+use serde::{Deserialize, Serialize};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, Storage};
+use crate::{error, info};
+
+/// A file sent along with a message, downloadable from `GET /attachments/<id>`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Attachment {
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+    pub size: usize,
+}
+
+/// Everyone who has reacted to a message with one emoji.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    /// Sessions that reacted, in the order they did
+    pub users: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct MessageRecord {
+    /// Unique id, assigned by state when the message is accepted
+    #[serde(default)]
+    pub id: String,
+    /// Room the message was sent in, empty for direct messages
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub room: String,
+    /// Position in the room or conversation, counting up from 1
+    #[serde(default)]
+    pub sequence: u64,
+    /// Session that sent the message
+    #[serde(default)]
+    pub uuid: String,
+    /// Nickname of the sender at the time, or a name derived from their uuid
+    #[serde(default)]
+    pub display_name: String,
+    pub contents: String,
+    /// Milliseconds since the unix epoch that the message was accepted at
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    /// Session a direct message was sent to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Milliseconds since the unix epoch that the author last edited the message at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+    /// Deleted by its author, leaving a tombstone without contents or attachment
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    /// Message this is a reply to, which is always the first message of its thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Replies in this message's thread
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub replies: usize,
+    /// Reactions in the order each emoji was first used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+}
+
+/// The latest sequence a room or conversation has handed out, kept through compaction so that
+/// numbering carries on even once its whole history has been evicted.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SequenceMark {
+    /// Room, empty for a conversation
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub room: String,
+    /// Both sides of a conversation, in order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<(String, String)>,
+    pub sequence: u64,
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+/// Milliseconds since the unix epoch.
+pub async fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Somewhere message history outlives the state task.
+///
+/// State keeps the working copy of history in memory - a store only has to replay what it was
+/// given, in order, when state starts up. Once retention has been applied to what was loaded,
+/// state hands back whatever is left to `compact` so that evicted messages are not replayed again,
+/// along with the sequence each room and conversation had reached.
+pub trait MessageStore: Send + Sync {
+    fn load(&mut self) -> Result<Vec<MessageRecord>, Box<dyn std::error::Error>>;
+    /// Sequences kept by the last compaction, which loaded records may since have passed.
+    fn sequences(&mut self) -> Result<Vec<SequenceMark>, Box<dyn std::error::Error>>;
+    fn append(&mut self, record: &MessageRecord) -> Result<(), Box<dyn std::error::Error>>;
+    fn compact(
+        &mut self,
+        records: &[MessageRecord],
+        sequences: &[SequenceMark],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Keeps nothing beyond what state already holds, so history is gone after a restart.
+pub struct MemoryStore;
+
+impl MemoryStore {
+    pub async fn init() -> MemoryStore {
+        MemoryStore
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn load(&mut self) -> Result<Vec<MessageRecord>, Box<dyn std::error::Error>> {
+        Ok(Vec::new())
+    }
+
+    fn sequences(&mut self) -> Result<Vec<SequenceMark>, Box<dyn std::error::Error>> {
+        Ok(Vec::new())
+    }
+
+    fn append(&mut self, _record: &MessageRecord) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn compact(
+        &mut self,
+        _records: &[MessageRecord],
+        _sequences: &[SequenceMark],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Appends each record as a line of JSON to a log file. Sequences kept by compaction are written
+/// alongside it, to the same path with `.sequences` added.
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileStore {
+    pub async fn init(path: &Path) -> Result<FileStore, Box<dyn std::error::Error>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(FileStore {
+            path: path.to_owned(),
+            file,
+        })
+    }
+
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut path = self.path.to_owned().into_os_string();
+
+        path.push(extension);
+
+        PathBuf::from(path)
+    }
+
+    /// Write each value as a line of JSON to a fresh file, then move it over `path`.
+    fn replace<T: Serialize>(
+        &self,
+        path: &Path,
+        values: &[T],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut compacted_path = path.to_owned().into_os_string();
+
+        compacted_path.push(".compact");
+
+        let mut compacted = File::create(&compacted_path)?;
+
+        for value in values {
+            let mut line = serde_json::to_string(value)?;
+
+            line.push('\n');
+
+            compacted.write_all(line.as_bytes())?;
+        }
+
+        compacted.sync_all()?;
+
+        std::fs::rename(&compacted_path, path)?;
+
+        Ok(())
+    }
+}
+
+impl MessageStore for FileStore {
+    fn load(&mut self) -> Result<Vec<MessageRecord>, Box<dyn std::error::Error>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut records = Vec::with_capacity(100);
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(error) => error!("skipping message log line {} -> {:?}", number + 1, error),
+            }
+        }
+
+        info!("loaded {} messages from {:?}", records.len(), &self.path);
+
+        Ok(records)
+    }
+
+    fn append(&mut self, record: &MessageRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let mut line = serde_json::to_string(record)?;
+
+        line.push('\n');
+
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+
+    fn sequences(&mut self) -> Result<Vec<SequenceMark>, Box<dyn std::error::Error>> {
+        let file = match File::open(self.sibling(".sequences")) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(Box::new(error)),
+        };
+        let mut sequences = Vec::with_capacity(10);
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+
+            if !line.trim().is_empty() {
+                sequences.push(serde_json::from_str(&line)?);
+            }
+        }
+
+        Ok(sequences)
+    }
+
+    /// Sequences are written first, as a mark ahead of the log it describes is harmless.
+    fn compact(
+        &mut self,
+        records: &[MessageRecord],
+        sequences: &[SequenceMark],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.replace(&self.sibling(".sequences"), sequences)?;
+        self.replace(&self.path, records)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+
+        info!("compacted {:?} to {} messages", &self.path, records.len());
+
+        Ok(())
+    }
+}
+
+pub async fn open(config: &Config) -> Result<Box<dyn MessageStore>, Box<dyn std::error::Error>> {
+    match config.storage {
+        Storage::Memory => Ok(Box::new(MemoryStore::init().await)),
+        Storage::File => {
+            info!("message log -> {:?}", &config.storage_path);
+
+            Ok(Box::new(FileStore::init(&config.storage_path).await?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn memory_store() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_store = MemoryStore::init().await;
+
+        let test_record = MessageRecord {
+            room: String::from("test_room"),
+            contents: String::from("test_contents"),
+            timestamp: timestamp().await,
+            ..MessageRecord::default()
+        };
+
+        test_store.append(&test_record)?;
+
+        assert!(test_store.load()?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_store() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+
+        let mut test_store = FileStore::init(&test_path).await?;
+
+        assert!(test_store.load()?.is_empty());
+
+        let test_record_one = MessageRecord {
+            room: String::from("test_room"),
+            contents: String::from("test_contents_one"),
+            timestamp: timestamp().await,
+            ..MessageRecord::default()
+        };
+        let test_record_two = MessageRecord {
+            room: String::from("test_other_room"),
+            contents: String::from("test_contents_two"),
+            timestamp: timestamp().await,
+            ..MessageRecord::default()
+        };
+
+        test_store.append(&test_record_one)?;
+        test_store.append(&test_record_two)?;
+
+        drop(test_store);
+
+        let mut test_reopened_store = FileStore::init(&test_path).await?;
+        let test_records = test_reopened_store.load()?;
+
+        assert_eq!(test_records, vec![test_record_one, test_record_two]);
+
+        std::fs::remove_file(&test_path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_store_skips_malformed_lines() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+
+        std::fs::write(
+            &test_path,
+            "{\"room\":\"test_room\",\"contents\":\"test_contents\"}\n\n{\"room\":\"test_ro",
+        )?;
+
+        let mut test_store = FileStore::init(&test_path).await?;
+        let test_records = test_store.load()?;
+
+        assert_eq!(test_records.len(), 1);
+        assert_eq!(test_records[0].contents, "test_contents");
+        assert_eq!(test_records[0].timestamp, 0);
+
+        std::fs::remove_file(&test_path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_store_compact() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let mut test_store = FileStore::init(&test_path).await?;
+        let mut test_records = Vec::with_capacity(3);
+
+        for test_contents in [
+            "test_contents_one",
+            "test_contents_two",
+            "test_contents_three",
+        ] {
+            let test_record = MessageRecord {
+                room: String::from("test_room"),
+                contents: test_contents.to_owned(),
+                timestamp: timestamp().await,
+                ..MessageRecord::default()
+            };
+
+            test_store.append(&test_record)?;
+            test_records.push(test_record);
+        }
+
+        assert!(test_store.sequences()?.is_empty());
+
+        let test_sequences = vec![SequenceMark {
+            room: String::from("test_room"),
+            conversation: None,
+            sequence: 3,
+        }];
+
+        test_store.compact(&test_records[1..], &test_sequences)?;
+
+        assert_eq!(test_store.load()?, test_records[1..].to_vec());
+        assert_eq!(test_store.sequences()?, test_sequences);
+
+        let test_record_four = MessageRecord {
+            room: String::from("test_room"),
+            contents: String::from("test_contents_four"),
+            timestamp: timestamp().await,
+            ..MessageRecord::default()
+        };
+
+        test_store.append(&test_record_four)?;
+
+        let test_loaded = test_store.load()?;
+
+        assert_eq!(test_loaded.len(), 3);
+        assert_eq!(test_loaded[2], test_record_four);
+
+        std::fs::remove_file(&test_path)?;
+        std::fs::remove_file(test_store.sibling(".sequences"))?;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn open() -> Result<(), Box<dyn std::error::Error>> {
+        let test_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let test_config = Config {
+            storage: Storage::File,
+            storage_path: test_path.to_owned(),
+            ..Config::default()
+        };
+
+        let mut test_store = super::open(&test_config).await?;
+
+        test_store.append(&MessageRecord {
+            room: String::from("test_room"),
+            contents: String::from("test_contents"),
+            timestamp: timestamp().await,
+            ..MessageRecord::default()
+        })?;
+
+        assert_eq!(test_store.load()?.len(), 1);
+
+        std::fs::remove_file(&test_path)?;
+
+        let mut test_memory_store = super::open(&Config::default()).await?;
+
+        assert!(test_memory_store.load()?.is_empty());
+
+        Ok(())
+    }
+}